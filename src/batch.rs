@@ -1,20 +1,51 @@
 use self::auto1111_api::APIClient;
+use self::backend::Backend;
+pub use self::backend::{BackendKind, Inventory, InventoryItem};
+use self::comfyui_api::ComfyClient;
+use self::compose::Extends;
+use self::gallery::PromptOrigin;
+use self::metadata::ImageDestination;
+use self::pool::{Job, Server};
+use self::progress::{BatchProgress, ProgressMode};
+use self::retry::{RetryPolicy, Retrying};
+use self::review::ReviewAction;
+pub use self::sheet::SheetSettings;
+use self::sweep::{SweepCell, SweepSettings};
+pub use self::validate::Problem;
+use self::wildcards::Wildcards;
 use choose_rand::rand::{ChooseRand, Probable};
 use chrono::Local;
-use image::io::Reader as ImageReader;
-use rand::Rng;
-use rand::{seq::SliceRandom, RngCore};
+use rand::{seq::SliceRandom, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::{
     fmt::{self},
     fs,
-    io::{Cursor, Write},
     path::{Path, PathBuf},
 };
 
 mod auto1111_api;
+mod backend;
+mod comfyui_api;
+mod compose;
+mod gallery;
+mod grid;
+mod metadata;
+#[cfg(test)]
+mod mock_api;
+mod pool;
+mod post_process;
+mod progress;
+mod retry;
+mod review;
+mod sheet;
+mod sweep;
+#[cfg(test)]
+mod testing;
+mod validate;
+mod wildcards;
 
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct PromptData {
@@ -31,10 +62,63 @@ pub struct PromptData {
     cfg: f32,
     // Clip Skip setting, defaults to 1
     clip_skip: Option<u8>,
+    /// LoRAs to add to the prompt, ex. [{"name": "add_detail", "weight": 0.8}]
+    loras: Option<Vec<LoraEntry>>,
+    /// Textual inversion embeddings to add to the positive prompt, by name
+    embeddings: Option<Vec<String>>,
+    /// Textual inversion embeddings to add to the negative prompt, by name
+    negative_embeddings: Option<Vec<String>>,
     seed: Option<i64>,
+    /// Number of images to generate in each batch, defaults to 1
+    batch_size: Option<u32>,
+    /// Number of batches to generate, defaults to 1
+    n_iter: Option<u32>,
     hires: Option<HiResSettings>,
-    /// Post-processing to perform on generated image
-    post_process: Option<PostProcesses>,
+    /// Generate from an existing image with img2img instead of txt2img
+    img2img: Option<Img2ImgSettings>,
+    /// Only regenerate the masked area of the img2img init image
+    inpaint: Option<InpaintSettings>,
+    /// ControlNet units to guide the generation with control images, needs Automatic1111's ControlNet extension
+    controlnet: Option<Vec<ControlNetUnit>>,
+    /// Post-processing steps to run on each generated image, in order
+    ///
+    /// A single step is accepted on its own too, ex. {"Resize": {"scale_by": 2.0}}
+    #[serde(default, deserialize_with = "one_or_many_steps")]
+    post_process: Option<Vec<PostProcesses>>,
+    /// Where the image belongs in its sweep grid, filled in by the log for sweep runs
+    sweep_cell: Option<SweepCell>,
+    /// Which pool prompt and modifier the prompt was rolled from, filled in by the log
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<PromptOrigin>,
+    /// Every image generated for the prompt, filled in by the log after generation
+    outputs: Option<Vec<GeneratedImage>>,
+}
+
+/// A single image generated for a prompt
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GeneratedImage {
+    /// Image filename, relative to the log file
+    filename: String,
+    seed: i64,
+    subseed: i64,
+    /// Generation parameters, as reported by Automatic1111
+    info: String,
+    /// Image as it was generated, before post-processing, if it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original: Option<String>,
+}
+
+/// A LoRA to add to the prompt, written into it as <lora:name:weight> when generating
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct LoraEntry {
+    /// LoRA name, ex. "add_detail", see List loras
+    name: String,
+    /// How strongly the LoRA is applied, defaults to 1.0
+    weight: Option<f32>,
+    /// Pick the weight at random between the two values instead, ex. [0.4, 0.9]
+    ///
+    /// The picked weight is recorded in the log.
+    weight_range: Option<[f32; 2]>,
 }
 
 #[derive(Serialize, Deserialize, Default, Clone)]
@@ -45,9 +129,190 @@ pub struct HiResSettings {
     steps: u8,
 }
 
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Img2ImgSettings {
+    /// Image to start from, or a directory of images to cycle through, one per prompt
+    ///
+    /// Relative paths are resolved from the template's directory
+    init_image: String,
+    /// How much the init image is allowed to change, from 0.0 to 1.0
+    denoising_strength: f32,
+    /// How to fit the init image to the image size, defaults to Resize
+    resize_mode: Option<ResizeMode>,
+}
+
+/// Matches the order of Automatic1111's img2img resize modes
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub enum ResizeMode {
+    /// Stretch the init image to the image size
+    #[default]
+    Resize,
+    CropAndResize,
+    /// Resize to fit and fill the empty space with the image's colors
+    ResizeAndFill,
+    /// Stretch the init image in latent space
+    LatentUpscale,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct InpaintSettings {
+    /// Mask image, or a directory of masks to cycle through like init images
+    ///
+    /// Only the white areas of the init image will be regenerated
+    mask: String,
+    /// Blur for the edges of the mask, in pixels, defaults to 4
+    mask_blur: Option<u32>,
+    /// What to fill the masked area with before inpainting, defaults to Original
+    fill: Option<InpaintFill>,
+    /// Inpaint only the masked area at full resolution, with this many pixels of padding around it
+    ///
+    /// Defaults to inpainting the whole image
+    full_res_padding: Option<u32>,
+    /// Regenerate the black areas of the mask instead of the white ones
+    invert_mask: Option<bool>,
+}
+
+/// A ControlNet unit, guiding the generation with a control image like a pose or depth map
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ControlNetUnit {
+    /// Preprocessor to turn the image into a control map, ex. "openpose" or "depth_midas"
+    ///
+    /// Defaults to "none", for images that already are control maps
+    module: Option<String>,
+    /// ControlNet model, ex. "control_v11p_sd15_openpose [cab727d4]"
+    model: String,
+    /// Control image, or a directory of images to cycle through like init images
+    image: String,
+    /// How strongly the control image guides the generation, defaults to 1.0
+    weight: Option<f32>,
+    /// Fraction of the steps to start guiding at, defaults to 0.0
+    guidance_start: Option<f32>,
+    /// Fraction of the steps to stop guiding at, defaults to 1.0
+    guidance_end: Option<f32>,
+    /// Whether the prompt or the control image matters more, defaults to Balanced
+    control_mode: Option<ControlMode>,
+    /// How the control image is fit to the image size, defaults to CropAndResize
+    ///
+    /// LatentUpscale is only an img2img resize mode, and can't be used
+    resize_mode: Option<ResizeMode>,
+}
+
+/// Matches the order of the ControlNet extension's control modes
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub enum ControlMode {
+    #[default]
+    Balanced,
+    PromptMoreImportant,
+    ControlNetMoreImportant,
+}
+
+/// Matches the order of Automatic1111's inpainting fill modes
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+pub enum InpaintFill {
+    /// Fill with the init image's colors, blurred
+    Fill,
+    /// Keep the init image as it is
+    #[default]
+    Original,
+    LatentNoise,
+    LatentNothing,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum PostProcesses {
+    /// Scale the image by a factor
     Resize { scale_by: f32 },
+    /// Resize to exactly this size, stretching the image if the aspect ratio is different
+    ResizeExact { width: u32, height: u32 },
+    /// Resize to fit within this size, keeping the aspect ratio
+    ResizeFit { width: u32, height: u32 },
+    /// Cut out an area of this size
+    Crop {
+        width: u32,
+        height: u32,
+        /// Point to keep in the middle of the crop, as fractions of the width and height, defaults to [0.5, 0.5]
+        focus: Option<[f32; 2]>,
+    },
+    /// Add borders to reach an aspect ratio, ex. "16:9"
+    Pad {
+        aspect_ratio: String,
+        /// Color of the borders, defaults to black
+        color: Option<[u8; 3]>,
+    },
+    /// Unsharp mask
+    Sharpen {
+        /// Blur radius of the mask, defaults to 1.0
+        sigma: Option<f32>,
+        /// Smallest difference to sharpen, defaults to 0
+        threshold: Option<i32>,
+    },
+    Adjust {
+        /// Added to each channel, negative to darken
+        brightness: Option<i32>,
+        /// Percentage to change the contrast by, negative to lower it
+        contrast: Option<f32>,
+        /// Degrees to rotate the hue by
+        hue: Option<i32>,
+    },
+    /// Upscale on the server with one of its upscalers, Automatic1111 only
+    Upscale(UpscaleSettings),
+    /// Save the image as JPEG or WebP instead of PNG
+    ///
+    /// Generation parameters are embedded as EXIF, the other texts sdbatch records are only embedded in PNGs
+    Convert {
+        format: OutputFormat,
+        /// From 1 to 100, defaults to 90
+        quality: Option<u8>,
+    },
+    /// Also save the image as it was generated, before any post-processing, as NN-original.png
+    KeepOriginal,
+}
+
+/// Upscaling and face restoration with Automatic1111's extras
+#[derive(clap::Args, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct UpscaleSettings {
+    /// Upscaler to use, ex. "R-ESRGAN 4x+", see List upscalers
+    #[arg(long)]
+    pub upscaler: String,
+    /// How many times larger to make the image
+    #[arg(long, default_value_t = 2.0)]
+    pub scale_by: f32,
+    /// Restore faces with GFPGAN, how visible the restoration is from 0.0 to 1.0
+    #[arg(long)]
+    pub gfpgan: Option<f32>,
+    /// Restore faces with CodeFormer, how visible the restoration is from 0.0 to 1.0
+    #[arg(long)]
+    pub codeformer: Option<f32>,
+    /// How closely CodeFormer keeps to the original face, from 0.0 to 1.0
+    #[arg(long, requires = "codeformer")]
+    pub codeformer_weight: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, clap::ValueEnum, Default, Clone, Copy, PartialEq, Debug)]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    Jpeg,
+    #[value(name = "webp")]
+    WebP,
+}
+
+/// Accept a single post-processing step as well as a list, templates used to only allow one
+fn one_or_many_steps<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<PostProcesses>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Steps {
+        One(PostProcesses),
+        Many(Vec<PostProcesses>),
+    }
+    Ok(
+        Option::<Steps>::deserialize(deserializer)?.map(|steps| match steps {
+            Steps::One(step) => vec![step],
+            Steps::Many(steps) => steps,
+        }),
+    )
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -58,11 +323,18 @@ pub struct BatchTemplate {
     /// Longer, detailed description
     pub description: Option<String>,
 
+    /// Template to start from, relative to this one, ex. "base.json"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extends: Option<Extends>,
+
     /// Automatic1111 URL
     ///
     /// Defaults to http://127.0.0.1:7860
     pub api_url: Option<String>,
 
+    /// Kind of server to generate images with, defaults to auto1111
+    pub backend: Option<BackendKind>,
+
     /// Prompt setup to be used for all images
     pub base_prompt: PromptData,
 
@@ -79,24 +351,175 @@ pub struct BatchTemplate {
     pub restore_faces: Option<bool>,
 
     /// The pool of prompts to pick from
+    ///
+    /// {"include": "animals.json"} adds the prompts listed in another file
     pub prompts: Vec<Prompts>,
 
     /// Additional modifiers to add to each prompt
+    ///
+    /// {"include": "styles.json"} adds the modifiers listed in another file
     pub modifiers: Option<Vec<PromptModifer>>,
+
+    /// Where to find the files for `__name__` wildcards in prompts,
+    /// defaults to a "wildcards" directory next to the template
+    pub wildcards: Option<WildcardSettings>,
+
+    /// Master seed driving every random choice made during a run:
+    /// prompt selection, modifier rolls and the per-image seeds
+    ///
+    /// Defaults to a random seed, which is recorded in the log so the run can be replayed
+    pub seed: Option<u64>,
+
+    /// Settings to generate each picked prompt with, stitched into a grid to compare them
+    pub sweep: Option<SweepSettings>,
+
+    /// File the template was loaded from, if any
+    #[serde(skip)]
+    pub file_path: Option<PathBuf>,
 }
 
-fn get_api_client(
-    api_url: Option<&str>,
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct WildcardSettings {
+    /// Directories to search for wildcard files in order, relative to the template
+    dirs: Option<Vec<String>>,
+    /// How many levels of wildcards inside wildcard files to expand, defaults to 10
+    max_depth: Option<usize>,
+}
+
+/// How many combinations a combinatorial run generates if no limit is given
+pub const DEFAULT_MAX_COMBINATIONS: usize = 1000;
+
+/// Options for a template run, set from the command line
+#[derive(Default)]
+pub struct RunOptions {
+    /// Only generate the prompts and log, no images
+    pub dry_run: bool,
+    /// Take prompts from the pool in order instead of picking at random
+    pub sequential: bool,
+    /// Generate every combination of the pool's prompts, their options and the modifiers
+    pub combinatorial: bool,
+    /// Most combinations to generate in combinatorial mode, defaults to [DEFAULT_MAX_COMBINATIONS]
+    pub max_combinations: Option<usize>,
+    pub server: ServerOptions,
+    /// Master seed, overrides the template's seed
+    pub seed: Option<u64>,
+    /// Don't show the live progress bar
+    pub quiet: bool,
+    /// Pause after each image for the user to keep or reroll it
+    pub interactive: bool,
+    /// Keep generating the other images when one fails, recording it in the log
+    pub continue_on_error: bool,
+    /// Don't check the template and the server has what it uses before starting
+    pub skip_preflight: bool,
+    /// Write an HTML gallery of the images next to the log when the run finishes
+    pub gallery: bool,
+}
+
+/// Servers to generate with, set from the command line
+#[derive(clap::Args, Default, Clone)]
+pub struct ServerOptions {
+    /// API URL to use, repeat it or separate URLs with commas to spread the images across several servers.
+    /// Defaults to 127.0.0.1:7860 for Automatic1111 or 127.0.0.1:8188 for ComfyUI
+    #[arg(long = "api-url", value_delimiter = ',')]
+    pub api_urls: Vec<String>,
+    /// File listing more API URLs to use, one per line
+    #[arg(long)]
+    pub servers: Option<PathBuf>,
+    /// Kind of server to generate with, overrides the backend of the template or log, defaults to auto1111
+    #[arg(long, value_enum)]
+    pub backend: Option<BackendKind>,
+    #[command(flatten)]
+    pub retry: RetryPolicy,
+}
+
+impl ServerOptions {
+    /// Every API URL to use, from the command line and the servers file, or the backend's default URL
+    fn urls(&self, backend: BackendKind) -> anyhow::Result<Vec<String>> {
+        let mut urls = self.api_urls.clone();
+        if let Some(servers_file) = &self.servers {
+            let contents = fs::read_to_string(servers_file).map_err(|e| BatchError {
+                message: format!(
+                    "unable to read servers file {}: {}",
+                    servers_file.display(),
+                    e
+                ),
+            })?;
+            urls.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        if urls.is_empty() {
+            urls.push(backend.default_url().to_string());
+        }
+        Ok(urls)
+    }
+}
+
+fn connect(
+    backend: BackendKind,
+    api_url: &str,
+    retry: &RetryPolicy,
+    save_images: &Option<bool>,
+    restore_faces: &Option<bool>,
+) -> anyhow::Result<Box<dyn Backend>> {
+    println!("Using {} API at: {}", backend, api_url);
+    new_client(backend, api_url, retry, save_images, restore_faces)
+}
+
+/// Same as connect, without telling the user which server is used
+fn new_client(
+    backend: BackendKind,
+    api_url: &str,
+    retry: &RetryPolicy,
     save_images: &Option<bool>,
     restore_faces: &Option<bool>,
-) -> anyhow::Result<APIClient> {
-    let url_to_use = match api_url {
-        Some(url) => url,
-        None => "http://127.0.0.1:7860",
+) -> anyhow::Result<Box<dyn Backend>> {
+    let timeout = retry.timeout();
+    let inner: Box<dyn Backend> = match backend {
+        BackendKind::Auto1111 => Box::new(APIClient::new(
+            api_url,
+            timeout,
+            save_images,
+            restore_faces,
+        )?),
+        BackendKind::ComfyUI => Box::new(ComfyClient::new(api_url, timeout, save_images)?),
     };
-    println!("Using API at: {}", url_to_use);
-    let api = auto1111_api::APIClient::new(&url_to_use, save_images, restore_faces)?;
-    Ok(api)
+    Ok(Box::new(Retrying {
+        inner,
+        policy: retry.clone(),
+    }))
+}
+
+/// Client for the first of the servers, for commands that generate one image at a time
+fn get_api_client(
+    server: &ServerOptions,
+    backend: BackendKind,
+    save_images: &Option<bool>,
+    restore_faces: &Option<bool>,
+) -> anyhow::Result<Box<dyn Backend>> {
+    let urls = server.urls(backend)?;
+    connect(backend, &urls[0], &server.retry, save_images, restore_faces)
+}
+
+/// Clients for all of the servers, to generate with as a pool
+fn get_api_clients(
+    server: &ServerOptions,
+    backend: BackendKind,
+    save_images: &Option<bool>,
+    restore_faces: &Option<bool>,
+) -> anyhow::Result<Vec<Server>> {
+    server
+        .urls(backend)?
+        .into_iter()
+        .map(|url| {
+            let api = connect(backend, &url, &server.retry, save_images, restore_faces)?;
+            Ok(Server { url, api })
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone)]
@@ -115,18 +538,86 @@ impl std::error::Error for BatchError {}
 #[derive(Deserialize)]
 struct Txt2ImgInfo {
     all_seeds: Vec<i64>,
+    #[serde(default)]
+    all_subseeds: Vec<i64>,
+    #[serde(default)]
+    infotexts: Vec<String>,
+    /// Images before this index are grids, not individual images
+    #[serde(default)]
+    index_of_first_image: usize,
+}
+
+impl Txt2ImgInfo {
+    fn image_record(&self, i: usize, filename: String) -> GeneratedImage {
+        GeneratedImage {
+            filename,
+            seed: self.all_seeds.get(i).copied().unwrap_or(-1),
+            subseed: self.all_subseeds.get(i).copied().unwrap_or(-1),
+            info: self.infotexts.get(i).cloned().unwrap_or_default(),
+            original: None,
+        }
+    }
+}
+
+impl PromptData {
+    /// Positive prompt to generate with, with the LoRAs and embeddings written into it
+    fn full_positive(&self) -> String {
+        let loras = self
+            .loras
+            .iter()
+            .flatten()
+            .map(|lora| format!("<lora:{}:{}>", lora.name, lora.weight.unwrap_or(1.0)));
+        let extras: Vec<String> = loras
+            .chain(self.embeddings.iter().flatten().cloned())
+            .collect();
+        Self::with_extras(&self.positive, &extras)
+    }
+
+    /// Negative prompt to generate with, with the negative embeddings written into it
+    fn full_negative(&self) -> String {
+        let extras = self.negative_embeddings.clone().unwrap_or_default();
+        Self::with_extras(&self.negative, &extras)
+    }
+
+    fn with_extras(prompt: &str, extras: &[String]) -> String {
+        match extras.is_empty() {
+            true => prompt.to_string(),
+            false => BatchTemplate::combine_prompts(prompt, &extras.join(", ")),
+        }
+    }
+
+    /// Pick the weight of every LoRA with a weight range
+    fn roll_lora_weights(&mut self, rng: &mut BatchRng) {
+        for lora in self.loras.iter_mut().flatten() {
+            if let Some([min, max]) = lora.weight_range.take() {
+                let weight = if min < max {
+                    rng.gen_range(min..=max)
+                } else {
+                    min
+                };
+                lora.weight = Some((weight * 100.0).round() / 100.0);
+            }
+        }
+    }
 }
 
+/// RNG used for all random decisions in a template run
+///
+/// ChaCha is used over `StdRng` because its output is stable across platforms and `rand` versions
+type BatchRng = ChaCha8Rng;
+
 impl BatchTemplate {
-    fn run(
-        &self,
-        dry_run: bool,
-        output_dir: &Path,
-        sequential: bool,
-        api_url: Option<&str>,
-    ) -> anyhow::Result<BatchLog> {
+    /// Load the template, with the template it extends and the files it includes
+    pub fn from_file(file_path: &Path) -> anyhow::Result<BatchTemplate> {
+        let mut template: BatchTemplate =
+            serde_json::from_value(compose::load_template(file_path)?)?;
+        template.file_path = Some(fs::canonicalize(file_path)?);
+        Ok(template)
+    }
+
+    fn run(&self, output_dir: &Path, options: &RunOptions) -> anyhow::Result<TemplateRunResults> {
         let count = self.count.unwrap_or(self.prompts.len());
-        if self.prompts.len() < count {
+        if !options.combinatorial && self.prompts.len() < count {
             return Err(BatchError {
                 message:
                     "count is too large, it must be less than or equal to the number of prompts"
@@ -135,73 +626,480 @@ impl BatchTemplate {
             .into());
         }
 
-        let prompt_pool: Vec<&Prompts> = if sequential {
-            self.prompts.iter().take(count).collect()
-        } else {
-            self.prompts
-                .choose_multiple(&mut rand::thread_rng(), count)
-                .collect()
+        let master_seed = options
+            .seed
+            .or(self.seed)
+            .unwrap_or_else(|| rand::thread_rng().next_u64());
+        println!("Using master seed: {}", master_seed);
+        let mut rng = BatchRng::seed_from_u64(master_seed);
+
+        let backend = options.server.backend.or(self.backend);
+        let servers = match options.dry_run {
+            true => vec![],
+            false => get_api_clients(
+                &options.server,
+                backend.unwrap_or_default(),
+                &self.save_images,
+                &self.restore_faces,
+            )?,
         };
+        if !options.skip_preflight {
+            let mut problems = validate::check_template(self, options.combinatorial);
+            if let Some(server) = servers.first() {
+                problems.extend(validate::check_server(self, server.api.as_ref())?);
+            }
+            if !problems.is_empty() {
+                return Err(problems_error(&problems));
+            }
+        }
 
         let mut batch_log = BatchLog::new(&self.name, output_dir);
+        batch_log.seed = Some(master_seed);
+        batch_log.backend = backend;
+        batch_log.template_file = self.file_path.clone();
+        batch_log.images = if options.combinatorial {
+            let mut combinations = self.generate_combinations(&mut rng)?;
+            let max_combinations = options.max_combinations.unwrap_or(DEFAULT_MAX_COMBINATIONS);
+            println!("Template has {} combinations", combinations.len());
+            if combinations.len() > max_combinations {
+                println!(
+                    "Only generating the first {} combinations, raise --max-combinations to generate more",
+                    max_combinations
+                );
+                combinations.truncate(max_combinations);
+            }
+            combinations
+        } else {
+            self.generate_logs(count, options.sequential, &mut rng)?
+        };
+        if let Some(sweep) = &self.sweep {
+            batch_log.images = sweep.expand_all(&batch_log.images)?;
+        }
+        let count = batch_log.images.len();
+        for (index, prompt) in batch_log.images.iter_mut().enumerate() {
+            // The cells of a sweep share their input images, so the grid only compares the swept settings
+            let input_index = prompt.sweep_cell.as_ref().map_or(index, SweepCell::sweep);
+            self.resolve_image_files(prompt, input_index)?;
+        }
         std::fs::create_dir_all(output_dir)?;
 
-        for prompt in prompt_pool.iter() {
-            let prompt_data = self.generate_log_for_prompt(prompt);
-            batch_log.images.push(prompt_data);
-        }
         let batch_log_name = batch_log.write()?;
         let batch_log_name = batch_log_name
             .file_name()
             .expect("log file to have a valid filename");
 
-        if dry_run {
+        let mut images_created = batch_log.images.len();
+        if options.dry_run {
             println!("Created log file {}", batch_log_name.to_string_lossy());
         } else {
-            let api = get_api_client(api_url, &self.save_images, &self.restore_faces)?;
             println!(
                 "Created log file {}, beginning image generation...",
                 batch_log_name.to_string_lossy()
             );
-            for (prompt_index, prompt) in batch_log.images.iter_mut().enumerate() {
-                println!("Generating image {} of {}...", prompt_index + 1, count);
-                Self::generate_image(output_dir, &api, prompt, prompt_index)?;
+            if options.interactive {
+                // Images are reviewed one at a time, so only the first server is needed
+                let api = servers[0].api.as_ref();
+                let destination = batch_log.destination();
+                let mut progress = BatchProgress::new(ProgressMode::detect(options.quiet), count);
+                images_created = 0;
+                for prompt_index in 0..batch_log.images.len() {
+                    println!("Generating image {} of {}...", prompt_index + 1, count);
+                    progress.start_image();
+                    let prompt = &mut batch_log.images[prompt_index];
+                    let generated =
+                        Self::generate_image(&destination, api, prompt, prompt_index, &progress);
+                    match generated {
+                        Err(e) if options.continue_on_error => {
+                            println!("Image {} failed, continuing: {}", prompt_index, e);
+                            batch_log.failed.push(prompt_index);
+                            batch_log.write()?;
+                            continue;
+                        }
+                        result => result?,
+                    }
+                    batch_log.write()?;
+                    images_created += 1;
+
+                    if !review_image(
+                        api,
+                        &mut batch_log,
+                        prompt_index,
+                        Some(self),
+                        &mut rng,
+                        &progress,
+                    )? {
+                        println!("Run aborted, use Resume to generate the remaining images");
+                        break;
+                    }
+                }
+            } else {
+                let jobs = batch_log
+                    .images
+                    .iter()
+                    .enumerate()
+                    .map(|(index, prompt)| Job::new(index, prompt.clone()))
+                    .collect();
+                let results = pool::generate(
+                    &servers,
+                    &batch_log.destination(),
+                    jobs,
+                    ProgressMode::detect(options.quiet),
+                    options.continue_on_error,
+                    |job| {
+                        job.record(&mut batch_log.images);
+                        batch_log.write()?;
+                        Ok(())
+                    },
+                )?;
+                images_created = results.generated;
+                batch_log.failed = results.failed;
+                batch_log.write()?;
+            }
+            report_failures(&batch_log.failed);
+            write_sweep_grids(output_dir, &batch_log.images)?;
+            if options.gallery {
+                let gallery = gallery::write_gallery(&batch_log)?;
+                println!("Saved gallery {}", gallery.display());
+            }
+        }
+
+        Ok(TemplateRunResults {
+            images_created,
+            log_file: batch_log.file_path,
+            seed: batch_log.seed,
+        })
+    }
+
+    /// Pick `count` prompts from the pool and roll the prompt data for each of them
+    fn generate_logs(
+        &self,
+        count: usize,
+        sequential: bool,
+        rng: &mut BatchRng,
+    ) -> anyhow::Result<Vec<PromptData>> {
+        let pool_indices: Vec<usize> = (0..self.prompts.len()).collect();
+        let picked: Vec<usize> = if sequential {
+            pool_indices.into_iter().take(count).collect()
+        } else {
+            pool_indices.choose_multiple(rng, count).copied().collect()
+        };
+
+        let wildcards = self.wildcards();
+        picked
+            .into_iter()
+            .map(|pool_index| self.generate_log_for_prompt(pool_index, rng, &wildcards))
+            .collect()
+    }
+
+    /// Roll the prompt data for every option of every prompt in the pool, once without a modifier
+    /// and once with each modifier that applies to it, ignoring the chances
+    ///
+    /// Wildcards and variants are still picked at random, once per prompt option
+    fn generate_combinations(&self, rng: &mut BatchRng) -> anyhow::Result<Vec<PromptData>> {
+        let wildcards = self.wildcards();
+        let mut combinations = vec![];
+        for (pool_index, prompt) in self.prompts.iter().enumerate() {
+            for mut prompt_data in self.prompt_options(prompt) {
+                prompt_data.positive = Self::expand_prompt(&prompt_data.positive, rng, &wildcards)?;
+                prompt_data.negative = Self::expand_prompt(&prompt_data.negative, rng, &wildcards)?;
+                prompt_data.origin = Some(PromptOrigin::new(pool_index, prompt));
+
+                let mut unmodified = prompt_data.clone();
+                unmodified.roll_lora_weights(rng);
+                unmodified.seed = Some(rng.next_u32() as i64);
+                combinations.push(unmodified);
+
+                for modifier in self.applicable_modifiers(&prompt_data) {
+                    let mut modified = prompt_data.clone();
+                    Self::add_modifier(&mut modified, modifier, rng, &wildcards)?;
+                    modified.roll_lora_weights(rng);
+                    modified.seed = Some(rng.next_u32() as i64);
+                    combinations.push(modified);
+                }
             }
         }
+        Ok(combinations)
+    }
 
-        Ok(batch_log)
+    /// Point the img2img and inpaint settings of the prompt at `index` at the exact files it will use,
+    /// picking from init image and mask directories in turn
+    fn resolve_image_files(&self, prompt: &mut PromptData, index: usize) -> anyhow::Result<()> {
+        let template_dir = self.template_dir();
+
+        match prompt.img2img.as_mut() {
+            Some(img2img) => {
+                img2img.init_image =
+                    resolve_image_file(&template_dir, &img2img.init_image, index, "init image")?;
+            }
+            None if prompt.inpaint.is_some() => {
+                return Err(BatchError {
+                    message: "inpainting needs img2img settings with an init image".to_string(),
+                }
+                .into());
+            }
+            None => {}
+        }
+        if let Some(inpaint) = prompt.inpaint.as_mut() {
+            inpaint.mask = resolve_image_file(&template_dir, &inpaint.mask, index, "mask")?;
+        }
+        for unit in prompt.controlnet.iter_mut().flatten() {
+            unit.image = resolve_image_file(&template_dir, &unit.image, index, "ControlNet image")?;
+        }
+
+        Ok(())
+    }
+
+    /// Directory of the template file, which relative paths in the template are resolved from
+    fn template_dir(&self) -> PathBuf {
+        self.file_path
+            .as_deref()
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    fn wildcards(&self) -> Wildcards {
+        let template_dir = self.template_dir();
+        let settings = self.wildcards.clone().unwrap_or_default();
+        let search_dirs = match settings.dirs {
+            Some(dirs) => dirs.iter().map(|dir| template_dir.join(dir)).collect(),
+            None => vec![template_dir.join("wildcards")],
+        };
+        Wildcards::new(
+            search_dirs,
+            settings.max_depth.unwrap_or(wildcards::DEFAULT_MAX_DEPTH),
+        )
     }
 
     /// Build a full positive prompt using Template's positive prompt settings and the given positive fragment
     fn build_positive(&self, positive: &str) -> String {
-        return Self::combine_prompts(&self.base_prompt.positive, positive);
+        Self::combine_prompts(&self.base_prompt.positive, positive)
     }
 
     fn combine_prompts(a: &str, b: &str) -> String {
+        if a.trim().is_empty() {
+            return b.to_string();
+        }
         let mut combined = a.trim_end().to_string();
 
-        if combined.chars().last() != Some(',') {
+        if !combined.ends_with(',') {
             combined.push(',');
         }
 
         combined.push(' ');
         combined.push_str(b);
-        return combined;
+        combined
+    }
+
+    /// Resolve the inline variants and wildcards in a prompt
+    ///
+    /// Variants are expanded again after the wildcards, to pick from any variants in wildcard files
+    fn expand_prompt(
+        text: &str,
+        rng: &mut BatchRng,
+        wildcards: &Wildcards,
+    ) -> anyhow::Result<String> {
+        let expanded = Self::expand_variants(text, rng)?;
+        let expanded = wildcards.expand(&expanded, rng)?;
+        Self::expand_variants(&expanded, rng)
+    }
+
+    /// Replace each inline variant in the text with a random pick of its options
+    ///
+    /// `{a|b|c}` picks one option, `{2::a|b}` makes a twice as likely to be picked,
+    /// `{2$$a|b|c}` picks two different options joined with ", ", and `{1-3$$ and $$a|b|c}`
+    /// picks one to three options joined with " and ". Variants can be nested in options.
+    /// Braces without a `|`, like `{masterpiece}`, and unmatched braces are kept as they are.
+    fn expand_variants(text: &str, rng: &mut BatchRng) -> anyhow::Result<String> {
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some(start) = rest.find('{') {
+            expanded.push_str(&rest[..start]);
+            let variant_end = matching_brace(&rest[start..])
+                .map(|end| start + end)
+                .filter(|end| split_top_level(&rest[start + 1..*end], "|").len() > 1);
+            match variant_end {
+                Some(end) => {
+                    expanded.push_str(&Self::pick_variant(&rest[start + 1..end], rng)?);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    expanded.push('{');
+                    rest = &rest[start + 1..];
+                }
+            }
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Pick from the options of a single variant, given without its braces
+    fn pick_variant(variant: &str, rng: &mut BatchRng) -> anyhow::Result<String> {
+        let parts = split_top_level(variant, "$$");
+        let (count_range, separator, options) = match parts.as_slice() {
+            [options] => ((1, 1), ", ", *options),
+            [count, options] => (parse_variant_count(count)?, ", ", *options),
+            [count, separator, options] => (parse_variant_count(count)?, *separator, *options),
+            _ => {
+                return Err(BatchError {
+                    message: format!("too many $$ in variant {{{}}}", variant),
+                }
+                .into())
+            }
+        };
+
+        let mut options = split_top_level(options, "|")
+            .into_iter()
+            .map(|option| match option.split_once("::") {
+                Some((weight, weighted)) => match weight.trim().parse::<f32>() {
+                    Ok(weight) if weight.is_finite() && weight >= 0.0 => Ok((weight, weighted)),
+                    Ok(_) => Err(BatchError {
+                        message: format!(
+                            "invalid weight \"{}\" in variant {{{}}}",
+                            weight, variant
+                        ),
+                    }),
+                    // Not a weight, so the "::" is part of the option
+                    Err(_) => Ok((1.0, option)),
+                },
+                None => Ok((1.0, option)),
+            })
+            .collect::<Result<Vec<(f32, &str)>, BatchError>>()?;
+        // Options weighted 0 are never picked
+        options.retain(|(weight, _)| *weight > 0.0);
+        if options.is_empty() {
+            return Err(BatchError {
+                message: format!(
+                    "variant {{{}}} has no options with a weight above 0",
+                    variant
+                ),
+            }
+            .into());
+        }
+
+        let count = rng
+            .gen_range(count_range.0..=count_range.1)
+            .min(options.len());
+        let mut picked = vec![];
+        for _ in 0..count {
+            let total: f32 = options.iter().map(|(weight, _)| weight).sum();
+            let mut roll = rng.gen::<f32>() * total;
+            let mut index = options.len() - 1;
+            for (i, (weight, _)) in options.iter().enumerate() {
+                if roll < *weight {
+                    index = i;
+                    break;
+                }
+                roll -= weight;
+            }
+            let (_, option) = options.remove(index);
+            picked.push(Self::expand_variants(option, rng)?);
+        }
+        Ok(picked.join(separator))
     }
 
     /// Copy template's base prompt and use the given positive prompt fragment to construct the positive prompt
     fn copy_with_positive(&self, positive: &str) -> PromptData {
         let mut data = self.base_prompt.clone();
         data.positive = self.build_positive(positive);
-        return data;
+        data
+    }
+
+    /// Prompt data for each of the options of a prompt from the pool
+    fn prompt_options(&self, prompt: &Prompts) -> Vec<PromptData> {
+        match prompt {
+            Prompts::Single(positive) => vec![self.copy_with_positive(positive)],
+            Prompts::Multiple(positive_vec) => positive_vec
+                .iter()
+                .map(|positive| self.copy_with_positive(positive))
+                .collect(),
+            Prompts::MultipleWeighted(positive_vec) => positive_vec
+                .iter()
+                .map(|weighted| self.copy_with_positive(&weighted.prompt))
+                .collect(),
+            Prompts::Detailed(detailed) => vec![self.copy_detailed(detailed)],
+        }
+    }
+
+    /// Copy template's base prompt with the detailed prompt's positive prompt and settings
+    fn copy_detailed(&self, detailed: &DetailedPrompt) -> PromptData {
+        let mut data = self.copy_with_positive(&detailed.prompt);
+        if detailed.img2img.is_some() {
+            data.img2img = detailed.img2img.clone();
+        }
+        if detailed.inpaint.is_some() {
+            data.inpaint = detailed.inpaint.clone();
+        }
+        if detailed.controlnet.is_some() {
+            data.controlnet = detailed.controlnet.clone();
+        }
+        data
     }
 
-    fn generate_log_for_prompt(&self, prompt: &Prompts) -> PromptData {
-        let mut rng = rand::thread_rng();
+    /// Add the modifier's prompt, LoRAs and embeddings to the prompt data
+    fn add_modifier(
+        prompt_data: &mut PromptData,
+        modifier: &PromptModifer,
+        rng: &mut BatchRng,
+        wildcards: &Wildcards,
+    ) -> anyhow::Result<()> {
+        let modifier_prompt = Self::expand_prompt(&modifier.prompt, rng, wildcards)?;
+        // Modifiers can add only LoRAs or embeddings
+        if !modifier_prompt.trim().is_empty() {
+            prompt_data.positive = Self::combine_prompts(&prompt_data.positive, &modifier_prompt);
+        }
+        if let Some(loras) = &modifier.loras {
+            prompt_data
+                .loras
+                .get_or_insert_with(Vec::new)
+                .extend(loras.iter().cloned());
+        }
+        if let Some(embeddings) = &modifier.embeddings {
+            prompt_data
+                .embeddings
+                .get_or_insert_with(Vec::new)
+                .extend(embeddings.iter().cloned());
+        }
+        if let Some(origin) = prompt_data.origin.as_mut() {
+            origin.modifier = Some(modifier.label());
+        }
+        Ok(())
+    }
+
+    /// The modifiers whose `if` and `if-not` activators allow them to be added to the prompt
+    fn applicable_modifiers(&self, prompt_data: &PromptData) -> Vec<&PromptModifer> {
+        let Some(modifiers) = &self.modifiers else {
+            return vec![];
+        };
+        modifiers
+            .iter()
+            .filter(|m| {
+                m.if_activator.is_none()
+                    || m.if_activator
+                        .as_ref()
+                        .is_some_and(|activator| prompt_data.positive.contains(activator))
+            })
+            .filter(|m| {
+                m.if_not_activator.is_none()
+                    || m.if_not_activator
+                        .as_ref()
+                        .is_some_and(|activator| filter_if_not(prompt_data, &activator))
+            })
+            .collect()
+    }
+
+    /// Roll the prompt data for the prompt at `pool_index` in the pool
+    fn generate_log_for_prompt(
+        &self,
+        pool_index: usize,
+        rng: &mut BatchRng,
+        wildcards: &Wildcards,
+    ) -> anyhow::Result<PromptData> {
+        let prompt = &self.prompts[pool_index];
         let mut prompt_data = match prompt {
             Prompts::Single(positive) => self.copy_with_positive(positive),
             Prompts::Multiple(positive_vec) => {
-                let positive = positive_vec.choose(&mut rng);
+                let positive = positive_vec.choose(rng);
                 self.copy_with_positive(
                     positive.expect("Prompts::Multiple to always pick Some positive prompt"),
                 )
@@ -209,100 +1107,194 @@ impl BatchTemplate {
             Prompts::MultipleWeighted(positive_vec) => {
                 let v: Vec<_> = choose_rand::helper::refcellify(positive_vec.to_owned()).collect();
 
-                let selected_prompt = v.choose_rand(&mut rng).expect("chances to sum to 1.0");
+                let selected_prompt = v.choose_rand(rng).expect("chances to sum to 1.0");
                 self.copy_with_positive(&selected_prompt.prompt)
             }
+            Prompts::Detailed(detailed) => self.copy_detailed(detailed),
         };
-
-        if let Some(modifiers) = &self.modifiers {
-            let applicable_modifiers: Vec<_> = modifiers
-                .iter()
-                .filter(|m| {
-                    m.if_activator.is_none()
-                        || m.if_activator
-                            .as_ref()
-                            .is_some_and(|activator| *&prompt_data.positive.contains(*&activator))
-                })
-                .filter(|m| {
-                    m.if_not_activator.is_none()
-                        || m.if_not_activator
-                            .as_ref()
-                            .is_some_and(|activator| filter_if_not(&prompt_data, &activator))
-                })
-                .collect();
-            if let Some(modifier) = applicable_modifiers.choose(&mut rng) {
-                let roll: f32 = rng.gen();
-                if roll <= modifier.chance.unwrap_or(1.0) {
-                    // ring-a-ding-ding!
-                    prompt_data.positive =
-                        Self::combine_prompts(&prompt_data.positive, &modifier.prompt);
-                }
+        prompt_data.origin = Some(PromptOrigin::new(pool_index, prompt));
+        // Expanded before the modifiers so their activators can match the picked text
+        prompt_data.positive = Self::expand_prompt(&prompt_data.positive, rng, wildcards)?;
+        prompt_data.negative = Self::expand_prompt(&prompt_data.negative, rng, wildcards)?;
+
+        if let Some(modifier) = self.applicable_modifiers(&prompt_data).choose(rng) {
+            let roll: f32 = rng.gen();
+            if roll <= modifier.chance.unwrap_or(1.0) {
+                // ring-a-ding-ding!
+                Self::add_modifier(&mut prompt_data, modifier, rng, wildcards)?;
             }
         }
+        prompt_data.roll_lora_weights(rng);
 
         // Assign a seed value
         prompt_data.seed = Some(rng.next_u32() as i64);
 
-        return prompt_data;
+        Ok(prompt_data)
     }
 
-    /// Use the Automatic1111 API and generate an image for the given prompt, and sets the seed
+    /// Use the backend's API and generate the images for the given prompt,
+    /// recording each of them in the prompt's outputs and setting the seed
     fn generate_image(
-        output_dir: &Path,
-        api: &auto1111_api::APIClient,
+        destination: &ImageDestination,
+        api: &dyn Backend,
         prompt: &mut PromptData,
         prompt_index: usize,
+        progress: &BatchProgress,
     ) -> anyhow::Result<()> {
-        let (image_list, info) = api.txt2img(prompt)?;
-
-        let info: Txt2ImgInfo = serde_json::from_str(&info)?;
+        let (image_list, info) = Self::request_images(api, prompt, progress)?;
 
+        let mut outputs = vec![];
         for (i, image_bytes) in image_list.iter().enumerate() {
-            let mut image_filename = PathBuf::from(output_dir);
-            if i == 0 {
-                image_filename.push(format!("{:02}.png", prompt_index));
-                if let Some(seed) = info.all_seeds.first() {
-                    prompt.seed = Some(*seed);
-                }
-            } else {
-                image_filename.push(format!("{:02}-{}.png", prompt_index, i));
-                // TODO: Better support for multiple images/batches.
-                // TODO: We will have seeds for these, but nowhere to put them in the log.
-            }
+            let mut record = info.image_record(i, image_filename(prompt_index, i));
+            let text_chunks = destination.text_chunks(prompt, &record, prompt_index, i);
+            Self::save_image(
+                api,
+                image_bytes,
+                &destination.output_dir,
+                &mut record,
+                prompt,
+                &text_chunks,
+            )?;
+            outputs.push(record);
+        }
 
-            match &prompt.post_process {
-                None => {
-                    let mut dest_file = fs::File::create(&image_filename)?;
-                    dest_file.write_all(image_bytes)?;
-                }
-                Some(p) => {
-                    print!("Post-processing...");
-                    match p {
-                        PostProcesses::Resize { scale_by } => {
-                            let (orig_img_w, orig_img_h) = match &prompt.hires {
-                                Some(hires) => (
-                                    (prompt.width as f32 * hires.upscale_by) as u32,
-                                    (prompt.height as f32 * hires.upscale_by) as u32,
-                                ),
-                                None => (prompt.width, prompt.height),
-                            };
-                            let orig_img = ImageReader::new(Cursor::new(image_bytes))
-                                .with_guessed_format()?
-                                .decode()?;
-                            let new_w = (orig_img_w as f32 * scale_by) as u32;
-                            let new_h = (orig_img_h as f32 * scale_by) as u32;
-                            println!("resizing to {}x{}", new_w, new_h);
-                            let resized_img = image::imageops::resize(
-                                &orig_img,
-                                new_w,
-                                new_h,
-                                image::imageops::FilterType::Lanczos3,
-                            );
-                            resized_img.save(&image_filename)?;
-                        }
-                    };
-                }
+        if let Some(seed) = info.all_seeds.first() {
+            prompt.seed = Some(*seed);
+        }
+        prompt.outputs = Some(outputs);
+
+        Ok(())
+    }
+
+    /// Regenerate a single image out of the prompt's batch, replacing its record in the outputs
+    ///
+    /// Uses a new random seed unless `seed` is given
+    fn generate_batch_image(
+        destination: &ImageDestination,
+        api: &dyn Backend,
+        prompt: &mut PromptData,
+        prompt_index: usize,
+        batch_index: usize,
+        seed: Option<i64>,
+        progress: &BatchProgress,
+    ) -> anyhow::Result<()> {
+        if prompt
+            .outputs
+            .as_ref()
+            .map_or(true, |outputs| outputs.len() <= batch_index)
+        {
+            return Err(BatchError {
+                message: format!(
+                    "image {} has no record of a batch image {}",
+                    prompt_index, batch_index
+                ),
             }
+            .into());
+        }
+
+        let mut single = prompt.clone();
+        single.batch_size = None;
+        single.n_iter = None;
+        single.seed = seed;
+        let (image_list, info) = Self::request_images(api, &single, progress)?;
+        let image_bytes = image_list.first().ok_or_else(|| BatchError {
+            message: "no image was returned".to_string(),
+        })?;
+
+        let mut record = info.image_record(0, image_filename(prompt_index, batch_index));
+        let text_chunks = destination.text_chunks(prompt, &record, prompt_index, batch_index);
+        Self::save_image(
+            api,
+            image_bytes,
+            &destination.output_dir,
+            &mut record,
+            prompt,
+            &text_chunks,
+        )?;
+        if let Some(outputs) = prompt.outputs.as_mut() {
+            outputs[batch_index] = record;
+        }
+
+        Ok(())
+    }
+
+    /// Run txt2img or img2img for the prompt, returning only the individual images and not any grids
+    fn request_images(
+        api: &dyn Backend,
+        prompt: &PromptData,
+        progress: &BatchProgress,
+    ) -> anyhow::Result<(Vec<Vec<u8>>, Txt2ImgInfo)> {
+        api.set_model(&prompt.model)?;
+        let result = if progress.wants_updates() {
+            api.generate_with_progress(prompt, &mut |p| progress.update(p))
+        } else {
+            api.generate(prompt)
+        };
+        progress.finish_image();
+        let (image_list, info) = result?;
+
+        let image_count = match info.all_seeds.len() {
+            0 => image_list.len(),
+            seed_count => seed_count,
+        };
+        let image_list = image_list
+            .into_iter()
+            .skip(info.index_of_first_image)
+            .take(image_count)
+            .collect();
+
+        Ok((image_list, info))
+    }
+
+    /// Save the image to `output_dir` under the record's filename, running the prompt's post-processing first
+    ///
+    /// The text chunks are embedded in PNGs. The record's filename is changed to match if the image is
+    /// converted to another format, and the original is recorded if it's kept.
+    fn save_image(
+        api: &dyn Backend,
+        image_bytes: &[u8],
+        output_dir: &Path,
+        record: &mut GeneratedImage,
+        prompt: &PromptData,
+        text_chunks: &[(&str, String)],
+    ) -> anyhow::Result<()> {
+        // The server may be set to send JPEG or WebP, but images are saved as PNG unless converted
+        let png = match metadata::is_png(image_bytes) {
+            true => image_bytes.to_vec(),
+            false => metadata::encode_png(&image::load_from_memory(image_bytes)?)?,
+        };
+        let steps = prompt.post_process.as_deref().unwrap_or_default();
+        if steps.is_empty() {
+            let png = metadata::add_text_chunks(&png, text_chunks)?;
+            fs::write(output_dir.join(&record.filename), png)?;
+            return Ok(());
+        }
+
+        let (processed, done) =
+            post_process::apply(image::load_from_memory(&png)?, steps, Some(api))?;
+        if !done.is_empty() {
+            println!("Post-processing: {}", done.join(", "));
+        }
+        let (format, quality) = post_process::output_format(steps);
+        let bytes = post_process::encode(&processed, format, quality)?;
+        let bytes = metadata::embed_texts(&bytes, format, text_chunks)?;
+        let filename = Path::new(&record.filename).with_extension(format.extension());
+        record.filename = filename.to_string_lossy().into_owned();
+        fs::write(output_dir.join(&record.filename), bytes)?;
+
+        if steps
+            .iter()
+            .any(|step| matches!(step, PostProcesses::KeepOriginal))
+        {
+            let original = format!(
+                "{}-original.png",
+                filename.file_stem().unwrap_or_default().to_string_lossy()
+            );
+            fs::write(
+                output_dir.join(&original),
+                metadata::add_text_chunks(&png, text_chunks)?,
+            )?;
+            record.original = Some(original);
         }
 
         Ok(())
@@ -310,7 +1302,7 @@ impl BatchTemplate {
 
     fn safe_template_filename(&self) -> PathBuf {
         let sanitized_filename = get_safe_filename(&self.name);
-        return format!("{}.json", sanitized_filename).into();
+        format!("{}.json", sanitized_filename).into()
     }
 
     /// Serialize to JSON and write to disk
@@ -323,15 +1315,97 @@ impl BatchTemplate {
     }
 }
 
+/// Filename of the `batch_index`th image generated for the log entry at `index`
+fn image_filename(index: usize, batch_index: usize) -> String {
+    if batch_index == 0 {
+        format!("{:02}.png", index)
+    } else {
+        format!("{:02}-{}.png", index, batch_index)
+    }
+}
+
+/// Path of the first image generated for the log entry at `index`
+fn image_path(output_dir: &Path, index: usize) -> PathBuf {
+    output_dir.join(image_filename(index, 0))
+}
+
+/// Path of the first image generated for the log entry, as recorded after post-processing may have changed its format
+fn saved_image_path(output_dir: &Path, index: usize, prompt: &PromptData) -> PathBuf {
+    match prompt.outputs.as_ref().and_then(|outputs| outputs.first()) {
+        Some(output) => output_dir.join(&output.filename),
+        None => image_path(output_dir, index),
+    }
+}
+
+/// Ask the user to review the already generated image at `index`, regenerating it until it's kept
+///
+/// Updates the log on disk after each reroll. Returns false if the user chose to abort.
+fn review_image(
+    api: &dyn Backend,
+    log: &mut BatchLog,
+    index: usize,
+    template: Option<&BatchTemplate>,
+    rng: &mut BatchRng,
+    progress: &BatchProgress,
+) -> anyhow::Result<bool> {
+    let output_dir = log.destination().output_dir;
+    loop {
+        let action = review::ask_action(
+            index,
+            &saved_image_path(&output_dir, index, &log.images[index]),
+            &log.images[index],
+            template.is_some(),
+        )?;
+        match action {
+            ReviewAction::Keep => return Ok(true),
+            ReviewAction::Abort => return Ok(false),
+            // A cell on its own seed or prompt wouldn't compare with the rest of its grid
+            ReviewAction::RerollSeed | ReviewAction::RerollPrompt
+                if log.images[index].sweep_cell.is_some() =>
+            {
+                println!(
+                    "Image {} is a cell of a sweep grid, use Reroll to reroll the whole sweep",
+                    index
+                );
+                continue;
+            }
+            ReviewAction::RerollSeed => {
+                print_reroll_start(index);
+                log.images[index].seed = Some(rng.next_u32() as i64);
+            }
+            ReviewAction::RerollPrompt => {
+                let template = template.expect("prompt rerolls to only be offered with a template");
+                let pool_indices: Vec<usize> = (0..template.prompts.len()).collect();
+                let pool_index = *pool_indices
+                    .choose(rng)
+                    .expect("template to have at least one prompt");
+                println!("Regenerating image {} with a new prompt...", index);
+                let mut prompt =
+                    template.generate_log_for_prompt(pool_index, rng, &template.wildcards())?;
+                template.resolve_image_files(&mut prompt, index)?;
+                log.images[index] = prompt;
+            }
+        }
+        let destination = log.destination();
+        BatchTemplate::generate_image(&destination, api, &mut log.images[index], index, progress)?;
+        log.write()?;
+    }
+}
+
+/// Stitch the grids of any sweeps in the log from the images generated so far
+fn write_sweep_grids(output_dir: &Path, images: &[PromptData]) -> anyhow::Result<()> {
+    for grid in sweep::write_grids(output_dir, images)? {
+        println!("Saved sweep grid {}", grid.display());
+    }
+    Ok(())
+}
+
 fn filter_if_not(prompt: &PromptData, activator: &&OneToManyPrompts) -> bool {
     match activator {
-        OneToManyPrompts::One(not_keyword) => {
-            return !prompt.positive.contains(not_keyword)
-        },
-        OneToManyPrompts::Many(not_keywords) => {
-            return !not_keywords.iter()
-                .any(|keyword| prompt.positive.contains(keyword))
-        },
+        OneToManyPrompts::One(not_keyword) => !prompt.positive.contains(not_keyword),
+        OneToManyPrompts::Many(not_keywords) => !not_keywords
+            .iter()
+            .any(|keyword| prompt.positive.contains(keyword)),
     }
 }
 
@@ -350,6 +1424,22 @@ pub enum Prompts {
     /// Like Multiple, but with some options more likely to be picked than others
     /// The sum of the specified chances must add up to 1.0
     MultipleWeighted(Vec<WeightedPrompt>),
+    /// Prompt with its own settings, which replace the base prompt's
+    ///
+    /// ex., {"prompt": "1girl, solo", "img2img": {"init_image": "girl.png", "denoising_strength": 0.6}}
+    Detailed(DetailedPrompt),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DetailedPrompt {
+    /// Prompt string to use
+    prompt: String,
+    /// img2img settings to use for this prompt
+    img2img: Option<Img2ImgSettings>,
+    /// Inpainting settings to use for this prompt
+    inpaint: Option<InpaintSettings>,
+    /// ControlNet units to use for this prompt, ex. a pose for each prompt
+    controlnet: Option<Vec<ControlNetUnit>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -364,13 +1454,12 @@ pub struct WeightedPrompt {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
-pub enum OneToManyPrompts
-{
+pub enum OneToManyPrompts {
     One(String),
-    Many(Vec<String>)
+    Many(Vec<String>),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct PromptModifer {
     /// Prompt string to use
     prompt: String,
@@ -384,6 +1473,22 @@ pub struct PromptModifer {
     /// If set, the modifier will only be considered if the selected prompt does not contain given string(s)
     #[serde(rename = "if-not")]
     if_not_activator: Option<OneToManyPrompts>,
+    /// LoRAs to add to the prompt along with the modifier
+    loras: Option<Vec<LoraEntry>>,
+    /// Textual inversion embeddings to add to the positive prompt along with the modifier
+    embeddings: Option<Vec<String>>,
+}
+
+impl PromptModifer {
+    /// The modifier's prompt, or the names of its LoRAs and embeddings if it only adds those
+    fn label(&self) -> String {
+        if !self.prompt.trim().is_empty() {
+            return self.prompt.clone();
+        }
+        let loras = self.loras.iter().flatten().map(|lora| lora.name.clone());
+        let embeddings = self.embeddings.iter().flatten().cloned();
+        loras.chain(embeddings).collect::<Vec<_>>().join(", ")
+    }
 }
 
 impl Probable for WeightedPrompt {
@@ -397,18 +1502,136 @@ pub struct BatchLog {
     /// Template name used for generation
     template: String,
 
+    /// Template file used for generation, if it was loaded from one
+    template_file: Option<PathBuf>,
+
+    /// Master seed the template run was generated with
+    seed: Option<u64>,
+
+    /// Kind of server the images were generated with
+    backend: Option<BackendKind>,
+
     /// Generated images
     images: Vec<PromptData>,
 
+    /// Indices of images that failed to generate with --continue-on-error, for Resume to retry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<usize>,
+
     #[serde(skip)]
     file_path: PathBuf,
 }
 
+/// Byte offset of the `}` closing the `{` that `text` starts with
+fn matching_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Split on `separator`, ignoring separators inside nested variants
+fn split_top_level<'a>(text: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut part_start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i..].starts_with('{') {
+            depth += 1;
+        } else if text[i..].starts_with('}') {
+            depth -= 1;
+        } else if depth == 0 && text[i..].starts_with(separator) {
+            parts.push(&text[part_start..i]);
+            i += separator.len();
+            part_start = i;
+            continue;
+        }
+        i += text[i..].chars().next().map_or(1, char::len_utf8);
+    }
+    parts.push(&text[part_start..]);
+    parts
+}
+
+/// Parse the `2` or `1-3` count of options to pick from a variant
+fn parse_variant_count(count: &str) -> anyhow::Result<(usize, usize)> {
+    let parsed = match count.split_once('-') {
+        Some((min, max)) => min
+            .trim()
+            .parse::<usize>()
+            .and_then(|min| Ok((min, max.trim().parse::<usize>()?))),
+        None => count.trim().parse::<usize>().map(|count| (count, count)),
+    };
+    match parsed {
+        Ok((min, max)) if min <= max => Ok((min, max)),
+        _ => Err(BatchError {
+            message: format!("invalid variant count \"{}\"", count),
+        }
+        .into()),
+    }
+}
+
+/// Resolve `path` from `base_dir` to an existing image file, or if it's a directory,
+/// to the image at `index` in it, wrapping around when there are fewer images
+fn resolve_image_file(
+    base_dir: &Path,
+    path: &str,
+    index: usize,
+    description: &str,
+) -> anyhow::Result<String> {
+    let mut image = base_dir.join(path);
+    if image.is_dir() {
+        let images = list_images(&image)?;
+        if images.is_empty() {
+            return Err(BatchError {
+                message: format!("no {} files found in {}", description, image.display()),
+            }
+            .into());
+        }
+        image = images[index % images.len()].clone();
+    } else if !image.exists() {
+        return Err(BatchError {
+            message: format!("{} {} not found", description, image.display()),
+        }
+        .into());
+    }
+    Ok(image.to_string_lossy().into_owned())
+}
+
+/// Image files in the given directory, sorted by name
+fn list_images(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut images = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_image = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                ["png", "jpg", "jpeg", "webp"].contains(&ext.to_lowercase().as_str())
+            });
+        if is_image {
+            images.push(path);
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
 fn create_file_and_dir(
     output_dir: &Path,
     dest_filename: &Path,
 ) -> anyhow::Result<(PathBuf, fs::File)> {
-    let dest: PathBuf = PathBuf::from(output_dir).join(&dest_filename);
+    let dest: PathBuf = PathBuf::from(output_dir).join(dest_filename);
     std::fs::create_dir_all(output_dir)?;
     let dest_file = fs::File::create(&dest)?;
     Ok((dest, dest_file))
@@ -416,19 +1639,22 @@ fn create_file_and_dir(
 
 fn get_safe_filename(name: &str) -> String {
     // TODO: could do more to strip unsafe characters, but this is good enough for now
-    let sanitized_filename = name.replace(" ", "-");
-    return sanitized_filename;
+    name.replace(' ', "-")
 }
 
 impl BatchLog {
     fn new(name: &str, output_dir: &Path) -> BatchLog {
         let mut file_path = PathBuf::from(output_dir);
         file_path.push(Self::safe_logfile_name(name));
-        return BatchLog {
+        BatchLog {
             template: name.to_owned(),
+            template_file: None,
+            seed: None,
+            backend: None,
             images: vec![],
-            file_path
-        };
+            failed: vec![],
+            file_path,
+        }
     }
 
     fn from_file(file_path: &str) -> anyhow::Result<BatchLog> {
@@ -443,7 +1669,20 @@ impl BatchLog {
         let timestamp = format!("{}", timestamp.format("%Y-%m-%d-%H%M"));
 
         let sanitized_filename = get_safe_filename(name);
-        return format!("{}-{}.json", sanitized_filename, timestamp).into();
+        format!("{}-{}.json", sanitized_filename, timestamp).into()
+    }
+
+    /// Where the log's images are saved, next to the log file
+    fn destination(&self) -> ImageDestination {
+        ImageDestination {
+            output_dir: self
+                .file_path
+                .parent()
+                .expect("log file to be in a directory")
+                .to_path_buf(),
+            template: self.template.clone(),
+            log_file: self.file_path.clone(),
+        }
     }
 
     /// Serialize to JSON and write to disk
@@ -472,40 +1711,108 @@ impl BatchLog {
 pub struct TemplateRunResults {
     pub images_created: usize,
     pub log_file: PathBuf,
+    pub seed: Option<u64>,
 }
 
 pub fn do_run(
-    dry_run: bool,
-    sequential: bool,
     template_filename: &str,
     output_dir: &str,
-    api_url: Option<&str>,
+    options: &RunOptions,
 ) -> anyhow::Result<TemplateRunResults> {
-    let json_string = fs::read_to_string(template_filename)?;
-    let template: BatchTemplate = serde_json::from_str(&json_string)?;
+    let template = BatchTemplate::from_file(Path::new(template_filename))?;
 
     let output_dir = PathBuf::from(output_dir);
 
-    let batch_log = template.run(dry_run, &output_dir, sequential, api_url)?;
-    // let log_file = batch_log.write(&output_dir, &template.name)?;
+    template.run(&output_dir, options)
+}
+
+/// One error listing every problem found with a template
+fn problems_error(problems: &[Problem]) -> anyhow::Error {
+    let mut message = format!(
+        "the template has {} problems, fix them or use --skip-preflight:",
+        problems.len()
+    );
+    for problem in problems {
+        message.push_str(&format!("\n  {}", problem));
+    }
+    BatchError { message }.into()
+}
+
+/// Write an HTML gallery of the log's images next to it, returning the gallery's path
+pub fn gallery(file_path: &str) -> anyhow::Result<PathBuf> {
+    let log = BatchLog::from_file(file_path)?;
+    gallery::write_gallery(&log)
+}
+
+/// Save contact sheets of the log's images next to it, returning their paths
+pub fn sheet(file_path: &str, settings: &SheetSettings) -> anyhow::Result<Vec<PathBuf>> {
+    let log = BatchLog::from_file(file_path)?;
+    sheet::write_sheets(&log, settings)
+}
+
+/// Check the template for problems, and unless `offline`, that the first server has everything it uses
+pub fn validate(
+    template_filename: &str,
+    server: &ServerOptions,
+    offline: bool,
+) -> anyhow::Result<Vec<Problem>> {
+    let template = BatchTemplate::from_file(Path::new(template_filename))?;
+    let mut problems = validate::check_template(&template, false);
+    if !offline {
+        let backend = server.backend.or(template.backend).unwrap_or_default();
+        let api = get_api_client(server, backend, &None, &None)?;
+        problems.extend(validate::check_server(&template, api.as_ref())?);
+    }
+    Ok(problems)
+}
+
+/// Everything of a kind the first server has, ex. every model
+///
+/// Prints nothing, so the list can be piped elsewhere
+pub fn list_inventory(
+    server: &ServerOptions,
+    kind: Inventory,
+) -> anyhow::Result<Vec<InventoryItem>> {
+    let backend = server.backend.unwrap_or_default();
+    let urls = server.urls(backend)?;
+    let api = new_client(backend, &urls[0], &server.retry, &None, &None)?;
+    api.inventory(kind)
+}
 
-    Ok(TemplateRunResults {
-        images_created: batch_log.images.len(),
-        log_file: batch_log.file_path,
-    })
+/// Tell the user which images failed to generate, if any did
+fn report_failures(failed: &[usize]) {
+    if failed.is_empty() {
+        return;
+    }
+    let indices: Vec<String> = failed.iter().map(|index| index.to_string()).collect();
+    println!(
+        "{} images failed to generate: {}, use Resume to try them again",
+        failed.len(),
+        indices.join(", ")
+    );
 }
 
 fn print_reroll_start(index: usize) {
     println!("Regenerating image {} with a new seed...", index);
 }
 
-pub fn reroll(file_path: &str, index: usize, api_url: Option<&str>) -> anyhow::Result<()> {
+/// Regenerate the image at `index` with a new seed, or only the `batch_index`th image of its batch
+///
+/// The cells of a sweep are all regenerated with one new seed, so its grid still only compares the
+/// swept settings.
+pub fn reroll(
+    file_path: &str,
+    index: usize,
+    batch_index: Option<usize>,
+    server: &ServerOptions,
+    quiet: bool,
+) -> anyhow::Result<()> {
     let mut log = BatchLog::from_file(file_path)?;
 
     let path = PathBuf::from(file_path);
     let output_dir = path.parent().expect("couldn't get folder from file_path");
 
-    match log.images.iter().nth(index) {
+    match log.images.get(index) {
         None => Err(BatchError {
             message: format!(
                 "Reroll error: Log file contains less than {} images",
@@ -514,99 +1821,822 @@ pub fn reroll(file_path: &str, index: usize, api_url: Option<&str>) -> anyhow::R
         }
         .into()),
         Some(prompt) => {
-            let api = get_api_client(api_url, &None, &None)?;
-
-            let mut updated_prompt = prompt.to_owned();
-            updated_prompt.seed = None;
-            print_reroll_start(index);
-            BatchTemplate::generate_image(output_dir, &api, &mut updated_prompt, index)?;
-            log.images[index] = updated_prompt;
-            let dest_file = fs::File::create(path)?;
-            log.write_update(&dest_file)?;
+            let sweep = prompt.sweep_cell.as_ref().map(SweepCell::sweep);
+            if sweep.is_some() && batch_index.is_some() {
+                return Err(BatchError {
+                    message: format!(
+                        "image {} is a cell of a sweep grid, reroll it without a batch index to reroll the whole sweep",
+                        index
+                    ),
+                }
+                .into());
+            }
+            let backend = server.backend.or(log.backend).unwrap_or_default();
+            let api = get_api_client(server, backend, &None, &None)?;
 
-            Ok(())
+            let mut result = Ok(());
+            match batch_index {
+                None => {
+                    let (indices, seed) = match sweep {
+                        Some(sweep) => (
+                            sweep::cells_of(&log.images, sweep),
+                            Some(BatchRng::from_entropy().next_u32() as i64),
+                        ),
+                        None => (vec![index], None),
+                    };
+                    let mut progress =
+                        BatchProgress::new(ProgressMode::detect(quiet), indices.len());
+                    for index in indices {
+                        let mut updated_prompt = log.images[index].to_owned();
+                        updated_prompt.seed = seed;
+                        progress.start_image();
+                        print_reroll_start(index);
+                        result = BatchTemplate::generate_image(
+                            &log.destination(),
+                            api.as_ref(),
+                            &mut updated_prompt,
+                            index,
+                            &progress,
+                        );
+                        if result.is_err() {
+                            break;
+                        }
+                        log.images[index] = updated_prompt;
+                    }
+                }
+                Some(batch_index) => {
+                    let mut progress = BatchProgress::new(ProgressMode::detect(quiet), 1);
+                    let mut updated_prompt = log.images[index].to_owned();
+                    progress.start_image();
+                    println!(
+                        "Regenerating image {} of batch {} with a new seed...",
+                        batch_index, index
+                    );
+                    result = BatchTemplate::generate_batch_image(
+                        &log.destination(),
+                        api.as_ref(),
+                        &mut updated_prompt,
+                        index,
+                        batch_index,
+                        None,
+                        &progress,
+                    );
+                    if result.is_ok() {
+                        log.images[index] = updated_prompt;
+                    }
+                }
+            }
+            // Record the sweep's cells rerolled so far, even if one failed
+            let dest_file = fs::File::create(&path)?;
+            log.write_update(&dest_file)?;
+            write_sweep_grids(output_dir, &log.images)?;
+            result
         }
     }
 }
 
-pub fn resume(file_path: &str, api_url: Option<&str>) -> anyhow::Result<u32> {
-    let mut missing_images_created: u32 = 0;
+pub fn resume(
+    file_path: &str,
+    server: &ServerOptions,
+    quiet: bool,
+    continue_on_error: bool,
+) -> anyhow::Result<u32> {
     let mut log = BatchLog::from_file(file_path)?;
 
     let path = PathBuf::from(file_path);
     let output_dir = path.parent().expect("couldn't get folder from file_path");
 
-    let api = get_api_client(api_url, &None, &None)?;
+    let backend = server.backend.or(log.backend).unwrap_or_default();
+    let missing = missing_jobs(&log, output_dir, backend);
+    if let Some(job) = missing.first() {
+        println!("Resuming starting with first missing image: {}", job.index);
+    }
+    let servers = get_api_clients(server, backend, &None, &None)?;
+    let results = pool::generate(
+        &servers,
+        &log.destination(),
+        missing,
+        ProgressMode::detect(quiet),
+        continue_on_error,
+        |job| {
+            job.record(&mut log.images);
+            Ok(())
+        },
+    );
+    // Every missing image was tried again, so only this attempt's failures are left
+    if let Ok(results) = &results {
+        log.failed = results.failed.clone();
+    }
+    // Record whatever was generated, even if some images failed
+    let dest_file = fs::File::create(&path)?;
+    log.write_update(&dest_file)?;
+    write_sweep_grids(output_dir, &log.images)?;
+    report_failures(&log.failed);
 
-    let mut output_dir_entries = fs::read_dir(output_dir)?
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, std::io::Error>>()?;
+    Ok(results?.generated as u32)
+}
 
-    output_dir_entries.sort();
-    let mut existing_image_indices = vec![];
-    for file_path in output_dir_entries {
-        match file_path.extension() {
+/// Jobs for the prompts that were never generated, and the images missing from a generated batch
+fn missing_jobs(log: &BatchLog, output_dir: &Path, backend: BackendKind) -> Vec<Job> {
+    let mut missing: Vec<Job> = vec![];
+    for (index, prompt) in log.images.iter().enumerate() {
+        match &prompt.outputs {
             None => {
-                continue;
+                if !image_path(output_dir, index).exists() {
+                    let mut prompt = prompt.to_owned();
+                    // Sweep cells keep the seed they share with the rest of their grid
+                    if prompt.sweep_cell.is_none() {
+                        prompt.seed = None;
+                    }
+                    missing.push(Job::new(index, prompt));
+                }
             }
-            Some(ext) => {
-                if ext != "png" {
-                    continue;
+            Some(outputs) => {
+                let missing_batch_images: Vec<usize> = (0..outputs.len())
+                    .filter(|&batch_index| {
+                        !output_dir.join(&outputs[batch_index].filename).exists()
+                    })
+                    .collect();
+                match backend {
+                    // ComfyUI makes the noise for a whole batch from one seed,
+                    // so a single image can only be restored by generating its batch again
+                    BackendKind::ComfyUI if !missing_batch_images.is_empty() => {
+                        let mut prompt = prompt.to_owned();
+                        prompt.seed = outputs.first().map(|output| output.seed);
+                        missing.push(Job::new(index, prompt));
+                    }
+                    _ => {
+                        // Reuse the recorded seed so the batch is restored as it was
+                        missing.extend(missing_batch_images.into_iter().map(|batch_index| Job {
+                            index,
+                            batch_index: Some(batch_index),
+                            seed: Some(outputs[batch_index].seed),
+                            prompt: prompt.to_owned(),
+                        }));
+                    }
                 }
             }
         }
+    }
+    missing
+}
 
-        if let Some(file_name) = file_path.file_stem() {
-            if let Some(image_index) =
-                usize::from_str_radix(file_name.to_str().unwrap_or(""), 10).ok()
-            {
-                existing_image_indices.push(image_index);
-            }
-        }
+pub fn reroll_all(
+    file_path: &str,
+    server: &ServerOptions,
+    quiet: bool,
+    continue_on_error: bool,
+) -> anyhow::Result<()> {
+    let mut log = BatchLog::from_file(file_path)?;
+
+    let path = PathBuf::from(file_path);
+    let output_dir = path.parent().expect("couldn't get folder from file_path");
+
+    // Every cell of a sweep gets the same new seed, so its grid still only compares the swept settings
+    let mut rng = BatchRng::from_entropy();
+    let mut sweep_seeds: HashMap<usize, i64> = HashMap::new();
+    let jobs = log
+        .images
+        .iter()
+        .enumerate()
+        .map(|(index, prompt)| {
+            let mut prompt = prompt.to_owned();
+            prompt.seed = prompt.sweep_cell.as_ref().map(|cell| {
+                *sweep_seeds
+                    .entry(cell.sweep())
+                    .or_insert_with(|| rng.next_u32() as i64)
+            });
+            Job::new(index, prompt)
+        })
+        .collect();
+    let backend = server.backend.or(log.backend).unwrap_or_default();
+    let servers = get_api_clients(server, backend, &None, &None)?;
+    let rerolled = pool::generate(
+        &servers,
+        &log.destination(),
+        jobs,
+        ProgressMode::detect(quiet),
+        continue_on_error,
+        |job| {
+            job.record(&mut log.images);
+            Ok(())
+        },
+    );
+    if let Ok(results) = &rerolled {
+        log.failed = results.failed.clone();
     }
+    let dest_file = fs::File::create(&path)?;
+    log.write_update(&dest_file)?;
+    write_sweep_grids(output_dir, &log.images)?;
+    report_failures(&log.failed);
+    rerolled?;
 
-    let mut first = true;
-    for (index, prompt) in log.images.clone().iter().enumerate() {
-        if existing_image_indices.contains(&index) {
+    Ok(())
+}
+
+/// Upscale every generated image in the log on the server, keeping the originals next to them
+///
+/// The upscale is added to each prompt's post-processing, so rerolled and resumed images are upscaled
+/// too. Images whose prompt already has the same upscale are skipped. Returns the number of images
+/// upscaled.
+pub fn upscale(
+    file_path: &str,
+    settings: &UpscaleSettings,
+    server: &ServerOptions,
+) -> anyhow::Result<usize> {
+    let mut log = BatchLog::from_file(file_path)?;
+    let output_dir = log.destination().output_dir;
+
+    let backend = server.backend.or(log.backend).unwrap_or_default();
+    let api = get_api_client(server, backend, &None, &None)?;
+
+    let mut images_upscaled = 0;
+    let mut result = Ok(());
+    'images: for (index, prompt) in log.images.iter_mut().enumerate() {
+        let mut steps = prompt.post_process.clone().unwrap_or_default();
+        let has_upscale = steps
+            .iter()
+            .any(|step| matches!(step, PostProcesses::Upscale(upscale) if upscale == settings));
+        if has_upscale {
+            println!("Image {} is already upscaled with these settings", index);
             continue;
-        } else if first {
-            println!("Resuming starting with first missing image: {}", index);
-            first = false;
         }
-        println!("Generating image {}...", index);
+        steps.push(PostProcesses::Upscale(settings.clone()));
+        let (format, quality) = post_process::output_format(&steps);
+        let Some(outputs) = prompt.outputs.as_mut() else {
+            println!(
+                "Image {} hasn't been generated yet, it will be upscaled when Resume creates it",
+                index
+            );
+            prompt.post_process = Some(steps);
+            continue;
+        };
+        for output in outputs {
+            if !output_dir.join(&output.filename).exists() {
+                println!("{} is missing, use Resume to create it", output.filename);
+                continue;
+            }
+            println!("Upscaling image {}...", output.filename);
+            result = upscale_image(api.as_ref(), &output_dir, output, settings, format, quality);
+            if result.is_err() {
+                break 'images;
+            }
+            images_upscaled += 1;
+        }
+        prompt.post_process = Some(steps);
+    }
+    // Record the images upscaled so far, even if one failed
+    log.write()?;
+    write_sweep_grids(&output_dir, &log.images)?;
+    result?;
 
-        let mut updated_prompt = prompt.to_owned();
-        updated_prompt.seed = None;
-        BatchTemplate::generate_image(output_dir, &api, &mut updated_prompt, index)?;
-        log.images[index] = updated_prompt;
+    Ok(images_upscaled)
+}
 
-        missing_images_created += 1;
+/// Replace the output with its upscaled image, saved in `format` and keeping its embedded texts,
+/// and keep the image it replaces as the original if there isn't one yet
+fn upscale_image(
+    api: &dyn Backend,
+    output_dir: &Path,
+    output: &mut GeneratedImage,
+    settings: &UpscaleSettings,
+    format: OutputFormat,
+    quality: u8,
+) -> anyhow::Result<()> {
+    let path = output_dir.join(&output.filename);
+    let image_bytes = fs::read(&path)?;
+    let upscaled = image::load_from_memory(&api.upscale(&image_bytes, settings)?)?;
+
+    let texts = metadata::read_texts(&image_bytes).unwrap_or_default();
+    let texts: Vec<(&str, String)> = texts
+        .iter()
+        .map(|(key, text)| (key.as_str(), text.clone()))
+        .collect();
+    let bytes = post_process::encode(&upscaled, format, quality)?;
+    let bytes = metadata::embed_texts(&bytes, format, &texts)?;
+    if output.original.is_none() {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let original = format!("{}-original.{}", stem, extension);
+        fs::rename(&path, output_dir.join(&original))?;
+        output.original = Some(original);
     }
-    let dest_file = fs::File::create(path)?;
-    log.write_update(&dest_file)?;
-
-    Ok(missing_images_created)
+    fs::write(&path, bytes)?;
+    Ok(())
 }
 
-pub fn reroll_all(file_path: &str, api_url: Option<&str>) -> anyhow::Result<()> {
-    // let mut log = BatchLog::from_file(file_path)?;
+/// Step through the images of a previous run, letting the user keep or reroll each one
+///
+/// Rerolling with a new prompt needs the template, which is taken from `template_file`
+/// or the file recorded in the log. Returns the number of images reviewed.
+pub fn review(
+    file_path: &str,
+    template_file: Option<&str>,
+    server: &ServerOptions,
+    quiet: bool,
+) -> anyhow::Result<usize> {
     let mut log = BatchLog::from_file(file_path)?;
 
     let path = PathBuf::from(file_path);
     let output_dir = path.parent().expect("couldn't get folder from file_path");
 
-    let api = get_api_client(api_url, &None, &None)?;
+    let template_file = template_file
+        .map(PathBuf::from)
+        .or(log.template_file.clone());
+    let template = match template_file {
+        None => None,
+        Some(template_file) => match BatchTemplate::from_file(&template_file) {
+            Ok(template) => Some(template),
+            Err(e) => {
+                println!(
+                    "Unable to load template {}, rerolling with a new prompt is disabled: {}",
+                    template_file.display(),
+                    e
+                );
+                None
+            }
+        },
+    };
+
+    let backend = server.backend.or(log.backend).unwrap_or_default();
+    let api = get_api_client(server, backend, &None, &None)?;
+    let mut rng = BatchRng::from_entropy();
+    let mut progress = BatchProgress::new(ProgressMode::detect(quiet), log.images.len());
+
+    let mut images_reviewed = 0;
+    for index in 0..log.images.len() {
+        progress.start_image();
+        if !saved_image_path(output_dir, index, &log.images[index]).exists() {
+            println!(
+                "Image {} hasn't been generated yet, use Resume to create it",
+                index
+            );
+            continue;
+        }
+        if !review_image(
+            api.as_ref(),
+            &mut log,
+            index,
+            template.as_ref(),
+            &mut rng,
+            &progress,
+        )? {
+            break;
+        }
+        images_reviewed += 1;
+    }
+    write_sweep_grids(output_dir, &log.images)?;
+
+    Ok(images_reviewed)
+}
 
-    for (index, prompt) in log.images.clone().iter().enumerate() {
-        print_reroll_start(index);
+/// An image made outside of sdbatch, with the prompt read from its parameters
+struct ImportedImage {
+    path: PathBuf,
+    prompt: PromptData,
+    /// The "parameters" text the prompt was read from
+    parameters: String,
+}
 
-        let mut updated_prompt = prompt.to_owned();
-        updated_prompt.seed = None;
-        BatchTemplate::generate_image(output_dir, &api, &mut updated_prompt, index)?;
-        log.images[index] = updated_prompt;
+/// Read the generation parameters Automatic1111 embedded in each PNG, and the PNGs in each directory
+fn read_imported_images(files: &[String]) -> anyhow::Result<Vec<ImportedImage>> {
+    let mut images = vec![];
+    for file in files {
+        let path = PathBuf::from(file);
+        let paths = if path.is_dir() {
+            list_images(&path)?
+        } else {
+            vec![path]
+        };
+        for path in paths {
+            let png = fs::read(&path)?;
+            let parameters = metadata::read_text_chunks(&png).ok().and_then(|texts| {
+                texts
+                    .into_iter()
+                    .find(|(key, _)| key == metadata::PARAMETERS_KEY)
+            });
+            match parameters {
+                Some((_, parameters)) => {
+                    let prompt = metadata::parse_infotext(&parameters).map_err(|e| BatchError {
+                        message: format!(
+                            "unable to read the parameters of {}: {}",
+                            path.display(),
+                            e
+                        ),
+                    })?;
+                    images.push(ImportedImage {
+                        path,
+                        prompt,
+                        parameters,
+                    });
+                }
+                None => println!(
+                    "Skipping {}, it has no generation parameters",
+                    path.display()
+                ),
+            }
+        }
     }
-    let dest_file = fs::File::create(path)?;
-    log.write_update(&dest_file)?;
+    if images.is_empty() {
+        return Err(BatchError {
+            message: "none of the images have generation parameters to import".to_string(),
+        }
+        .into());
+    }
+    Ok(images)
+}
 
-    Ok(())
+/// Create a template with the prompts of the imported images as its pool
+///
+/// The other settings are taken from the first image. Returns the path of the template.
+pub fn import_template(files: &[String], name: &str, output_dir: &Path) -> anyhow::Result<PathBuf> {
+    let images = read_imported_images(files)?;
+    let mut base_prompt = images[0].prompt.clone();
+    base_prompt.positive = String::new();
+    base_prompt.seed = None;
+    if images
+        .iter()
+        .any(|image| image.prompt.negative != base_prompt.negative)
+    {
+        println!("The images have different negative prompts, using the first image's");
+    }
+    let template = BatchTemplate {
+        name: name.to_string(),
+        base_prompt,
+        prompts: images
+            .into_iter()
+            .map(|image| Prompts::Single(image.prompt.positive))
+            .collect(),
+        ..Default::default()
+    };
+    Ok(output_dir.join(template.write(output_dir)?))
+}
+
+/// Create a log with an entry for each imported image, copying the images next to it
+///
+/// Each image is recorded with the seed it was generated with, so Reroll and Resume can work on them
+/// like images sdbatch generated. Images already in the output directory are never overwritten,
+/// unless they are the image being imported. Returns the path of the log.
+pub fn import_log(files: &[String], name: &str, output_dir: &Path) -> anyhow::Result<PathBuf> {
+    let images = read_imported_images(files)?;
+    // Check every image before copying any, so a failed import leaves the directory as it was
+    let mut copies = vec![];
+    for (index, image) in images.iter().enumerate() {
+        let dest = image_path(output_dir, index);
+        if !dest.exists() {
+            copies.push((&image.path, dest));
+        } else if fs::canonicalize(&image.path)? != fs::canonicalize(&dest)? {
+            return Err(BatchError {
+                message: format!(
+                    "{} already exists, import into an empty or new directory",
+                    dest.display()
+                ),
+            }
+            .into());
+        }
+    }
+    fs::create_dir_all(output_dir)?;
+    for (source, dest) in copies {
+        fs::copy(source, dest)?;
+    }
+
+    let mut log = BatchLog::new(name, output_dir);
+    for (index, image) in images.into_iter().enumerate() {
+        let mut prompt = image.prompt;
+        prompt.outputs = Some(vec![GeneratedImage {
+            filename: image_filename(index, 0),
+            seed: prompt.seed.unwrap_or(-1),
+            subseed: -1,
+            info: image.parameters,
+            original: None,
+        }]);
+        log.images.push(prompt);
+    }
+    log.write()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::test_dir;
+    use super::*;
+
+    fn test_template() -> BatchTemplate {
+        BatchTemplate {
+            name: "test".to_string(),
+            base_prompt: PromptData {
+                positive: "masterpiece".to_string(),
+                ..Default::default()
+            },
+            prompts: vec![
+                Prompts::Single("1girl, solo".to_string()),
+                Prompts::Multiple(vec![
+                    "1boy, suit".to_string(),
+                    "1boy, hoodie".to_string(),
+                    "1boy, armor".to_string(),
+                ]),
+                Prompts::MultipleWeighted(vec![
+                    WeightedPrompt {
+                        prompt: "cat".to_string(),
+                        chance: Some(0.25),
+                    },
+                    WeightedPrompt {
+                        prompt: "dog".to_string(),
+                        chance: Some(0.75),
+                    },
+                ]),
+                Prompts::Single("landscape".to_string()),
+            ],
+            modifiers: Some(vec![
+                PromptModifer {
+                    prompt: "smiling".to_string(),
+                    chance: Some(0.5),
+                    if_activator: None,
+                    if_not_activator: None,
+                    ..Default::default()
+                },
+                PromptModifer {
+                    prompt: "night".to_string(),
+                    chance: None,
+                    if_activator: None,
+                    if_not_activator: None,
+                    ..Default::default()
+                },
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn positives_and_seeds(logs: &[PromptData]) -> Vec<(String, Option<i64>)> {
+        logs.iter().map(|p| (p.positive.clone(), p.seed)).collect()
+    }
+
+    #[test]
+    fn same_master_seed_generates_same_logs() {
+        let template = test_template();
+        let first = template
+            .generate_logs(3, false, &mut BatchRng::seed_from_u64(1234))
+            .unwrap();
+        let second = template
+            .generate_logs(3, false, &mut BatchRng::seed_from_u64(1234))
+            .unwrap();
+
+        assert_eq!(positives_and_seeds(&first), positives_and_seeds(&second));
+    }
+
+    #[test]
+    fn different_master_seeds_generate_different_logs() {
+        let template = test_template();
+        let first = template
+            .generate_logs(4, false, &mut BatchRng::seed_from_u64(1))
+            .unwrap();
+        let second = template
+            .generate_logs(4, false, &mut BatchRng::seed_from_u64(2))
+            .unwrap();
+
+        assert_ne!(positives_and_seeds(&first), positives_and_seeds(&second));
+    }
+
+    #[test]
+    fn batch_image_filenames() {
+        assert_eq!(image_filename(3, 0), "03.png");
+        assert_eq!(image_filename(3, 2), "03-2.png");
+        assert_eq!(image_filename(120, 1), "120-1.png");
+    }
+
+    #[test]
+    fn txt2img_info_records_each_image() {
+        let info: Txt2ImgInfo = serde_json::from_str(
+            r#"{"all_seeds": [10, 11], "all_subseeds": [20, 21], "infotexts": ["a", "b"], "index_of_first_image": 1}"#,
+        )
+        .unwrap();
+        let record = info.image_record(1, image_filename(0, 1));
+        assert_eq!(record.filename, "00-1.png");
+        assert_eq!(record.seed, 11);
+        assert_eq!(record.subseed, 21);
+        assert_eq!(record.info, "b");
+
+        let info: Txt2ImgInfo = serde_json::from_str(r#"{"all_seeds": [10]}"#).unwrap();
+        assert_eq!(info.index_of_first_image, 0);
+        assert_eq!(info.image_record(0, image_filename(0, 0)).subseed, -1);
+    }
+
+    #[test]
+    fn post_process_accepts_one_step_or_a_list() {
+        let steps = |post_process: &str| {
+            let mut prompt = serde_json::to_value(PromptData::default()).unwrap();
+            prompt["post_process"] = serde_json::from_str(post_process).unwrap();
+            serde_json::from_value::<PromptData>(prompt)
+                .unwrap()
+                .post_process
+        };
+        assert_eq!(steps(r#"{"Resize": {"scale_by": 2.0}}"#).unwrap().len(), 1);
+        let list = steps(r#"[{"Crop": {"width": 512, "height": 512}}, "KeepOriginal"]"#).unwrap();
+        assert!(matches!(list[1], PostProcesses::KeepOriginal));
+        assert!(steps("null").is_none());
+    }
+
+    #[test]
+    fn init_image_directories_are_cycled() {
+        let dir = test_dir();
+        for name in ["b.png", "a.png", "notes.txt"] {
+            fs::write(dir.join(name), []).unwrap();
+        }
+
+        let mut template = test_template();
+        template.base_prompt.img2img = Some(Img2ImgSettings {
+            init_image: dir.to_string_lossy().into_owned(),
+            denoising_strength: 0.5,
+            ..Default::default()
+        });
+        let mut prompts = template
+            .generate_logs(3, true, &mut BatchRng::seed_from_u64(0))
+            .unwrap();
+        for (index, prompt) in prompts.iter_mut().enumerate() {
+            template.resolve_image_files(prompt, index).unwrap();
+        }
+
+        let init_images: Vec<_> = prompts
+            .iter()
+            .map(|p| p.img2img.as_ref().unwrap().init_image.clone())
+            .collect();
+        let expected: Vec<_> = ["a.png", "b.png", "a.png"]
+            .iter()
+            .map(|name| dir.join(name).to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(init_images, expected);
+    }
+
+    #[test]
+    fn comfyui_batches_are_resumed_whole() {
+        let dir = test_dir();
+        fs::write(dir.join("00.png"), []).unwrap();
+        let mut log = BatchLog::new("missing", &dir);
+        log.images.push(PromptData {
+            seed: Some(-1),
+            outputs: Some(
+                (0..2)
+                    .map(|batch_index| GeneratedImage {
+                        filename: image_filename(0, batch_index),
+                        seed: 100,
+                        subseed: 0,
+                        info: String::new(),
+                        original: None,
+                    })
+                    .collect(),
+            ),
+            ..Default::default()
+        });
+
+        let jobs = missing_jobs(&log, &dir, BackendKind::Auto1111);
+        assert_eq!(jobs.len(), 1);
+        assert_eq!((jobs[0].batch_index, jobs[0].seed), (Some(1), Some(100)));
+
+        let jobs = missing_jobs(&log, &dir, BackendKind::ComfyUI);
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].batch_index, None);
+        assert_eq!(jobs[0].prompt.seed, Some(100));
+    }
+
+    #[test]
+    fn wildcards_are_resolved_in_logs() {
+        let dir = test_dir();
+        fs::create_dir_all(dir.join("wildcards")).unwrap();
+        fs::write(dir.join("wildcards").join("colors.txt"), "red\n").unwrap();
+        fs::write(dir.join("wildcards").join("places.txt"), "beach\n").unwrap();
+
+        let template = BatchTemplate {
+            base_prompt: PromptData {
+                positive: "__colors__ theme".to_string(),
+                ..Default::default()
+            },
+            prompts: vec![Prompts::Single("__colors__ hair".to_string())],
+            modifiers: Some(vec![PromptModifer {
+                prompt: "at the __places__".to_string(),
+                chance: None,
+                if_activator: Some("red hair".to_string()),
+                if_not_activator: None,
+                ..Default::default()
+            }]),
+            file_path: Some(dir.join("template.json")),
+            ..Default::default()
+        };
+        let prompts = template
+            .generate_logs(1, true, &mut BatchRng::seed_from_u64(0))
+            .unwrap();
+        assert_eq!(prompts[0].positive, "red theme, red hair, at the beach");
+    }
+
+    #[test]
+    fn combinations_cover_every_option_and_modifier() {
+        let mut template = test_template();
+        template.modifiers.as_mut().unwrap().push(PromptModifer {
+            prompt: "barking".to_string(),
+            chance: Some(0.1),
+            if_activator: Some("dog".to_string()),
+            if_not_activator: None,
+            ..Default::default()
+        });
+        let combinations = template
+            .generate_combinations(&mut BatchRng::seed_from_u64(0))
+            .unwrap();
+        let positives: Vec<_> = combinations.iter().map(|p| p.positive.as_str()).collect();
+
+        // 7 prompt options, each alone and with the 2 modifiers, plus barking for the dog
+        assert_eq!(positives.len(), 7 * 3 + 1);
+        assert_eq!(
+            positives[..3],
+            [
+                "masterpiece, 1girl, solo",
+                "masterpiece, 1girl, solo, smiling",
+                "masterpiece, 1girl, solo, night"
+            ]
+        );
+        assert!(positives.contains(&"masterpiece, 1boy, armor, night"));
+        assert!(positives.contains(&"masterpiece, dog, barking"));
+        assert!(!positives.contains(&"masterpiece, cat, barking"));
+        assert!(combinations.iter().all(|p| p.seed.is_some()));
+    }
+
+    fn expand_variants(text: &str, seed: u64) -> String {
+        BatchTemplate::expand_variants(text, &mut BatchRng::seed_from_u64(seed)).unwrap()
+    }
+
+    #[test]
+    fn variants_pick_one_option() {
+        for seed in 0..20 {
+            let expanded = expand_variants("a {red|blue|green} dress", seed);
+            assert!(["a red dress", "a blue dress", "a green dress"].contains(&expanded.as_str()));
+        }
+        assert_eq!(expand_variants("no variants here", 0), "no variants here");
+    }
+
+    #[test]
+    fn variants_are_reproducible() {
+        let text = "{red|blue|green}, {2$$a|b|c|d}";
+        assert_eq!(expand_variants(text, 42), expand_variants(text, 42));
+    }
+
+    #[test]
+    fn variant_weights() {
+        for seed in 0..20 {
+            assert_eq!(expand_variants("{0::red|1::blue|0::green}", seed), "blue");
+            assert_eq!(expand_variants("{3$$0::red|blue|0::green}", seed), "blue");
+            let expanded = expand_variants("{note::foo|bar}", seed);
+            assert!(["note::foo", "bar"].contains(&expanded.as_str()));
+        }
+    }
+
+    #[test]
+    fn variants_pick_several() {
+        for seed in 0..20 {
+            let expanded = expand_variants("{2$$a|b|c}", seed);
+            let picked: Vec<_> = expanded.split(", ").collect();
+            assert_eq!(picked.len(), 2);
+            assert_ne!(picked[0], picked[1]);
+
+            let expanded = expand_variants("{1-2$$ and $$x|y}", seed);
+            assert!(["x", "y", "x and y", "y and x"].contains(&expanded.as_str()));
+        }
+        assert_eq!(expand_variants("{5$$a|b}", 0).len(), "a, b".len());
+    }
+
+    #[test]
+    fn nested_variants() {
+        for seed in 0..20 {
+            let expanded = expand_variants("{{light|dark} blue|2::{red|crimson}}", seed);
+            assert!(["light blue", "dark blue", "red", "crimson"].contains(&expanded.as_str()));
+        }
+    }
+
+    #[test]
+    fn braces_without_options_are_kept() {
+        assert_eq!(
+            expand_variants("{masterpiece}, cat", 0),
+            "{masterpiece}, cat"
+        );
+        assert_eq!(expand_variants("{{best quality}}", 0), "{{best quality}}");
+        assert_eq!(expand_variants("{{red|red}} {a|b", 0), "{red} {a|b");
+        assert_eq!(expand_variants("a|b} {", 0), "a|b} {");
+    }
+
+    #[test]
+    fn invalid_variants() {
+        let mut rng = BatchRng::seed_from_u64(0);
+        assert!(BatchTemplate::expand_variants("{x$$a|b}", &mut rng).is_err());
+        assert!(BatchTemplate::expand_variants("{3-1$$a|b}", &mut rng).is_err());
+        assert!(BatchTemplate::expand_variants("{-1::a|b}", &mut rng).is_err());
+        assert!(BatchTemplate::expand_variants("{0::a|0::b}", &mut rng).is_err());
+    }
+
+    #[test]
+    fn combine_prompts_adds_separator() {
+        assert_eq!(BatchTemplate::combine_prompts("a, b", "c"), "a, b, c");
+        assert_eq!(BatchTemplate::combine_prompts("a, b, ", "c"), "a, b, c");
+    }
+
+    #[test]
+    fn combine_prompts_skips_an_empty_base() {
+        assert_eq!(BatchTemplate::combine_prompts("", "c"), "c");
+        assert_eq!(BatchTemplate::combine_prompts(" ", "c, d"), "c, d");
+    }
 }
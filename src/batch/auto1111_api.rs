@@ -1,14 +1,14 @@
-use base64::{
-    alphabet,
-    engine::{self, general_purpose},
-    Engine as _,
-};
+use std::collections::BTreeMap;
 
-use reqwest::blocking::ClientBuilder;
+use base64::{engine::general_purpose, Engine as _};
+
+use reqwest::blocking::{ClientBuilder, Response};
 use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
-use super::BatchError;
+use super::backend::{Backend, Inventory, InventoryItem, Progress, StatusError};
+use super::{BatchError, ResizeMode, Txt2ImgInfo, UpscaleSettings};
 
 #[derive(Serialize, Deserialize)]
 struct Sampler {
@@ -29,7 +29,45 @@ struct SDModel {
 #[derive(Deserialize)]
 struct Upscaler {
     name: String,
-    scale: f32,
+    scale: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct LatentUpscaleMode {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Lora {
+    name: String,
+    alias: Option<String>,
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Embeddings {
+    /// Embeddings usable with the current model, by name
+    loaded: BTreeMap<String, Embedding>,
+    /// Embeddings that don't fit the current model
+    skipped: BTreeMap<String, Embedding>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    vectors: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct PromptStyle {
+    name: String,
+    prompt: Option<String>,
+    negative_prompt: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Vae {
+    model_name: String,
+    filename: String,
 }
 
 /// Many more options are available, but we only care about these
@@ -53,6 +91,8 @@ struct PromptData {
     cfg_scale: f32,
     overrides: Option<SettingsOverrides>,
     seed: i64,
+    batch_size: u32,
+    n_iter: u32,
     enable_hr: bool,
     hr_scale: f32,
     hr_upscaler: String,
@@ -61,9 +101,66 @@ struct PromptData {
     send_images: bool,
     save_images: bool,
     restore_faces: bool,
+    /// Only sent, the parameters in responses have whatever the server's scripts use
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    alwayson_scripts: Option<AlwaysOnScripts>,
+}
+
+/// Arguments for extensions that run with every generation
+#[derive(Serialize, Clone, Debug)]
+struct AlwaysOnScripts {
+    controlnet: ScriptArgs<ControlNetArgs>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct ScriptArgs<T> {
+    args: Vec<T>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct ControlNetArgs {
+    enabled: bool,
+    module: String,
+    model: String,
+    /// Base64 encoded control image
+    image: String,
+    weight: f32,
+    guidance_start: f32,
+    guidance_end: f32,
+    control_mode: u8,
+    resize_mode: u8,
+}
+
+impl AlwaysOnScripts {
+    /// Scripts for the prompt's ControlNet units, or None if it has none
+    fn new(value: &super::PromptData) -> anyhow::Result<Option<AlwaysOnScripts>> {
+        let Some(units) = value.controlnet.as_ref().filter(|units| !units.is_empty()) else {
+            return Ok(None);
+        };
+        let args = units
+            .iter()
+            .map(|unit| {
+                Ok(ControlNetArgs {
+                    enabled: true,
+                    module: unit.module.clone().unwrap_or_else(|| "none".to_string()),
+                    model: unit.model.clone(),
+                    image: encode_image_file(&unit.image)?,
+                    weight: unit.weight.unwrap_or(1.0),
+                    guidance_start: unit.guidance_start.unwrap_or(0.0),
+                    guidance_end: unit.guidance_end.unwrap_or(1.0),
+                    control_mode: unit.control_mode.unwrap_or_default() as u8,
+                    resize_mode: unit.resize_mode.unwrap_or(ResizeMode::CropAndResize) as u8,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Some(AlwaysOnScripts {
+            controlnet: ScriptArgs { args },
+        }))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[allow(non_snake_case)]
 struct SettingsOverrides {
     /// Clip Skip
     CLIP_stop_at_last_layers: u8,
@@ -72,8 +169,8 @@ struct SettingsOverrides {
 impl From<&super::PromptData> for PromptData {
     fn from(value: &super::PromptData) -> Self {
         PromptData {
-            prompt: value.positive.clone(),
-            negative_prompt: value.negative.clone(),
+            prompt: value.full_positive(),
+            negative_prompt: value.full_negative(),
             sampler_name: value.sampler.clone(),
             steps: value.steps,
             width: value.width,
@@ -83,6 +180,8 @@ impl From<&super::PromptData> for PromptData {
                 CLIP_stop_at_last_layers: value.clip_skip.unwrap_or(1),
             }),
             seed: value.seed.unwrap_or(-1),
+            batch_size: value.batch_size.unwrap_or(1),
+            n_iter: value.n_iter.unwrap_or(1),
             enable_hr: value.hires.is_some(),
             hr_scale: match &value.hires {
                 Some(hires) => hires.upscale_by,
@@ -103,14 +202,84 @@ impl From<&super::PromptData> for PromptData {
             send_images: true,
             save_images: false,
             restore_faces: false,
+            alwayson_scripts: None,
         }
     }
 }
 
-/*
-Automatic1111 API notes
-GET /sdapi/v1/progress <- might be cool to use this to show a progress bar in the terminal?
-*/
+#[derive(Serialize, Debug)]
+struct Img2ImgRequest {
+    #[serde(flatten)]
+    prompt: PromptData,
+    /// Base64 encoded init images
+    init_images: Vec<String>,
+    resize_mode: u8,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    inpaint: Option<InpaintRequest>,
+}
+
+#[derive(Serialize, Debug)]
+struct InpaintRequest {
+    /// Base64 encoded mask image
+    mask: String,
+    mask_blur: u32,
+    inpainting_fill: u8,
+    /// Inpaint only the masked area
+    inpaint_full_res: bool,
+    inpaint_full_res_padding: u32,
+    inpainting_mask_invert: u8,
+}
+
+impl InpaintRequest {
+    fn new(settings: &super::InpaintSettings) -> anyhow::Result<InpaintRequest> {
+        Ok(InpaintRequest {
+            mask: encode_image_file(&settings.mask)?,
+            mask_blur: settings.mask_blur.unwrap_or(4),
+            inpainting_fill: settings.fill.unwrap_or_default() as u8,
+            inpaint_full_res: settings.full_res_padding.is_some(),
+            inpaint_full_res_padding: settings.full_res_padding.unwrap_or(0),
+            inpainting_mask_invert: settings.invert_mask.unwrap_or(false) as u8,
+        })
+    }
+}
+
+impl Img2ImgRequest {
+    fn new(
+        value: &super::PromptData,
+        settings: &super::Img2ImgSettings,
+    ) -> anyhow::Result<Img2ImgRequest> {
+        let mut prompt: PromptData = value.into();
+        // Replaces the Hi-res denoising strength, which img2img doesn't use
+        prompt.denoising_strength = settings.denoising_strength;
+        prompt.alwayson_scripts = AlwaysOnScripts::new(value)?;
+
+        Ok(Img2ImgRequest {
+            prompt,
+            init_images: vec![encode_image_file(&settings.init_image)?],
+            resize_mode: settings.resize_mode.unwrap_or_default() as u8,
+            inpaint: value
+                .inpaint
+                .as_ref()
+                .map(InpaintRequest::new)
+                .transpose()?,
+        })
+    }
+}
+
+fn encode_image_file(path: &str) -> anyhow::Result<String> {
+    let image_bytes = std::fs::read(path).map_err(|e| BatchError {
+        message: format!("unable to read image {}: {}", path, e),
+    })?;
+    Ok(general_purpose::STANDARD.encode(image_bytes))
+}
+
+fn decode_images(base64_images: Vec<String>) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut image_list = vec![];
+    for base64image in base64_images {
+        image_list.push(general_purpose::STANDARD.decode(base64image)?);
+    }
+    Ok(image_list)
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Txt2ImgResponse {
@@ -119,6 +288,43 @@ struct Txt2ImgResponse {
     info: String,
 }
 
+#[derive(Deserialize, Debug)]
+struct Img2ImgResponse {
+    images: Vec<String>,
+    info: String,
+}
+
+#[derive(Serialize)]
+struct ExtrasRequest {
+    image: String,
+    /// 0 to scale by upscaling_resize, 1 to resize to a given size
+    resize_mode: u8,
+    upscaling_resize: f32,
+    upscaler_1: String,
+    gfpgan_visibility: f32,
+    codeformer_visibility: f32,
+    codeformer_weight: f32,
+}
+
+impl ExtrasRequest {
+    fn new(image_bytes: &[u8], settings: &UpscaleSettings) -> ExtrasRequest {
+        ExtrasRequest {
+            image: general_purpose::STANDARD.encode(image_bytes),
+            resize_mode: 0,
+            upscaling_resize: settings.scale_by,
+            upscaler_1: settings.upscaler.clone(),
+            gfpgan_visibility: settings.gfpgan.unwrap_or_default(),
+            codeformer_visibility: settings.codeformer.unwrap_or_default(),
+            codeformer_weight: settings.codeformer_weight.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ExtrasResponse {
+    image: String,
+}
+
 pub struct APIClient {
     api_url: String,
     client: reqwest::blocking::Client,
@@ -129,10 +335,10 @@ pub struct APIClient {
 impl APIClient {
     pub fn new(
         api_url: &str,
+        timeout: std::time::Duration,
         save_images: &Option<bool>,
         restore_faces: &Option<bool>,
     ) -> anyhow::Result<APIClient> {
-        let timeout = std::time::Duration::new(180, 0);
         let client = ClientBuilder::new().timeout(timeout).build()?;
 
         Ok(APIClient {
@@ -143,6 +349,16 @@ impl APIClient {
         })
     }
 
+    /// GET an endpoint of the API, ex. "loras" for /sdapi/v1/loras
+    fn get_json<T: DeserializeOwned>(&self, endpoint: &str) -> anyhow::Result<T> {
+        let resp = self
+            .client
+            .get(format!("{}/sdapi/v1/{}", &self.api_url, endpoint))
+            .send()?
+            .error_for_status()?;
+        Ok(resp.json()?)
+    }
+
     fn get_samplers(&self) -> anyhow::Result<Vec<Sampler>> {
         let resp = self
             .client
@@ -204,47 +420,73 @@ impl APIClient {
         Ok(super::BatchError { message: error_msg })
     }
 
-    pub fn txt2img(&self, prompt: &super::PromptData) -> anyhow::Result<(Vec<Vec<u8>>, String)> {
-        self.ensure_model(&prompt.model)?;
-
+    fn txt2img(&self, prompt: &super::PromptData) -> anyhow::Result<(Vec<Vec<u8>>, String)> {
+        let alwayson_scripts = AlwaysOnScripts::new(prompt)?;
         let mut prompt: PromptData = prompt.into();
         prompt.save_images = self.save_images;
         prompt.restore_faces = self.restore_faces;
+        prompt.alwayson_scripts = alwayson_scripts;
 
         let resp = self
             .client
             .post(format!("{}/sdapi/v1/txt2img", &self.api_url))
             .json(&prompt)
             .send()?;
-        let resp_status = resp.status();
-        if resp_status != StatusCode::OK {
-            let error_msg = &resp.text()?;
-
-            if error_msg.contains("Sampler not found") {
-                let sampler_error = self.invalid_sampler(&prompt)?;
-                return Err(sampler_error.into());
-            }
-            if error_msg.contains("could not find upscaler named") {
-                let upscaler_error = self.invalid_upscaler(&prompt.hr_upscaler)?;
-                return Err(upscaler_error.into());
-            }
-            return Err(super::BatchError {
-                message: format!(
-                    "Unexpected response when trying txt2img: {}, {:?}",
-                    resp_status, error_msg
-                ),
-            }
-            .into());
-        }
+        let resp = self.check_generation_response(resp, &prompt, "txt2img")?;
 
         let resp: Txt2ImgResponse = resp.json()?;
-        let mut image_list = vec![];
-        for base64image in resp.images {
-            let image_bytes = general_purpose::STANDARD.decode(base64image).unwrap();
-            image_list.push(image_bytes);
+        Ok((decode_images(resp.images)?, resp.info))
+    }
+
+    fn img2img(
+        &self,
+        prompt: &super::PromptData,
+        settings: &super::Img2ImgSettings,
+    ) -> anyhow::Result<(Vec<Vec<u8>>, String)> {
+        let mut request = Img2ImgRequest::new(prompt, settings)?;
+        request.prompt.save_images = self.save_images;
+        request.prompt.restore_faces = self.restore_faces;
+
+        let resp = self
+            .client
+            .post(format!("{}/sdapi/v1/img2img", &self.api_url))
+            .json(&request)
+            .send()?;
+        let resp = self.check_generation_response(resp, &request.prompt, "img2img")?;
+
+        let resp: Img2ImgResponse = resp.json()?;
+        Ok((decode_images(resp.images)?, resp.info))
+    }
+
+    /// Pass through a successful response, or turn a failed one into the most helpful error we can
+    fn check_generation_response(
+        &self,
+        resp: Response,
+        prompt: &PromptData,
+        action: &str,
+    ) -> anyhow::Result<Response> {
+        let resp_status = resp.status();
+        if resp_status == StatusCode::OK {
+            return Ok(resp);
         }
+        let error_msg = &resp.text()?;
 
-        Ok((image_list, resp.info))
+        if error_msg.contains("Sampler not found") {
+            let sampler_error = self.invalid_sampler(prompt)?;
+            return Err(sampler_error.into());
+        }
+        if error_msg.contains("could not find upscaler named") {
+            let upscaler_error = self.invalid_upscaler(&prompt.hr_upscaler)?;
+            return Err(upscaler_error.into());
+        }
+        Err(StatusError {
+            status: resp_status.as_u16(),
+            message: format!(
+                "Unexpected response when trying {}: {}, {:?}",
+                action, resp_status, error_msg
+            ),
+        }
+        .into())
     }
 
     fn ensure_model(&self, model: &str) -> anyhow::Result<()> {
@@ -265,10 +507,11 @@ impl APIClient {
             if resp_status != StatusCode::OK {
                 let error_msg = resp.text()?;
                 if error_msg.contains(&format!("model '{}' not found", model)) {
-                    let model_error = self.invalid_model(&model)?;
+                    let model_error = self.invalid_model(model)?;
                     return Err(model_error.into());
                 }
-                return Err(super::BatchError {
+                return Err(StatusError {
+                    status: resp_status.as_u16(),
                     message: format!(
                         "Unexpected response when trying to set model option: {}, {:?}",
                         resp_status, error_msg
@@ -282,10 +525,137 @@ impl APIClient {
     }
 }
 
+impl Backend for APIClient {
+    fn generate(&self, prompt: &super::PromptData) -> anyhow::Result<(Vec<Vec<u8>>, Txt2ImgInfo)> {
+        let (images, info) = match &prompt.img2img {
+            Some(settings) => self.img2img(prompt, settings)?,
+            None => self.txt2img(prompt)?,
+        };
+        Ok((images, serde_json::from_str(&info)?))
+    }
+
+    fn progress(&self) -> anyhow::Result<Option<Progress>> {
+        let progress: Progress = self
+            .client
+            .get(format!(
+                "{}/sdapi/v1/progress?skip_current_image=true",
+                &self.api_url
+            ))
+            .send()?
+            .json()?;
+        Ok(Some(progress))
+    }
+
+    fn inventory(&self, kind: Inventory) -> anyhow::Result<Vec<InventoryItem>> {
+        Ok(match kind {
+            Inventory::Models => self
+                .get_checkpoints()?
+                .into_iter()
+                .map(|model| {
+                    InventoryItem::new(model.title)
+                        .with("model_name", model.model_name)
+                        .with("hash", model.hash.unwrap_or_default())
+                        .with("filename", model.filename)
+                })
+                .collect(),
+            Inventory::Samplers => self
+                .get_samplers()?
+                .into_iter()
+                .map(|sampler| {
+                    InventoryItem::new(sampler.name).with("aliases", sampler.aliases.join(", "))
+                })
+                .collect(),
+            Inventory::Upscalers => {
+                // Hi-res can also upscale in latent space, which the upscalers list leaves out
+                let latent_modes: Vec<LatentUpscaleMode> = self.get_json("latent-upscale-modes")?;
+                let latent = latent_modes.into_iter().map(|mode| {
+                    InventoryItem::new(mode.name)
+                        .with("type", "latent")
+                        .with("scale", "")
+                });
+                let upscalers = self.get_upscalers()?.into_iter().map(|upscaler| {
+                    let scale = upscaler.scale.map(|s| s.to_string()).unwrap_or_default();
+                    InventoryItem::new(upscaler.name)
+                        .with("type", "image")
+                        .with("scale", scale)
+                });
+                latent.chain(upscalers).collect()
+            }
+            Inventory::Loras => self
+                .get_json::<Vec<Lora>>("loras")?
+                .into_iter()
+                .map(|lora| {
+                    InventoryItem::new(lora.name)
+                        .with("alias", lora.alias.unwrap_or_default())
+                        .with("path", lora.path.unwrap_or_default())
+                })
+                .collect(),
+            Inventory::Embeddings => {
+                let embeddings: Embeddings = self.get_json("embeddings")?;
+                let loaded = embeddings.loaded.into_iter().map(|e| (e, "loaded"));
+                let skipped = embeddings.skipped.into_iter().map(|e| (e, "skipped"));
+                loaded
+                    .chain(skipped)
+                    .map(|((name, embedding), status)| {
+                        let vectors = embedding.vectors.map(|v| v.to_string()).unwrap_or_default();
+                        InventoryItem::new(name)
+                            .with("status", status)
+                            .with("vectors", vectors)
+                    })
+                    .collect()
+            }
+            Inventory::Styles => self
+                .get_json::<Vec<PromptStyle>>("prompt-styles")?
+                .into_iter()
+                .map(|style| {
+                    InventoryItem::new(style.name)
+                        .with("prompt", style.prompt.unwrap_or_default())
+                        .with("negative_prompt", style.negative_prompt.unwrap_or_default())
+                })
+                .collect(),
+            Inventory::Vaes => self
+                .get_json::<Vec<Vae>>("sd-vae")?
+                .into_iter()
+                .map(|vae| InventoryItem::new(vae.model_name).with("filename", vae.filename))
+                .collect(),
+        })
+    }
+
+    fn set_model(&self, model: &str) -> anyhow::Result<()> {
+        self.ensure_model(model)
+    }
+
+    fn upscale(&self, image: &[u8], settings: &UpscaleSettings) -> anyhow::Result<Vec<u8>> {
+        let resp = self
+            .client
+            .post(format!("{}/sdapi/v1/extra-single-image", &self.api_url))
+            .json(&ExtrasRequest::new(image, settings))
+            .send()?;
+        let resp_status = resp.status();
+        if resp_status != StatusCode::OK {
+            let error_msg = resp.text()?;
+            if error_msg.contains("could not find upscaler named") {
+                return Err(self.invalid_upscaler(&settings.upscaler)?.into());
+            }
+            return Err(StatusError {
+                status: resp_status.as_u16(),
+                message: format!(
+                    "Unexpected response when trying to upscale: {}, {:?}",
+                    resp_status, error_msg
+                ),
+            }
+            .into());
+        }
+        let resp: ExtrasResponse = resp.json()?;
+        Ok(general_purpose::STANDARD.decode(resp.image)?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
 
+    use super::super::testing::test_dir;
     use super::*;
 
     #[test]
@@ -296,4 +666,91 @@ mod tests {
         let _response: Txt2ImgResponse =
             serde_json::from_str(mock_response.as_str()).expect("error deserializing json");
     }
+
+    #[test]
+    fn test_img2img_request_payload() {
+        let dir = test_dir();
+        let init_image = dir.join("init_image.png");
+        fs::write(&init_image, [1, 2, 3]).unwrap();
+
+        let settings: super::super::Img2ImgSettings = serde_json::from_value(serde_json::json!({
+            "init_image": init_image.to_string_lossy(),
+            "denoising_strength": 0.6,
+            "resize_mode": "CropAndResize",
+        }))
+        .unwrap();
+        let request = Img2ImgRequest::new(&Default::default(), &settings).unwrap();
+        let payload = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(payload["init_images"][0], "AQID");
+        assert_eq!(payload["resize_mode"], 1);
+        assert_eq!(payload["denoising_strength"], 0.6f32 as f64);
+        assert!(payload.get("mask").is_none());
+        // Flattened txt2img fields
+        assert_eq!(payload["seed"], -1);
+    }
+
+    #[test]
+    fn test_inpaint_request_payload() {
+        let dir = test_dir();
+        let mask = dir.join("mask.png");
+        fs::write(&mask, [4, 5, 6]).unwrap();
+
+        let settings: super::super::InpaintSettings = serde_json::from_value(serde_json::json!({
+            "mask": mask.to_string_lossy(),
+            "fill": "LatentNoise",
+            "full_res_padding": 32,
+            "invert_mask": true,
+        }))
+        .unwrap();
+        let payload = serde_json::to_value(InpaintRequest::new(&settings).unwrap()).unwrap();
+
+        assert_eq!(payload["mask"], "BAUG");
+        assert_eq!(payload["mask_blur"], 4);
+        assert_eq!(payload["inpainting_fill"], 2);
+        assert_eq!(payload["inpaint_full_res"], true);
+        assert_eq!(payload["inpaint_full_res_padding"], 32);
+        assert_eq!(payload["inpainting_mask_invert"], 1);
+    }
+
+    #[test]
+    fn test_controlnet_request_payload() {
+        let dir = test_dir();
+        let pose = dir.join("pose.png");
+        fs::write(&pose, [7, 8, 9]).unwrap();
+
+        let prompt: super::super::PromptData = serde_json::from_value(serde_json::json!({
+            "positive": "1girl",
+            "negative": "",
+            "model": "model.safetensors",
+            "sampler": "Euler a",
+            "steps": 20,
+            "width": 512,
+            "height": 512,
+            "cfg": 7.0,
+            "controlnet": [{
+                "module": "openpose",
+                "model": "control_v11p_sd15_openpose",
+                "image": pose.to_string_lossy(),
+                "guidance_end": 0.8,
+                "control_mode": "ControlNetMoreImportant",
+            }],
+        }))
+        .unwrap();
+        let payload = serde_json::to_value(AlwaysOnScripts::new(&prompt).unwrap()).unwrap();
+
+        let unit = &payload["controlnet"]["args"][0];
+        assert_eq!(unit["enabled"], true);
+        assert_eq!(unit["module"], "openpose");
+        assert_eq!(unit["image"], "BwgJ");
+        assert_eq!(unit["weight"], 1.0);
+        assert_eq!(unit["guidance_end"], 0.8f32 as f64);
+        assert_eq!(unit["control_mode"], 2);
+        assert_eq!(unit["resize_mode"], 1);
+        let request: PromptData = (&super::super::PromptData::default()).into();
+        assert!(serde_json::to_value(request)
+            .unwrap()
+            .get("alwayson_scripts")
+            .is_none());
+    }
 }
@@ -0,0 +1,194 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::{BatchError, PromptData, Txt2ImgInfo, UpscaleSettings};
+
+/// Which kind of server to generate images with
+#[derive(Serialize, Deserialize, ValueEnum, Default, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// Automatic1111's Stable Diffusion web UI, or a fork with the same API
+    #[default]
+    Auto1111,
+    #[value(name = "comfyui")]
+    ComfyUI,
+}
+
+impl BackendKind {
+    pub fn default_url(&self) -> &'static str {
+        match self {
+            BackendKind::Auto1111 => "http://127.0.0.1:7860",
+            BackendKind::ComfyUI => "http://127.0.0.1:8188",
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendKind::Auto1111 => write!(f, "Automatic1111"),
+            BackendKind::ComfyUI => write!(f, "ComfyUI"),
+        }
+    }
+}
+
+/// Kinds of things on a server that templates refer to by name
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Inventory {
+    Models,
+    Samplers,
+    /// Upscalers for Hi-res, including the latent upscale modes
+    Upscalers,
+    Loras,
+    /// Textual inversion embeddings
+    Embeddings,
+    /// Saved prompt styles
+    Styles,
+    Vaes,
+}
+
+impl fmt::Display for Inventory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Inventory::Models => "models",
+            Inventory::Samplers => "samplers",
+            Inventory::Upscalers => "upscalers",
+            Inventory::Loras => "LoRAs",
+            Inventory::Embeddings => "embeddings",
+            Inventory::Styles => "styles",
+            Inventory::Vaes => "VAEs",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something the server has, with whatever else the server says about it
+pub struct InventoryItem {
+    /// Name to use in templates
+    pub name: String,
+    /// Extra columns, in the order to show them
+    pub details: Vec<(&'static str, String)>,
+}
+
+impl InventoryItem {
+    pub fn new(name: impl Into<String>) -> InventoryItem {
+        InventoryItem {
+            name: name.into(),
+            details: vec![],
+        }
+    }
+
+    pub fn with(mut self, column: &'static str, value: impl ToString) -> InventoryItem {
+        self.details.push((column, value.to_string()));
+        self
+    }
+}
+
+/// An object of the name and details, keeping the details in order
+impl Serialize for InventoryItem {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.details.len() + 1))?;
+        map.serialize_entry("name", &self.name)?;
+        for (column, value) in &self.details {
+            map.serialize_entry(column, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Progress of the job currently running on the server
+#[derive(Deserialize, Debug, Default)]
+pub struct Progress {
+    /// Progress of the current job, from 0.0 to 1.0
+    pub progress: f32,
+    /// Estimated seconds remaining for the current job
+    pub eta_relative: f32,
+    pub state: ProgressState,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ProgressState {
+    #[serde(default)]
+    pub sampling_step: u32,
+    #[serde(default)]
+    pub sampling_steps: u32,
+}
+
+/// The server answered a request with an HTTP status we didn't expect
+///
+/// Kept apart from other errors so retries can tell which statuses are worth trying again
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// How often to poll for progress while an image is generating
+const PROGRESS_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// A server that can generate images for prompts
+pub trait Backend: Sync {
+    /// Generate images for the prompt, using img2img if it has img2img settings and txt2img otherwise
+    ///
+    /// Returns every image the server sent back, with the info describing them
+    fn generate(&self, prompt: &PromptData) -> anyhow::Result<(Vec<Vec<u8>>, Txt2ImgInfo)>;
+
+    /// Progress of the image currently generating, if the server reports it
+    fn progress(&self) -> anyhow::Result<Option<Progress>>;
+
+    /// Everything of a kind the server has, ex. every model
+    fn inventory(&self, kind: Inventory) -> anyhow::Result<Vec<InventoryItem>>;
+
+    /// Names of everything of a kind the server has
+    fn names(&self, kind: Inventory) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .inventory(kind)?
+            .into_iter()
+            .map(|item| item.name)
+            .collect())
+    }
+
+    /// Load the model on the server, if it isn't already
+    fn set_model(&self, model: &str) -> anyhow::Result<()>;
+
+    /// Upscale an image on the server, returning the upscaled PNG
+    fn upscale(&self, _image: &[u8], _settings: &UpscaleSettings) -> anyhow::Result<Vec<u8>> {
+        Err(BatchError {
+            message: "upscaling on the server is only supported by Automatic1111".to_string(),
+        }
+        .into())
+    }
+
+    /// Same as generate, but polls the server's progress while the request is in flight
+    /// and passes each update to `on_progress`
+    fn generate_with_progress(
+        &self,
+        prompt: &PromptData,
+        on_progress: &mut dyn FnMut(&Progress),
+    ) -> anyhow::Result<(Vec<Vec<u8>>, Txt2ImgInfo)> {
+        std::thread::scope(|scope| {
+            let request = scope.spawn(|| self.generate(prompt));
+            while !request.is_finished() {
+                std::thread::sleep(PROGRESS_POLL_INTERVAL);
+                // Progress is only cosmetic, a failed poll shouldn't fail the image
+                if let Ok(Some(progress)) = self.progress() {
+                    if !request.is_finished() {
+                        on_progress(&progress);
+                    }
+                }
+            }
+            request.join().expect("generation request thread panicked")
+        })
+    }
+}
@@ -0,0 +1,819 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use reqwest::blocking::{multipart, ClientBuilder};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use super::backend::{Backend, Inventory, InventoryItem, Progress, StatusError};
+use super::{BatchError, PromptData, ResizeMode, Txt2ImgInfo};
+
+/// How often to check the history for the prompt while it's queued or generating
+const HISTORY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Upscalers that resize in latent space, named like Automatic1111's
+const LATENT_UPSCALERS: [&str; 3] = ["Latent", "Latent (bicubic)", "Latent (nearest-exact)"];
+
+#[derive(Deserialize)]
+struct QueueResponse {
+    prompt_id: String,
+}
+
+#[derive(Deserialize)]
+struct UploadResponse {
+    name: String,
+    #[serde(default)]
+    subfolder: String,
+}
+
+#[derive(Deserialize)]
+struct HistoryEntry {
+    outputs: BTreeMap<String, NodeOutput>,
+    status: Option<HistoryStatus>,
+}
+
+#[derive(Deserialize)]
+struct NodeOutput {
+    #[serde(default)]
+    images: Vec<OutputImage>,
+}
+
+#[derive(Deserialize)]
+struct OutputImage {
+    filename: String,
+    subfolder: String,
+    #[serde(rename = "type")]
+    folder_type: String,
+}
+
+#[derive(Deserialize)]
+struct HistoryStatus {
+    status_str: String,
+    /// Pairs of event type and event data
+    #[serde(default)]
+    messages: Vec<(String, Value)>,
+}
+
+/// Generates images by building a ComfyUI workflow for each prompt
+///
+/// Face restoration has no ComfyUI equivalent and isn't supported
+pub struct ComfyClient {
+    api_url: String,
+    client: reqwest::blocking::Client,
+    /// Identifies our prompts in ComfyUI's queue
+    client_id: String,
+    save_images: bool,
+    /// How long to wait for each request, and for a queued prompt to finish
+    timeout: Duration,
+}
+
+impl ComfyClient {
+    pub fn new(
+        api_url: &str,
+        timeout: Duration,
+        save_images: &Option<bool>,
+    ) -> anyhow::Result<ComfyClient> {
+        let client = ClientBuilder::new().timeout(timeout).build()?;
+
+        Ok(ComfyClient {
+            api_url: api_url.to_owned(),
+            client,
+            client_id: format!("sdbatch-{:016x}", rand::random::<u64>()),
+            save_images: save_images.unwrap_or(false),
+            timeout,
+        })
+    }
+
+    /// Copy a local image into ComfyUI's input directory, returning the name to load it with
+    fn upload_image(&self, path: &str) -> anyhow::Result<String> {
+        let form = multipart::Form::new()
+            .file("image", path)?
+            .text("overwrite", "true");
+        let resp = self
+            .client
+            .post(format!("{}/upload/image", &self.api_url))
+            .multipart(form)
+            .send()?;
+        let resp_status = resp.status();
+        if resp_status != StatusCode::OK {
+            return Err(StatusError {
+                status: resp_status.as_u16(),
+                message: format!(
+                    "Unexpected response when uploading {}: {}, {:?}",
+                    path,
+                    resp_status,
+                    resp.text()?
+                ),
+            }
+            .into());
+        }
+        let uploaded: UploadResponse = resp.json()?;
+        Ok(match uploaded.subfolder.as_str() {
+            "" => uploaded.name,
+            subfolder => format!("{}/{}", subfolder, uploaded.name),
+        })
+    }
+
+    fn queue_prompt(&self, workflow: &Value) -> anyhow::Result<String> {
+        let resp = self
+            .client
+            .post(format!("{}/prompt", &self.api_url))
+            .json(&json!({ "prompt": workflow, "client_id": self.client_id }))
+            .send()?;
+        let resp_status = resp.status();
+        if resp_status != StatusCode::OK {
+            return Err(StatusError {
+                status: resp_status.as_u16(),
+                message: format!(
+                    "ComfyUI rejected the workflow: {}, {}",
+                    resp_status,
+                    describe_prompt_errors(&resp.text()?)
+                ),
+            }
+            .into());
+        }
+        let queued: QueueResponse = resp.json()?;
+        Ok(queued.prompt_id)
+    }
+
+    /// Poll the history until the prompt has finished, returning the images it output
+    fn wait_for_outputs(&self, prompt_id: &str) -> anyhow::Result<Vec<OutputImage>> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let mut history: BTreeMap<String, HistoryEntry> = self
+                .client
+                .get(format!("{}/history/{}", &self.api_url, prompt_id))
+                .send()?
+                .json()?;
+            if let Some(entry) = history.remove(prompt_id) {
+                if let Some(status) = entry.status.filter(|s| s.status_str == "error") {
+                    return Err(BatchError {
+                        message: format!(
+                            "ComfyUI failed to run the workflow: {}",
+                            describe_execution_errors(&status.messages)
+                        ),
+                    }
+                    .into());
+                }
+                return Ok(entry
+                    .outputs
+                    .into_values()
+                    .flat_map(|output| output.images)
+                    .collect());
+            }
+            if Instant::now() > deadline {
+                return Err(BatchError {
+                    message: format!("timed out waiting for ComfyUI to run prompt {}", prompt_id),
+                }
+                .into());
+            }
+            std::thread::sleep(HISTORY_POLL_INTERVAL);
+        }
+    }
+
+    fn download_image(&self, image: &OutputImage) -> anyhow::Result<Vec<u8>> {
+        let resp = self
+            .client
+            .get(format!("{}/view", &self.api_url))
+            .query(&[
+                ("filename", image.filename.as_str()),
+                ("subfolder", image.subfolder.as_str()),
+                ("type", image.folder_type.as_str()),
+            ])
+            .send()?
+            .error_for_status()?;
+        Ok(resp.bytes()?.to_vec())
+    }
+
+    /// Options of a node's input, from GET /object_info
+    fn node_input_options(&self, node: &str, input: &str) -> anyhow::Result<Vec<String>> {
+        let info: Value = self
+            .client
+            .get(format!("{}/object_info/{}", &self.api_url, node))
+            .send()?
+            .json()?;
+        let options = info[node]["input"]["required"][input][0]
+            .as_array()
+            .ok_or_else(|| BatchError {
+                message: format!("ComfyUI didn't list the options for {} {}", node, input),
+            })?;
+        Ok(options
+            .iter()
+            .filter_map(|option| option.as_str().map(str::to_string))
+            .collect())
+    }
+}
+
+impl Backend for ComfyClient {
+    fn generate(&self, prompt: &PromptData) -> anyhow::Result<(Vec<Vec<u8>>, Txt2ImgInfo)> {
+        if prompt
+            .controlnet
+            .as_ref()
+            .is_some_and(|units| !units.is_empty())
+        {
+            return Err(BatchError {
+                message: "ControlNet units are only supported by Automatic1111".to_string(),
+            }
+            .into());
+        }
+        let (init_image, mask) = match &prompt.img2img {
+            Some(settings) => (
+                Some(self.upload_image(&settings.init_image)?),
+                match &prompt.inpaint {
+                    Some(inpaint) => Some(self.upload_image(&inpaint.mask)?),
+                    None => None,
+                },
+            ),
+            None => (None, None),
+        };
+
+        let batch_size = prompt.batch_size.unwrap_or(1);
+        let first_seed = match prompt.seed {
+            Some(seed) if seed >= 0 => seed,
+            _ => rand::thread_rng().gen::<u32>() as i64,
+        };
+        let mut images = vec![];
+        let mut info = Txt2ImgInfo {
+            all_seeds: vec![],
+            all_subseeds: vec![],
+            infotexts: vec![],
+            index_of_first_image: 0,
+        };
+        for iteration in 0..prompt.n_iter.unwrap_or(1) {
+            // Like Automatic1111, each batch continues from the seeds of the batch before it.
+            // ComfyUI generates the noise for a whole batch from a single seed.
+            let seed = first_seed + (iteration * batch_size) as i64;
+            let workflow = build_workflow(
+                prompt,
+                seed,
+                init_image.as_deref(),
+                mask.as_deref(),
+                self.save_images,
+            );
+            let prompt_id = self.queue_prompt(&workflow)?;
+            for output in self.wait_for_outputs(&prompt_id)? {
+                images.push(self.download_image(&output)?);
+                info.all_seeds.push(seed);
+                info.all_subseeds.push(seed);
+                info.infotexts.push(infotext(prompt, seed));
+            }
+        }
+        Ok((images, info))
+    }
+
+    fn progress(&self) -> anyhow::Result<Option<Progress>> {
+        // ComfyUI only reports progress over its websocket
+        Ok(None)
+    }
+
+    fn inventory(&self, kind: Inventory) -> anyhow::Result<Vec<InventoryItem>> {
+        let names = match kind {
+            Inventory::Models => self.node_input_options("CheckpointLoaderSimple", "ckpt_name")?,
+            Inventory::Samplers => self.node_input_options("KSampler", "sampler_name")?,
+            Inventory::Upscalers => {
+                let mut upscalers: Vec<String> =
+                    LATENT_UPSCALERS.iter().map(|u| u.to_string()).collect();
+                upscalers.extend(self.node_input_options("UpscaleModelLoader", "model_name")?);
+                upscalers
+            }
+            Inventory::Loras => self.node_input_options("LoraLoader", "lora_name")?,
+            Inventory::Embeddings => self
+                .client
+                .get(format!("{}/embeddings", &self.api_url))
+                .send()?
+                .json()?,
+            Inventory::Vaes => self.node_input_options("VAELoader", "vae_name")?,
+            Inventory::Styles => {
+                return Err(BatchError {
+                    message: "ComfyUI has no saved prompt styles".to_string(),
+                }
+                .into())
+            }
+        };
+        Ok(names.into_iter().map(InventoryItem::new).collect())
+    }
+
+    fn set_model(&self, _model: &str) -> anyhow::Result<()> {
+        // Every workflow loads its own checkpoint
+        Ok(())
+    }
+}
+
+/// ComfyUI workflow in the API format, nodes keyed by their id
+#[derive(Default)]
+struct Workflow {
+    nodes: Map<String, Value>,
+}
+
+impl Workflow {
+    /// Add a node, returning its id
+    fn add(&mut self, class_type: &str, inputs: Value) -> String {
+        let id = (self.nodes.len() + 1).to_string();
+        self.nodes.insert(
+            id.clone(),
+            json!({ "class_type": class_type, "inputs": inputs }),
+        );
+        id
+    }
+}
+
+/// Link to an output of a node, for another node's input
+fn link(node: &str, output: u32) -> Value {
+    json!([node, output])
+}
+
+/// Build a workflow that generates the prompt the way Automatic1111 would
+///
+/// `init_image` and `mask` are the uploaded names of the prompt's img2img and inpainting images.
+/// Inpainting only uses the mask and whether to invert it, the rest of the settings are Automatic1111's.
+fn build_workflow(
+    prompt: &PromptData,
+    seed: i64,
+    init_image: Option<&str>,
+    mask: Option<&str>,
+    save_images: bool,
+) -> Value {
+    let mut workflow = Workflow::default();
+    let checkpoint = workflow.add(
+        "CheckpointLoaderSimple",
+        json!({ "ckpt_name": checkpoint_name(&prompt.model) }),
+    );
+    let vae = link(&checkpoint, 2);
+    let mut model = link(&checkpoint, 0);
+    let mut clip = link(&checkpoint, 1);
+    for lora in prompt.loras.iter().flatten() {
+        let weight = lora.weight.unwrap_or(1.0);
+        let loader = workflow.add(
+            "LoraLoader",
+            json!({
+                "model": model,
+                "clip": clip,
+                "lora_name": lora_file_name(&lora.name),
+                "strength_model": weight,
+                "strength_clip": weight,
+            }),
+        );
+        model = link(&loader, 0);
+        clip = link(&loader, 1);
+    }
+    if let Some(clip_skip) = prompt.clip_skip.filter(|clip_skip| *clip_skip > 1) {
+        let clip_layer = workflow.add(
+            "CLIPSetLastLayer",
+            json!({ "clip": clip, "stop_at_clip_layer": -(clip_skip as i32) }),
+        );
+        clip = link(&clip_layer, 0);
+    }
+    let positive_text = with_embeddings(&prompt.positive, &prompt.embeddings);
+    let positive = workflow.add(
+        "CLIPTextEncode",
+        json!({ "text": positive_text, "clip": clip }),
+    );
+    let negative_text = with_embeddings(&prompt.negative, &prompt.negative_embeddings);
+    let negative = workflow.add(
+        "CLIPTextEncode",
+        json!({ "text": negative_text, "clip": clip }),
+    );
+
+    let batch_size = prompt.batch_size.unwrap_or(1);
+    let (latent, denoise) = match (init_image, &prompt.img2img) {
+        (Some(init_image), Some(settings)) => {
+            let image = workflow.add("LoadImage", json!({ "image": init_image }));
+            let crop = match settings.resize_mode.unwrap_or_default() {
+                ResizeMode::CropAndResize => "center",
+                _ => "disabled",
+            };
+            let scaled = workflow.add(
+                "ImageScale",
+                json!({
+                    "image": link(&image, 0),
+                    "upscale_method": "lanczos",
+                    "width": prompt.width,
+                    "height": prompt.height,
+                    "crop": crop,
+                }),
+            );
+            let encoded = workflow.add(
+                "VAEEncode",
+                json!({ "pixels": link(&scaled, 0), "vae": vae }),
+            );
+            let mut latent = link(&encoded, 0);
+            if let Some(mask) = mask {
+                let mask_image = workflow.add("LoadImage", json!({ "image": mask }));
+                let mut mask = link(
+                    &workflow.add(
+                        "ImageToMask",
+                        json!({ "image": link(&mask_image, 0), "channel": "red" }),
+                    ),
+                    0,
+                );
+                if prompt
+                    .inpaint
+                    .as_ref()
+                    .and_then(|inpaint| inpaint.invert_mask)
+                    .unwrap_or(false)
+                {
+                    mask = link(&workflow.add("InvertMask", json!({ "mask": mask })), 0);
+                }
+                let masked = workflow.add(
+                    "SetLatentNoiseMask",
+                    json!({ "samples": latent, "mask": mask }),
+                );
+                latent = link(&masked, 0);
+            }
+            if batch_size > 1 {
+                let repeated = workflow.add(
+                    "RepeatLatentBatch",
+                    json!({ "samples": latent, "amount": batch_size }),
+                );
+                latent = link(&repeated, 0);
+            }
+            (latent, settings.denoising_strength)
+        }
+        _ => {
+            let empty = workflow.add(
+                "EmptyLatentImage",
+                json!({ "width": prompt.width, "height": prompt.height, "batch_size": batch_size }),
+            );
+            (link(&empty, 0), 1.0)
+        }
+    };
+
+    let (sampler_name, scheduler) = sampler_and_scheduler(&prompt.sampler);
+    let ksampler = |workflow: &mut Workflow, latent: Value, steps: u32, denoise: f32| {
+        workflow.add(
+            "KSampler",
+            json!({
+                "model": model,
+                "positive": link(&positive, 0),
+                "negative": link(&negative, 0),
+                "latent_image": latent,
+                "seed": seed,
+                "steps": steps,
+                "cfg": prompt.cfg,
+                "sampler_name": sampler_name,
+                "scheduler": scheduler,
+                "denoise": denoise,
+            }),
+        )
+    };
+    let mut samples = link(&ksampler(&mut workflow, latent, prompt.steps, denoise), 0);
+
+    if let Some(hires) = &prompt.hires {
+        let upscaled = match latent_upscale_method(&hires.upscaler) {
+            Some(method) => workflow.add(
+                "LatentUpscaleBy",
+                json!({ "samples": samples, "upscale_method": method, "scale_by": hires.upscale_by }),
+            ),
+            None => {
+                let decoded = workflow.add("VAEDecode", json!({ "samples": samples, "vae": vae }));
+                let upscale_model = workflow.add(
+                    "UpscaleModelLoader",
+                    json!({ "model_name": hires.upscaler }),
+                );
+                let upscaled = workflow.add(
+                    "ImageUpscaleWithModel",
+                    json!({ "upscale_model": link(&upscale_model, 0), "image": link(&decoded, 0) }),
+                );
+                // Upscale models have their own fixed scale, resize to the size Hi-res asked for
+                let resized = workflow.add(
+                    "ImageScale",
+                    json!({
+                        "image": link(&upscaled, 0),
+                        "upscale_method": "lanczos",
+                        "width": (prompt.width as f32 * hires.upscale_by) as u32,
+                        "height": (prompt.height as f32 * hires.upscale_by) as u32,
+                        "crop": "disabled",
+                    }),
+                );
+                workflow.add(
+                    "VAEEncode",
+                    json!({ "pixels": link(&resized, 0), "vae": vae }),
+                )
+            }
+        };
+        // Like Automatic1111, 0 Hi-res steps means the same number of steps as the first pass
+        let hires_steps = match hires.steps {
+            0 => prompt.steps,
+            steps => steps as u32,
+        };
+        let second_pass = ksampler(
+            &mut workflow,
+            link(&upscaled, 0),
+            hires_steps,
+            hires.denoising_strength,
+        );
+        samples = link(&second_pass, 0);
+    }
+
+    let decoded = workflow.add("VAEDecode", json!({ "samples": samples, "vae": vae }));
+    if save_images {
+        workflow.add(
+            "SaveImage",
+            json!({ "images": link(&decoded, 0), "filename_prefix": "sdbatch" }),
+        );
+    } else {
+        workflow.add("PreviewImage", json!({ "images": link(&decoded, 0) }));
+    }
+    Value::Object(workflow.nodes)
+}
+
+/// Drop the " [hash]" Automatic1111 adds to model titles, ComfyUI only knows the filename
+fn checkpoint_name(model: &str) -> &str {
+    match model.rsplit_once(" [") {
+        Some((name, hash)) if hash.ends_with(']') => name,
+        _ => model,
+    }
+}
+
+/// LoRA file name, ComfyUI needs the extension that Automatic1111 leaves out
+fn lora_file_name(name: &str) -> String {
+    match [".safetensors", ".ckpt", ".pt"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+    {
+        true => name.to_string(),
+        false => format!("{}.safetensors", name),
+    }
+}
+
+/// Prompt text with the embeddings added the way ComfyUI reads them, ex. "embedding:easynegative"
+fn with_embeddings(prompt: &str, embeddings: &Option<Vec<String>>) -> String {
+    let embeddings: Vec<String> = embeddings
+        .iter()
+        .flatten()
+        .map(|embedding| format!("embedding:{}", embedding))
+        .collect();
+    PromptData::with_extras(prompt, &embeddings)
+}
+
+/// Translate an Automatic1111 sampler name, like "DPM++ 2M Karras", to a ComfyUI sampler and scheduler
+///
+/// Names ComfyUI already knows, like "dpmpp_2m", are used as they are with the normal scheduler
+fn sampler_and_scheduler(sampler: &str) -> (&str, &'static str) {
+    let (name, scheduler) = if let Some(name) = sampler.strip_suffix(" Karras") {
+        (name, "karras")
+    } else if let Some(name) = sampler.strip_suffix(" Exponential") {
+        (name, "exponential")
+    } else {
+        (sampler, "normal")
+    };
+    let name = match name {
+        "Euler" => "euler",
+        "Euler a" => "euler_ancestral",
+        "Heun" => "heun",
+        "LMS" => "lms",
+        "DPM2" => "dpm_2",
+        "DPM2 a" => "dpm_2_ancestral",
+        "DPM++ 2S a" => "dpmpp_2s_ancestral",
+        "DPM++ 2M" => "dpmpp_2m",
+        "DPM++ SDE" => "dpmpp_sde",
+        "DPM++ 2M SDE" => "dpmpp_2m_sde",
+        "DPM++ 3M SDE" => "dpmpp_3m_sde",
+        "DPM fast" => "dpm_fast",
+        "DPM adaptive" => "dpm_adaptive",
+        "DDIM" => "ddim",
+        "UniPC" => "uni_pc",
+        "LCM" => "lcm",
+        name => name,
+    };
+    (name, scheduler)
+}
+
+/// ComfyUI's latent upscale method for one of the latent upscalers, or None for upscale models
+fn latent_upscale_method(upscaler: &str) -> Option<&'static str> {
+    match upscaler {
+        "Latent" => Some("bilinear"),
+        "Latent (bicubic)" => Some("bicubic"),
+        "Latent (nearest-exact)" => Some("nearest-exact"),
+        _ => None,
+    }
+}
+
+/// Generation parameters in the same format as Automatic1111's infotexts
+fn infotext(prompt: &PromptData, seed: i64) -> String {
+    format!(
+        "{}\nNegative prompt: {}\nSteps: {}, Sampler: {}, CFG scale: {}, Seed: {}, Size: {}x{}, Model: {}",
+        prompt.full_positive(),
+        prompt.full_negative(),
+        prompt.steps,
+        prompt.sampler,
+        prompt.cfg,
+        seed,
+        prompt.width,
+        prompt.height,
+        checkpoint_name(&prompt.model)
+    )
+}
+
+/// Summarize the errors in a rejected POST /prompt response, falling back to the raw response
+fn describe_prompt_errors(response: &str) -> String {
+    let Ok(errors) = serde_json::from_str::<Value>(response) else {
+        return response.to_string();
+    };
+    let mut messages = vec![];
+    if let Some(message) = errors["error"]["message"].as_str() {
+        messages.push(message.to_string());
+    }
+    if let Some(node_errors) = errors["node_errors"].as_object() {
+        for node in node_errors.values() {
+            for error in node["errors"].as_array().into_iter().flatten() {
+                messages.push(format!(
+                    "{}: {} {}",
+                    node["class_type"].as_str().unwrap_or("node"),
+                    error["message"].as_str().unwrap_or_default(),
+                    error["details"].as_str().unwrap_or_default()
+                ));
+            }
+        }
+    }
+    if messages.is_empty() {
+        response.to_string()
+    } else {
+        messages.join("; ")
+    }
+}
+
+fn describe_execution_errors(messages: &[(String, Value)]) -> String {
+    let errors: Vec<String> = messages
+        .iter()
+        .filter(|(event, _)| event == "execution_error")
+        .map(|(_, data)| {
+            format!(
+                "{}: {}",
+                data["node_type"].as_str().unwrap_or("node"),
+                data["exception_message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .trim()
+            )
+        })
+        .collect();
+    if errors.is_empty() {
+        "no error details were given".to_string()
+    } else {
+        errors.join("; ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{HiResSettings, Img2ImgSettings, InpaintSettings, LoraEntry};
+    use super::*;
+
+    fn test_prompt() -> PromptData {
+        PromptData {
+            positive: "1girl, solo".to_string(),
+            negative: "lowres".to_string(),
+            model: "anything-v5.safetensors [7f96a1a9ca]".to_string(),
+            sampler: "DPM++ 2M Karras".to_string(),
+            steps: 20,
+            width: 512,
+            height: 768,
+            cfg: 7.0,
+            clip_skip: Some(2),
+            ..Default::default()
+        }
+    }
+
+    /// Nodes of the workflow with the given class type
+    fn nodes<'a>(workflow: &'a Value, class_type: &str) -> Vec<&'a Value> {
+        workflow
+            .as_object()
+            .unwrap()
+            .values()
+            .filter(|node| node["class_type"] == class_type)
+            .map(|node| &node["inputs"])
+            .collect()
+    }
+
+    #[test]
+    fn translates_sampler_names() {
+        assert_eq!(
+            sampler_and_scheduler("DPM++ 2M Karras"),
+            ("dpmpp_2m", "karras")
+        );
+        assert_eq!(
+            sampler_and_scheduler("Euler a"),
+            ("euler_ancestral", "normal")
+        );
+        assert_eq!(sampler_and_scheduler("dpmpp_sde"), ("dpmpp_sde", "normal"));
+        assert_eq!(
+            checkpoint_name("anything-v5.safetensors [7f96a1a9ca]"),
+            "anything-v5.safetensors"
+        );
+        assert_eq!(checkpoint_name("sdxl.safetensors"), "sdxl.safetensors");
+    }
+
+    #[test]
+    fn txt2img_workflow() {
+        let workflow = build_workflow(&test_prompt(), 1234, None, None, false);
+
+        let checkpoints = nodes(&workflow, "CheckpointLoaderSimple");
+        assert_eq!(checkpoints[0]["ckpt_name"], "anything-v5.safetensors");
+        assert_eq!(
+            nodes(&workflow, "CLIPSetLastLayer")[0]["stop_at_clip_layer"],
+            -2
+        );
+        let ksamplers = nodes(&workflow, "KSampler");
+        assert_eq!(ksamplers.len(), 1);
+        assert_eq!(ksamplers[0]["seed"], 1234);
+        assert_eq!(ksamplers[0]["sampler_name"], "dpmpp_2m");
+        assert_eq!(ksamplers[0]["scheduler"], "karras");
+        assert_eq!(ksamplers[0]["denoise"], 1.0);
+        assert_eq!(nodes(&workflow, "EmptyLatentImage")[0]["height"], 768);
+        assert_eq!(nodes(&workflow, "PreviewImage").len(), 1);
+    }
+
+    #[test]
+    fn loras_and_embeddings_are_added_to_the_workflow() {
+        let mut prompt = test_prompt();
+        prompt.loras = Some(vec![LoraEntry {
+            name: "add_detail".to_string(),
+            weight: Some(0.8),
+            weight_range: None,
+        }]);
+        prompt.negative_embeddings = Some(vec!["easynegative".to_string()]);
+        let workflow = build_workflow(&prompt, 1234, None, None, false);
+
+        let loader = nodes(&workflow, "LoraLoader")[0];
+        assert_eq!(loader["lora_name"], "add_detail.safetensors");
+        assert_eq!(loader["strength_model"], 0.8_f32);
+        let texts: Vec<_> = nodes(&workflow, "CLIPTextEncode")
+            .iter()
+            .map(|node| node["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, vec!["1girl, solo", "lowres, embedding:easynegative"]);
+        // The sampler uses the model with the LoRA applied
+        let ksampler = nodes(&workflow, "KSampler")[0];
+        assert_eq!(
+            workflow[ksampler["model"][0].as_str().unwrap()]["class_type"],
+            "LoraLoader"
+        );
+    }
+
+    #[test]
+    fn hires_workflow_has_second_pass() {
+        let mut prompt = test_prompt();
+        prompt.hires = Some(HiResSettings {
+            upscaler: "R-ESRGAN 4x+".to_string(),
+            upscale_by: 1.5,
+            denoising_strength: 0.4,
+            steps: 0,
+        });
+        let workflow = build_workflow(&prompt, 1234, None, None, true);
+
+        let ksamplers = nodes(&workflow, "KSampler");
+        assert_eq!(ksamplers.len(), 2);
+        let second_pass = ksamplers.iter().find(|k| k["denoise"] != 1.0).unwrap();
+        assert_eq!(second_pass["steps"], 20);
+        assert_eq!(
+            nodes(&workflow, "UpscaleModelLoader")[0]["model_name"],
+            "R-ESRGAN 4x+"
+        );
+        assert_eq!(nodes(&workflow, "ImageScale")[0]["width"], 768);
+        assert_eq!(nodes(&workflow, "SaveImage").len(), 1);
+    }
+
+    #[test]
+    fn inpaint_workflow_masks_the_init_image() {
+        let mut prompt = test_prompt();
+        prompt.batch_size = Some(2);
+        prompt.img2img = Some(Img2ImgSettings {
+            init_image: "girl.png".to_string(),
+            denoising_strength: 0.6,
+            resize_mode: Some(ResizeMode::CropAndResize),
+        });
+        prompt.inpaint = Some(InpaintSettings {
+            mask: "mask.png".to_string(),
+            invert_mask: Some(true),
+            ..Default::default()
+        });
+        let workflow = build_workflow(&prompt, 1, Some("girl.png"), Some("mask.png"), false);
+
+        assert_eq!(nodes(&workflow, "LoadImage").len(), 2);
+        assert_eq!(nodes(&workflow, "ImageScale")[0]["crop"], "center");
+        assert_eq!(nodes(&workflow, "InvertMask").len(), 1);
+        assert_eq!(nodes(&workflow, "SetLatentNoiseMask").len(), 1);
+        assert_eq!(nodes(&workflow, "RepeatLatentBatch")[0]["amount"], 2);
+        assert!(nodes(&workflow, "EmptyLatentImage").is_empty());
+        let denoise = nodes(&workflow, "KSampler")[0]["denoise"].as_f64().unwrap();
+        assert!((denoise - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn describes_rejected_workflows() {
+        let response = r#"{
+            "error": {"type": "prompt_outputs_failed_validation", "message": "Prompt outputs failed validation"},
+            "node_errors": {"3": {"class_type": "KSampler", "errors": [
+                {"message": "Value not in list", "details": "sampler_name: 'bogus' not in ['euler']"}
+            ]}}
+        }"#;
+        assert_eq!(
+            describe_prompt_errors(response),
+            "Prompt outputs failed validation; KSampler: Value not in list sampler_name: 'bogus' not in ['euler']"
+        );
+        assert_eq!(describe_prompt_errors("Bad Request"), "Bad Request");
+    }
+}
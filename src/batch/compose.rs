@@ -0,0 +1,384 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::BatchError;
+
+/// Another template to start from, with this template's settings on top
+///
+/// Settings are merged into the extended template's, so a `base_prompt` only needs the settings it changes.
+/// Relative paths, like init images and wildcard directories, are resolved from the directory of the template
+/// they're written in.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Extends {
+    /// Path of the template to extend, relative to this one, adding to its prompts and modifiers
+    ///
+    /// ex., "base.json"
+    Template(String),
+    /// Template to extend, with how to combine the prompts and modifiers
+    ///
+    /// ex., {"template": "base.json", "prompts": "Replace"}
+    Detailed {
+        template: String,
+        /// Defaults to Append
+        prompts: Option<ListMerge>,
+        /// Defaults to Append
+        modifiers: Option<ListMerge>,
+    },
+}
+
+/// How a list in an extending template combines with the extended template's list
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub enum ListMerge {
+    /// Add the entries after the extended template's
+    #[default]
+    Append,
+    /// Use only this template's entries
+    Replace,
+}
+
+impl Extends {
+    fn template(&self) -> &str {
+        match self {
+            Extends::Template(template) | Extends::Detailed { template, .. } => template,
+        }
+    }
+
+    fn list_merge(&self, key: &str) -> ListMerge {
+        match (self, key) {
+            (Extends::Detailed { prompts, .. }, "prompts") => prompts.unwrap_or_default(),
+            (Extends::Detailed { modifiers, .. }, "modifiers") => modifiers.unwrap_or_default(),
+            _ => ListMerge::Append,
+        }
+    }
+}
+
+/// Read a template file as JSON, with what it extends merged in and its includes replaced by their entries
+///
+/// Entries like {"include": "animals.json"} in `prompts` and `modifiers` are replaced by the entries
+/// in the file, a list or a single entry. Paths are relative to the file they're written in, so those
+/// from other files are made relative to their file's directory.
+pub fn load_template(path: &Path) -> anyhow::Result<Value> {
+    Ok(load(path, &mut vec![])?.0)
+}
+
+/// Load the template at `path`, with `stack` holding the files that led to it to catch cycles,
+/// returning it with its directory
+fn load(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<(Value, PathBuf)> {
+    let (mut template, dir) = read_json(path, stack)?;
+    let Value::Object(fields) = &mut template else {
+        return Err(BatchError {
+            message: format!("template {} isn't a JSON object", path.display()),
+        }
+        .into());
+    };
+    for key in ["prompts", "modifiers"] {
+        if let Some(list) = fields.get_mut(key) {
+            resolve_includes(list, &dir, stack)?;
+        }
+    }
+
+    if let Some(extends) = fields.get("extends").filter(|v| !v.is_null()).cloned() {
+        let extends: Extends = serde_json::from_value(extends).map_err(|e| BatchError {
+            message: format!("extends in {} is invalid: {}", path.display(), e),
+        })?;
+        let (mut base, base_dir) = load(&dir.join(extends.template()), stack)?;
+        rebase_template_paths(&mut base, &base_dir);
+        template = merge_template(base, template, &extends);
+    }
+    stack.pop();
+    Ok((template, dir))
+}
+
+/// Read the JSON file, adding it to the stack, returning it with its directory
+fn read_json(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<(Value, PathBuf)> {
+    let path = fs::canonicalize(path).map_err(|e| BatchError {
+        message: format!("unable to read {}: {}", path.display(), e),
+    })?;
+    if stack.contains(&path) {
+        let cycle: Vec<String> = stack
+            .iter()
+            .chain([&path])
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(BatchError {
+            message: format!("templates include each other: {}", cycle.join(" -> ")),
+        }
+        .into());
+    }
+    let value = serde_json::from_str(&fs::read_to_string(&path)?).map_err(|e| BatchError {
+        message: format!("unable to parse {}: {}", path.display(), e),
+    })?;
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    stack.push(path);
+    Ok((value, dir))
+}
+
+/// Replace each {"include": "file.json"} in the list with the entries in the file
+fn resolve_includes(list: &mut Value, dir: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let Value::Array(items) = list else {
+        return Ok(());
+    };
+    let mut resolved = vec![];
+    for item in items.drain(..) {
+        let Some(file) = include_path(&item) else {
+            resolved.push(item);
+            continue;
+        };
+        let (fragment, fragment_dir) = read_json(&dir.join(file), stack)?;
+        let mut fragment = match fragment {
+            Value::Array(_) => fragment,
+            entry => Value::Array(vec![entry]),
+        };
+        resolve_includes(&mut fragment, &fragment_dir, stack)?;
+        stack.pop();
+        if let Value::Array(entries) = fragment {
+            for mut entry in entries {
+                rebase_prompt_paths(&mut entry, &fragment_dir);
+                resolved.push(entry);
+            }
+        }
+    }
+    *items = resolved;
+    Ok(())
+}
+
+fn include_path(item: &Value) -> Option<&str> {
+    let fields = item.as_object().filter(|fields| fields.len() == 1)?;
+    fields.get("include")?.as_str()
+}
+
+/// Join the relative paths in the template read from `dir` onto it
+fn rebase_template_paths(template: &mut Value, dir: &Path) {
+    if let Some(Value::Array(dirs)) = template.pointer_mut("/wildcards/dirs") {
+        dirs.iter_mut().for_each(|path| rebase_path(path, dir));
+    }
+    if let Some(base_prompt) = template.get_mut("base_prompt") {
+        rebase_prompt_paths(base_prompt, dir);
+    }
+    if let Some(Value::Array(prompts)) = template.get_mut("prompts") {
+        prompts
+            .iter_mut()
+            .for_each(|prompt| rebase_prompt_paths(prompt, dir));
+    }
+}
+
+/// Join the image paths in a base or detailed prompt read from `dir` onto it
+fn rebase_prompt_paths(prompt: &mut Value, dir: &Path) {
+    for pointer in ["/img2img/init_image", "/inpaint/mask"] {
+        if let Some(path) = prompt.pointer_mut(pointer) {
+            rebase_path(path, dir);
+        }
+    }
+    if let Some(Value::Array(units)) = prompt.get_mut("controlnet") {
+        for image in units.iter_mut().filter_map(|unit| unit.get_mut("image")) {
+            rebase_path(image, dir);
+        }
+    }
+}
+
+fn rebase_path(path: &mut Value, dir: &Path) {
+    if let Value::String(path) = path {
+        if !path.is_empty() {
+            *path = dir.join(&*path).to_string_lossy().into_owned();
+        }
+    }
+}
+
+/// Put the extending template's settings on top of the extended template's
+fn merge_template(base: Value, template: Value, extends: &Extends) -> Value {
+    let (Value::Object(mut merged), Value::Object(fields)) = (base, template) else {
+        unreachable!("templates to be checked to be objects when loaded")
+    };
+    for (key, value) in fields {
+        let appended = matches!(key.as_str(), "prompts" | "modifiers")
+            && extends.list_merge(&key) == ListMerge::Append;
+        match (merged.get_mut(&key), value) {
+            (Some(Value::Array(base_items)), Value::Array(items)) if appended => {
+                base_items.extend(items)
+            }
+            (Some(target), value) => merge(target, value),
+            (None, value) => {
+                merged.insert(key, value);
+            }
+        }
+    }
+    Value::Object(merged)
+}
+
+/// Merge objects key by key, replacing anything else
+fn merge(target: &mut Value, value: Value) {
+    match (target, value) {
+        (Value::Object(target), Value::Object(fields)) => merge_objects(target, fields),
+        (target, value) => *target = value,
+    }
+}
+
+fn merge_objects(target: &mut Map<String, Value>, fields: Map<String, Value>) {
+    for (key, value) in fields {
+        match target.get_mut(&key) {
+            Some(existing) => merge(existing, value),
+            None => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::testing::test_dir;
+    use super::super::BatchTemplate;
+    use super::*;
+
+    fn write(dir: &Path, name: &str, value: Value) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, value.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn extended_templates_are_merged() {
+        let dir = test_dir();
+        write(
+            &dir,
+            "base.json",
+            json!({
+                "name": "base",
+                "base_prompt": {
+                    "positive": "masterpiece",
+                    "negative": "lowres",
+                    "model": "model.safetensors",
+                    "sampler": "Euler a",
+                    "steps": 20,
+                    "width": 512,
+                    "height": 512,
+                    "cfg": 7.0,
+                    "hires": { "upscaler": "Latent", "upscale_by": 2.0, "denoising_strength": 0.5, "steps": 10 },
+                },
+                "prompts": ["cat"],
+                "modifiers": [{ "prompt": "smiling" }],
+            }),
+        );
+        write(
+            &dir,
+            "pools/animals.json",
+            json!(["dog", { "include": "bird.json" }]),
+        );
+        write(&dir, "pools/bird.json", json!("bird"));
+        let template_file = write(
+            &dir,
+            "child.json",
+            json!({
+                "name": "child",
+                "extends": { "template": "base.json", "modifiers": "Replace" },
+                "base_prompt": { "steps": 30, "hires": { "denoising_strength": 0.3 } },
+                "prompts": [{ "include": "pools/animals.json" }, "fish"],
+                "modifiers": [{ "prompt": "night" }],
+            }),
+        );
+
+        let template = BatchTemplate::from_file(&template_file).unwrap();
+        assert_eq!(template.name, "child");
+        assert_eq!(template.base_prompt.negative, "lowres");
+        assert_eq!(template.base_prompt.steps, 30);
+        let hires = template.base_prompt.hires.as_ref().unwrap();
+        assert_eq!(
+            (hires.upscaler.as_str(), hires.denoising_strength),
+            ("Latent", 0.3)
+        );
+        let prompts = serde_json::to_value(&template.prompts).unwrap();
+        assert_eq!(prompts, json!(["cat", "dog", "bird", "fish"]));
+        let modifiers = template.modifiers.as_ref().unwrap();
+        assert_eq!(modifiers.len(), 1);
+        assert_eq!(modifiers[0].prompt, "night");
+    }
+
+    #[test]
+    fn paths_are_relative_to_their_template() {
+        let dir = test_dir();
+        write(
+            &dir,
+            "bases/base.json",
+            json!({
+                "name": "base",
+                "base_prompt": {
+                    "img2img": { "init_image": "init.png", "denoising_strength": 0.5 },
+                    "inpaint": { "mask": "mask.png" },
+                    "controlnet": [{ "model": "openpose", "image": "/poses/pose.png" }],
+                },
+                "prompts": [{ "prompt": "cat", "img2img": { "init_image": "cat.png", "denoising_strength": 0.5 } }],
+                "wildcards": { "dirs": ["wildcards", "../shared"] },
+            }),
+        );
+        write(
+            &dir,
+            "pools/dogs.json",
+            json!([{ "prompt": "dog", "controlnet": [{ "model": "openpose", "image": "dog.png" }] }]),
+        );
+        let child = write(
+            &dir,
+            "child.json",
+            json!({
+                "name": "child",
+                "extends": "bases/base.json",
+                "base_prompt": { "inpaint": { "mask": "child-mask.png" } },
+                "prompts": [{ "include": "pools/dogs.json" }],
+            }),
+        );
+
+        let template = load_template(&child).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        let path = |path: &str| json!(dir.join(path).to_string_lossy());
+        let base_prompt = &template["base_prompt"];
+        assert_eq!(base_prompt["img2img"]["init_image"], path("bases/init.png"));
+        assert_eq!(base_prompt["inpaint"]["mask"], "child-mask.png");
+        assert_eq!(base_prompt["controlnet"][0]["image"], "/poses/pose.png");
+        assert_eq!(
+            template["prompts"][0]["img2img"]["init_image"],
+            path("bases/cat.png")
+        );
+        assert_eq!(
+            template["prompts"][1]["controlnet"][0]["image"],
+            path("pools/dog.png")
+        );
+        assert_eq!(
+            template["wildcards"]["dirs"],
+            json!([path("bases/wildcards"), path("bases/../shared")])
+        );
+    }
+
+    #[test]
+    fn cycles_are_errors() {
+        let dir = test_dir();
+        let a = write(&dir, "a.json", json!({ "extends": "b.json" }));
+        write(&dir, "b.json", json!({ "extends": "a.json" }));
+        let error = load_template(&a).unwrap_err().to_string();
+        assert!(error.contains("templates include each other"));
+        assert!(error.ends_with("a.json"));
+
+        let pool = write(
+            &dir,
+            "pool.json",
+            json!({ "prompts": [{ "include": "pool.json" }] }),
+        );
+        assert!(load_template(&pool).is_err());
+        // The same fragment can be included twice, as long as it doesn't include itself
+        write(&dir, "fragment.json", json!(["cat"]));
+        let twice = write(
+            &dir,
+            "twice.json",
+            json!({ "prompts": [{ "include": "fragment.json" }, { "include": "fragment.json" }] }),
+        );
+        assert_eq!(
+            load_template(&twice).unwrap()["prompts"],
+            json!(["cat", "cat"])
+        );
+    }
+}
@@ -0,0 +1,388 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use super::post_process;
+use super::{BatchLog, GeneratedImage, OutputFormat, PromptData, Prompts};
+
+/// Longest side of the thumbnails embedded in the gallery
+const THUMBNAIL_SIZE: u32 = 256;
+
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Which pool prompt and modifier a prompt was rolled from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PromptOrigin {
+    /// Index of the prompt in the template's pool
+    pub prompt_index: usize,
+    /// The pool prompt as written in the template, with its options separated by " | "
+    pub prompt: String,
+    /// Modifier prompt added to the prompt as written in the template, if one was
+    pub modifier: Option<String>,
+}
+
+impl PromptOrigin {
+    pub fn new(prompt_index: usize, prompt: &Prompts) -> PromptOrigin {
+        let prompt = match prompt {
+            Prompts::Single(positive) => positive.clone(),
+            Prompts::Multiple(options) => options.join(" | "),
+            Prompts::MultipleWeighted(options) => options
+                .iter()
+                .map(|option| option.prompt.as_str())
+                .collect::<Vec<_>>()
+                .join(" | "),
+            Prompts::Detailed(detailed) => detailed.prompt.clone(),
+        };
+        PromptOrigin {
+            prompt_index,
+            prompt,
+            modifier: None,
+        }
+    }
+}
+
+/// Write a self-contained HTML page of every image in the log next to it, returning its path
+///
+/// Thumbnails are embedded in the page, and link to the full images next to it.
+pub fn write_gallery(log: &BatchLog) -> anyhow::Result<PathBuf> {
+    let gallery_path = log.file_path.with_extension("html");
+    let output_dir = log.destination().output_dir;
+    std::fs::write(&gallery_path, render(log, &output_dir)?)?;
+    Ok(gallery_path)
+}
+
+fn render(log: &BatchLog, output_dir: &Path) -> anyhow::Result<String> {
+    let mut pool_prompts: Vec<&PromptOrigin> = vec![];
+    let mut modifiers: Vec<&str> = vec![];
+    for origin in log
+        .images
+        .iter()
+        .filter_map(|prompt| prompt.origin.as_ref())
+    {
+        if !pool_prompts
+            .iter()
+            .any(|seen| seen.prompt_index == origin.prompt_index)
+        {
+            pool_prompts.push(origin);
+        }
+        if let Some(modifier) = origin.modifier.as_deref() {
+            if !modifiers.contains(&modifier) {
+                modifiers.push(modifier);
+            }
+        }
+    }
+    pool_prompts.sort_by_key(|origin| origin.prompt_index);
+
+    let mut html = String::new();
+    writeln!(html, "<!DOCTYPE html>")?;
+    writeln!(html, "<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(html, "<title>{}</title>", escape(&log.template))?;
+    writeln!(html, "<style>{}</style>\n</head>\n<body>", STYLE)?;
+    writeln!(html, "<h1>{}</h1>", escape(&log.template))?;
+
+    if !pool_prompts.is_empty() {
+        writeln!(html, "<div class=\"filters\">")?;
+        writeln!(
+            html,
+            "<label>Prompt <select id=\"prompt-filter\" onchange=\"filterImages()\">"
+        )?;
+        writeln!(html, "<option value=\"\">All</option>")?;
+        for origin in &pool_prompts {
+            writeln!(
+                html,
+                "<option value=\"{}\">{}: {}</option>",
+                origin.prompt_index,
+                origin.prompt_index,
+                escape(&truncate(&origin.prompt, 80))
+            )?;
+        }
+        writeln!(html, "</select></label>")?;
+        writeln!(
+            html,
+            "<label>Modifier <select id=\"modifier-filter\" onchange=\"filterImages()\">"
+        )?;
+        writeln!(html, "<option value=\"\">All</option>")?;
+        writeln!(html, "<option value=\"none\">None</option>")?;
+        for (index, modifier) in modifiers.iter().enumerate() {
+            writeln!(
+                html,
+                "<option value=\"{}\">{}</option>",
+                index,
+                escape(&truncate(modifier, 80))
+            )?;
+        }
+        writeln!(html, "</select></label>\n</div>")?;
+    }
+
+    let log_file = log.file_path.to_string_lossy();
+    writeln!(html, "<div class=\"images\">")?;
+    let mut images = 0;
+    for (index, prompt) in log.images.iter().enumerate() {
+        let outputs = prompt.outputs.as_deref().unwrap_or_default();
+        for (batch_index, image) in outputs.iter().enumerate() {
+            let mut command = format!("sdbatch reroll {} {}", shell_quote(&log_file), index);
+            if outputs.len() > 1 {
+                command.push_str(&format!(" --image {}", batch_index));
+            }
+            let modifier = match prompt.origin.as_ref().map(|origin| &origin.modifier) {
+                Some(Some(modifier)) => modifiers
+                    .iter()
+                    .position(|m| m == modifier)
+                    .map(|m| m.to_string())
+                    .unwrap_or_default(),
+                Some(None) => "none".to_string(),
+                None => String::new(),
+            };
+            let card = Card {
+                index,
+                prompt,
+                image,
+                thumbnail: thumbnail(&output_dir.join(&image.filename)),
+                command,
+                modifier,
+            };
+            card.render(&mut html)?;
+            images += 1;
+        }
+    }
+    writeln!(html, "</div>")?;
+    if images == 0 {
+        writeln!(html, "<p>No images have been generated yet.</p>")?;
+    }
+
+    writeln!(html, "<script>{}</script>\n</body>\n</html>", SCRIPT)?;
+    Ok(html)
+}
+
+/// One generated image on the page
+struct Card<'a> {
+    index: usize,
+    prompt: &'a PromptData,
+    image: &'a GeneratedImage,
+    /// Data URI of the image's thumbnail, if it could be read
+    thumbnail: Option<String>,
+    /// Command to reroll the image with
+    command: String,
+    /// Value of the modifier filter that shows the image
+    modifier: String,
+}
+
+impl Card<'_> {
+    fn render(&self, html: &mut String) -> anyhow::Result<()> {
+        let prompt = self.prompt;
+        let pool_prompt = prompt
+            .origin
+            .as_ref()
+            .map(|origin| origin.prompt_index.to_string())
+            .unwrap_or_default();
+        writeln!(
+            html,
+            "<div class=\"card\" data-prompt=\"{}\" data-modifier=\"{}\">",
+            pool_prompt, self.modifier
+        )?;
+        let filename = escape(&self.image.filename);
+        match &self.thumbnail {
+            Some(thumbnail) => writeln!(
+                html,
+                "<a href=\"{}\"><img src=\"{}\" alt=\"{}\"></a>",
+                filename, thumbnail, filename
+            )?,
+            None => writeln!(html, "<p class=\"missing\">{} is missing</p>", filename)?,
+        }
+        writeln!(
+            html,
+            "<h2>#{} <a href=\"{}\">{}</a></h2>",
+            self.index, filename, filename
+        )?;
+        writeln!(
+            html,
+            "<p class=\"prompt\">{}</p>",
+            escape(&prompt.full_positive())
+        )?;
+        let negative = prompt.full_negative();
+        if !negative.is_empty() {
+            writeln!(
+                html,
+                "<p class=\"negative\"><b>Negative:</b> {}</p>",
+                escape(&negative)
+            )?;
+        }
+        let mut settings = vec![
+            ("Seed", self.image.seed.to_string()),
+            ("Sampler", prompt.sampler.clone()),
+            ("Steps", prompt.steps.to_string()),
+            ("CFG", prompt.cfg.to_string()),
+            ("Size", format!("{}x{}", prompt.width, prompt.height)),
+            ("Model", prompt.model.clone()),
+        ];
+        if let Some(origin) = &prompt.origin {
+            settings.push(("Pool prompt", origin.prompt_index.to_string()));
+            if let Some(modifier) = &origin.modifier {
+                settings.push(("Modifier", modifier.clone()));
+            }
+        }
+        writeln!(html, "<dl>")?;
+        for (name, value) in settings {
+            writeln!(html, "<dt>{}</dt><dd>{}</dd>", name, escape(&value))?;
+        }
+        writeln!(html, "</dl>")?;
+        writeln!(
+            html,
+            "<button data-command=\"{}\" onclick=\"copyCommand(this)\">Copy reroll command</button>",
+            escape(&self.command)
+        )?;
+        writeln!(html, "</div>")?;
+        Ok(())
+    }
+}
+
+/// JPEG thumbnail of the image as a data URI, or None if it can't be read
+fn thumbnail(path: &Path) -> Option<String> {
+    let image = image::open(path).ok()?;
+    let thumbnail = image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let jpeg = post_process::encode(&thumbnail, OutputFormat::Jpeg, THUMBNAIL_QUALITY).ok()?;
+    Some(format!(
+        "data:image/jpeg;base64,{}",
+        general_purpose::STANDARD.encode(jpeg)
+    ))
+}
+
+/// Quote the text for the shell if it needs to be
+fn shell_quote(text: &str) -> String {
+    let plain = text
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/\\._-:".contains(c));
+    match plain {
+        true => text.to_string(),
+        false => format!("'{}'", text.replace('\'', "'\\''")),
+    }
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// Escape text for HTML content and quoted attributes
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1em; background: #202020; color: #e0e0e0; }
+a { color: #8ab4f8; }
+.filters { display: flex; gap: 1em; margin-bottom: 1em; }
+.filters select { max-width: 30em; }
+.images { display: grid; grid-template-columns: repeat(auto-fill, minmax(300px, 1fr)); gap: 1em; }
+.card { background: #2c2c2c; border-radius: 4px; padding: 0.5em; }
+.card img { display: block; margin: 0 auto; max-width: 100%; }
+.card h2 { font-size: 1em; }
+.prompt, .negative { white-space: pre-wrap; word-break: break-word; }
+.negative { color: #b0b0b0; }
+.missing { color: #f28b82; }
+dl { display: grid; grid-template-columns: max-content auto; gap: 0.2em 1em; }
+dt { font-weight: bold; }
+dd { margin: 0; word-break: break-word; }
+"#;
+
+const SCRIPT: &str = r#"
+function filterImages() {
+    const prompt = document.getElementById("prompt-filter").value;
+    const modifier = document.getElementById("modifier-filter").value;
+    for (const card of document.querySelectorAll(".card")) {
+        card.hidden = (prompt !== "" && card.dataset.prompt !== prompt)
+            || (modifier !== "" && card.dataset.modifier !== modifier);
+    }
+}
+
+function copyCommand(button) {
+    const command = button.dataset.command;
+    const copied = () => {
+        button.textContent = "Copied!";
+        setTimeout(() => button.textContent = "Copy reroll command", 1500);
+    };
+    if (navigator.clipboard) {
+        navigator.clipboard.writeText(command).then(copied);
+    } else {
+        // Pages opened from a file aren't always allowed the clipboard API
+        const text = document.createElement("textarea");
+        text.value = command;
+        document.body.appendChild(text);
+        text.select();
+        document.execCommand("copy");
+        text.remove();
+        copied();
+    }
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::super::testing::test_dir;
+    use super::*;
+
+    #[test]
+    fn gallery_shows_every_generated_image() {
+        let output_dir = test_dir();
+        RgbaImage::from_pixel(512, 256, image::Rgba([255, 0, 0, 255]))
+            .save(output_dir.join("00.png"))
+            .unwrap();
+
+        let mut log = BatchLog::new("gallery", &output_dir);
+        let generated = |filename: &str| GeneratedImage {
+            filename: filename.to_string(),
+            seed: 1234,
+            subseed: 0,
+            info: String::new(),
+            original: None,
+        };
+        let mut origin = PromptOrigin::new(
+            2,
+            &Prompts::Multiple(vec!["cat".to_string(), "dog".to_string()]),
+        );
+        origin.modifier = Some("wearing a \"hat\"".to_string());
+        log.images = vec![
+            PromptData {
+                positive: "cat, <lora:style:0.8>".to_string(),
+                origin: Some(origin),
+                outputs: Some(vec![generated("00.png"), generated("00-1.png")]),
+                ..Default::default()
+            },
+            // Not generated yet
+            PromptData::default(),
+        ];
+
+        let html = render(&log, &output_dir).unwrap();
+        assert_eq!(html.matches("class=\"card\"").count(), 2);
+        assert_eq!(html.matches("data:image/jpeg;base64,").count(), 1);
+        assert!(html.contains("00-1.png is missing"));
+        assert!(html.contains("cat, &lt;lora:style:0.8&gt;"));
+        assert!(html.contains("<option value=\"2\">2: cat | dog</option>"));
+        assert!(html.contains("<option value=\"0\">wearing a &quot;hat&quot;</option>"));
+        assert!(html.contains("data-prompt=\"2\" data-modifier=\"0\""));
+        let log_file = log.file_path.to_string_lossy();
+        assert!(html.contains(&format!("sdbatch reroll {} 0 --image 1", log_file)));
+        assert_eq!(shell_quote("my logs/it's.json"), "'my logs/it'\\''s.json'");
+
+        let gallery = write_gallery(&log).unwrap();
+        assert_eq!(gallery.extension().unwrap(), "html");
+        assert!(gallery.exists());
+    }
+}
@@ -0,0 +1,74 @@
+use font8x8::{UnicodeFonts, BASIC_FONTS, LATIN_FONTS};
+use image::{Rgba, RgbaImage};
+
+/// Width and height of a character of label text, before scaling
+pub const GLYPH_SIZE: u32 = 8;
+
+pub const BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+pub const TEXT_COLOR: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+/// Width of `text` in pixels when drawn at `scale`
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    text.chars().count() as u32 * GLYPH_SIZE * scale
+}
+
+/// Shorten `text` with "..." so it fits in `max_width` pixels when drawn at `scale`
+pub fn fit_text(text: &str, max_width: u32, scale: u32) -> String {
+    let max_chars = (max_width / (GLYPH_SIZE * scale)) as usize;
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut fitted: String = text.chars().take(max_chars.saturating_sub(3)).collect();
+    fitted.push_str("...");
+    fitted
+}
+
+/// Draw a line of text with its top left corner at `x`, `y`, clipping anything outside the image
+///
+/// Characters the font doesn't have are drawn as "?"
+pub fn draw_text(image: &mut RgbaImage, x: u32, y: u32, text: &str, scale: u32) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph = BASIC_FONTS
+            .get(c)
+            .or_else(|| LATIN_FONTS.get(c))
+            .or_else(|| BASIC_FONTS.get('?'))
+            .unwrap_or_default();
+        let glyph_x = x + i as u32 * GLYPH_SIZE * scale;
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_SIZE {
+                // Bit 0 is the leftmost pixel of the row
+                if bits & (1 << column) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = glyph_x + column * scale + dx;
+                        let py = y + row as u32 * scale + dy;
+                        if px < image.width() && py < image.height() {
+                            image.put_pixel(px, py, TEXT_COLOR);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_text_is_shortened() {
+        assert_eq!(fit_text("Euler a", 80, 1), "Euler a");
+        assert_eq!(fit_text("DPM++ 2M Karras", 80, 1), "DPM++ 2...");
+        assert_eq!(text_width(&fit_text("DPM++ 2M Karras", 80, 2), 2), 80);
+    }
+
+    #[test]
+    fn text_is_clipped_to_image() {
+        let mut image = RgbaImage::from_pixel(12, 4, BACKGROUND);
+        draw_text(&mut image, 0, 0, "MM", 1);
+        assert!(image.pixels().any(|pixel| *pixel == TEXT_COLOR));
+    }
+}
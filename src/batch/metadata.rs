@@ -0,0 +1,680 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+
+use image::{DynamicImage, ImageOutputFormat};
+
+use super::{BatchError, GeneratedImage, HiResSettings, OutputFormat, PromptData};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+/// EXIF tags of the pointer to the Exif IFD, and of the UserComment in it
+const EXIF_IFD_TAG: u16 = 0x8769;
+const USER_COMMENT_TAG: u16 = 0x9286;
+const UNICODE_PREFIX: &[u8] = b"UNICODE\0";
+const ASCII_PREFIX: &[u8] = b"ASCII\0\0\0";
+
+/// Key Automatic1111 stores the generation parameters under, read by PNG Info and Civitai
+pub const PARAMETERS_KEY: &str = "parameters";
+pub const TEMPLATE_KEY: &str = "sdbatch-template";
+pub const LOG_FILE_KEY: &str = "sdbatch-log";
+pub const INDEX_KEY: &str = "sdbatch-index";
+pub const BATCH_INDEX_KEY: &str = "sdbatch-batch-index";
+
+/// Where a run's images are saved, and what to record in them about the run
+#[derive(Clone)]
+pub struct ImageDestination {
+    pub output_dir: PathBuf,
+    /// Name of the template the images were generated from
+    pub template: String,
+    /// Log file of the run, saved next to the images
+    pub log_file: PathBuf,
+}
+
+impl ImageDestination {
+    /// Text to embed in the `batch_index`th image of the log entry at `index`
+    ///
+    /// The parameters are the server's own infotext when it sent one, otherwise they're built from the prompt
+    pub fn text_chunks(
+        &self,
+        prompt: &PromptData,
+        image: &GeneratedImage,
+        index: usize,
+        batch_index: usize,
+    ) -> Vec<(&'static str, String)> {
+        let parameters = match image.info.as_str() {
+            "" => infotext(prompt, image.seed),
+            info => info.to_string(),
+        };
+        let log_file = self
+            .log_file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        vec![
+            (PARAMETERS_KEY, parameters),
+            (TEMPLATE_KEY, self.template.clone()),
+            (LOG_FILE_KEY, log_file),
+            (INDEX_KEY, index.to_string()),
+            (BATCH_INDEX_KEY, batch_index.to_string()),
+        ]
+    }
+}
+
+/// Describe the prompt the way Automatic1111 does in its "parameters" text
+pub fn infotext(prompt: &PromptData, seed: i64) -> String {
+    let mut settings = vec![
+        format!("Steps: {}", prompt.steps),
+        format!("Sampler: {}", prompt.sampler),
+        format!("CFG scale: {}", prompt.cfg),
+        format!("Seed: {}", seed),
+        format!("Size: {}x{}", prompt.width, prompt.height),
+    ];
+    if !prompt.model.is_empty() {
+        match prompt.model.rsplit_once(" [") {
+            Some((name, hash)) if hash.ends_with(']') => {
+                settings.push(format!("Model hash: {}", hash.trim_end_matches(']')));
+                settings.push(format!("Model: {}", name));
+            }
+            _ => settings.push(format!("Model: {}", prompt.model)),
+        }
+    }
+    if let Some(clip_skip) = prompt.clip_skip.filter(|c| *c > 1) {
+        settings.push(format!("Clip skip: {}", clip_skip));
+    }
+    if let Some(img2img) = &prompt.img2img {
+        settings.push(format!(
+            "Denoising strength: {}",
+            img2img.denoising_strength
+        ));
+    }
+    if let Some(hires) = &prompt.hires {
+        settings.push(format!("Denoising strength: {}", hires.denoising_strength));
+        settings.push(format!("Hires upscale: {}", hires.upscale_by));
+        settings.push(format!("Hires steps: {}", hires.steps));
+        settings.push(format!("Hires upscaler: {}", hires.upscaler));
+    }
+
+    let mut text = prompt.full_positive();
+    let negative = prompt.full_negative();
+    if !negative.is_empty() {
+        text.push_str(&format!("\nNegative prompt: {}", negative));
+    }
+    text.push('\n');
+    text.push_str(&settings.join(", "));
+    text
+}
+
+/// Read an Automatic1111 "parameters" text back into a prompt, setting its seed
+///
+/// Settings sdbatch doesn't use, like the VAE or ADetailer's, are ignored
+pub fn parse_infotext(text: &str) -> anyhow::Result<PromptData> {
+    let mut lines: Vec<&str> = text.trim().lines().collect();
+    let settings = match lines.pop() {
+        Some(last) if last.starts_with("Steps: ") => parse_settings(last),
+        _ => {
+            return Err(BatchError {
+                message: "no generation settings found in the parameters".to_string(),
+            }
+            .into())
+        }
+    };
+
+    let mut prompt = PromptData::default();
+    let negative_start = lines
+        .iter()
+        .position(|line| line.starts_with("Negative prompt:"))
+        .unwrap_or(lines.len());
+    prompt.positive = lines[..negative_start].join("\n");
+    if let Some((first, rest)) = lines[negative_start..].split_first() {
+        let mut negative = vec![first.trim_start_matches("Negative prompt:").trim_start()];
+        negative.extend(rest);
+        prompt.negative = negative.join("\n");
+    }
+
+    let setting = |key: &str| {
+        settings
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    };
+    let number = |key: &str| -> anyhow::Result<Option<f32>> {
+        setting(key)
+            .map(|value| {
+                value.parse::<f32>().map_err(|_| BatchError {
+                    message: format!("\"{}\" isn't a valid {}", value, key),
+                })
+            })
+            .transpose()
+            .map_err(Into::into)
+    };
+
+    prompt.steps = number("Steps")?.unwrap_or_default() as u32;
+    prompt.cfg = number("CFG scale")?.unwrap_or_default();
+    prompt.seed = setting("Seed").and_then(|seed| seed.parse().ok());
+    prompt.clip_skip = number("Clip skip")?.map(|clip_skip| clip_skip as u8);
+    prompt.sampler = setting("Sampler").unwrap_or_default().to_string();
+    // Newer versions of Automatic1111 record the scheduler on its own, older ones only know the combined name
+    if let Some(schedule) = setting("Schedule type").filter(|s| *s != "Automatic") {
+        prompt.sampler = format!("{} {}", prompt.sampler, schedule);
+    }
+    // Automatic1111 accepts the model's name without the hash or extension
+    prompt.model = setting("Model").unwrap_or_default().to_string();
+    if let Some((width, height)) = setting("Size").and_then(|size| size.split_once('x')) {
+        prompt.width = width.parse()?;
+        prompt.height = height.parse()?;
+    }
+    if let Some(upscale_by) = number("Hires upscale")? {
+        prompt.hires = Some(HiResSettings {
+            upscaler: setting("Hires upscaler").unwrap_or("Latent").to_string(),
+            upscale_by,
+            denoising_strength: number("Denoising strength")?.unwrap_or(0.7),
+            steps: number("Hires steps")?.unwrap_or_default() as u8,
+        });
+    }
+
+    Ok(prompt)
+}
+
+/// Split a settings line like `Steps: 20, Lora hashes: "a: 1, b: 2"` into keys and values
+fn parse_settings(line: &str) -> Vec<(String, String)> {
+    let mut settings = vec![];
+    let mut rest = line.trim();
+    while let Some((key, after_key)) = rest.split_once(':') {
+        let after_key = after_key.trim_start();
+        let (value, after_value) = match after_key.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let after = quoted.get(end + 1..).unwrap_or_default();
+                (&quoted[..end], after.split_once(',').map_or("", |(_, a)| a))
+            }
+            None => after_key.split_once(',').unwrap_or((after_key, "")),
+        };
+        settings.push((key.trim().to_string(), value.trim().to_string()));
+        rest = after_value.trim_start();
+    }
+    settings
+}
+
+pub fn is_png(image_bytes: &[u8]) -> bool {
+    image_bytes.starts_with(&PNG_SIGNATURE)
+}
+
+pub fn encode_png(image: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+    let mut png = vec![];
+    image.write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+/// Add text chunks to a PNG, replacing any chunks already there with the same keys
+///
+/// Text that can't be written as Latin-1 is written as UTF-8 in an iTXt chunk, like Automatic1111 does
+pub fn add_text_chunks(png: &[u8], texts: &[(&str, String)]) -> anyhow::Result<Vec<u8>> {
+    let chunks = read_chunks(png)?;
+    let mut output = PNG_SIGNATURE.to_vec();
+    for chunk in &chunks {
+        if chunk.kind == *b"IEND" {
+            for (key, text) in texts {
+                write_text_chunk(&mut output, key, text);
+            }
+        }
+        let replaced =
+            text_chunk(chunk).is_some_and(|(key, _)| texts.iter().any(|(k, _)| *k == key));
+        if !replaced {
+            write_chunk(&mut output, &chunk.kind, chunk.data);
+        }
+    }
+    Ok(output)
+}
+
+/// Every uncompressed text chunk in a PNG, by key
+pub fn read_text_chunks(png: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    Ok(read_chunks(png)?.iter().filter_map(text_chunk).collect())
+}
+
+/// Embed the texts in an image encoded as `format`
+///
+/// PNGs get every text as a chunk. JPEG and WebP only get the parameters, as an EXIF UserComment
+/// like Automatic1111 saves them.
+pub fn embed_texts(
+    bytes: &[u8],
+    format: OutputFormat,
+    texts: &[(&str, String)],
+) -> anyhow::Result<Vec<u8>> {
+    let parameters = texts.iter().find(|(key, _)| *key == PARAMETERS_KEY);
+    match (format, parameters) {
+        (OutputFormat::Png, _) => add_text_chunks(bytes, texts),
+        (_, None) => Ok(bytes.to_vec()),
+        (OutputFormat::Jpeg, Some((_, parameters))) => {
+            add_jpeg_exif(bytes, &user_comment_exif(parameters))
+        }
+        (OutputFormat::WebP, Some((_, parameters))) => {
+            add_webp_exif(bytes, &user_comment_exif(parameters))
+        }
+    }
+}
+
+/// The texts embedded in a PNG, JPEG or WebP by `embed_texts`
+pub fn read_texts(bytes: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    if is_png(bytes) {
+        return read_text_chunks(bytes);
+    }
+    let exif = match bytes {
+        [0xff, 0xd8, ..] => jpeg_exif(bytes),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => webp_exif(bytes),
+        _ => None,
+    };
+    Ok(exif
+        .and_then(read_user_comment)
+        .map(|parameters| vec![(PARAMETERS_KEY.to_string(), parameters)])
+        .unwrap_or_default())
+}
+
+/// A big-endian TIFF structure with only an Exif IFD holding the UserComment, written as UTF-16
+fn user_comment_exif(comment: &str) -> Vec<u8> {
+    let mut user_comment = UNICODE_PREFIX.to_vec();
+    user_comment.extend(comment.encode_utf16().flat_map(u16::to_be_bytes));
+
+    // Header, then each IFD with its one entry and no next IFD
+    let exif_ifd_offset: u32 = 8 + 18;
+    let user_comment_offset: u32 = exif_ifd_offset + 18;
+    let mut tiff = b"MM\0\x2a".to_vec();
+    tiff.extend(8u32.to_be_bytes());
+    for (tag, kind, count, value) in [
+        (EXIF_IFD_TAG, 4u16, 1, exif_ifd_offset),
+        (
+            USER_COMMENT_TAG,
+            7u16,
+            user_comment.len() as u32,
+            user_comment_offset,
+        ),
+    ] {
+        tiff.extend(1u16.to_be_bytes());
+        tiff.extend(tag.to_be_bytes());
+        tiff.extend(kind.to_be_bytes());
+        tiff.extend(count.to_be_bytes());
+        tiff.extend(value.to_be_bytes());
+        tiff.extend(0u32.to_be_bytes());
+    }
+    tiff.extend(user_comment);
+    tiff
+}
+
+/// Add an APP1 segment with the EXIF after the JPEG's JFIF header
+fn add_jpeg_exif(jpeg: &[u8], tiff: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let length = u16::try_from(2 + EXIF_HEADER.len() + tiff.len()).map_err(|_| BatchError {
+        message: "generation parameters are too long to embed in a JPEG".to_string(),
+    })?;
+    let mut insert_at = 2;
+    if jpeg.get(2..4) == Some(&[0xff, 0xe0]) {
+        insert_at += 2 + jpeg_segment_length(jpeg, 2)?;
+    }
+    let mut output = jpeg[..insert_at].to_vec();
+    output.extend([0xff, 0xe1]);
+    output.extend(length.to_be_bytes());
+    output.extend(EXIF_HEADER);
+    output.extend(tiff);
+    output.extend(&jpeg[insert_at..]);
+    Ok(output)
+}
+
+/// Length of the JPEG segment whose marker is at `marker`, including the length bytes
+fn jpeg_segment_length(jpeg: &[u8], marker: usize) -> anyhow::Result<usize> {
+    let length = jpeg.get(marker + 2..marker + 4).ok_or_else(|| BatchError {
+        message: "image isn't a valid JPEG".to_string(),
+    })?;
+    Ok(u16::from_be_bytes([length[0], length[1]]) as usize)
+}
+
+/// The TIFF structure in the JPEG's EXIF segment
+fn jpeg_exif(jpeg: &[u8]) -> Option<&[u8]> {
+    let mut marker = 2;
+    // Segments with a length come before the image data, which starts at SOS
+    while jpeg.get(marker) == Some(&0xff) && jpeg.get(marker + 1) != Some(&0xda) {
+        let length = jpeg_segment_length(jpeg, marker).ok()?;
+        let data = jpeg.get(marker + 4..marker + 2 + length)?;
+        if jpeg[marker + 1] == 0xe1 {
+            if let Some(tiff) = data.strip_prefix(EXIF_HEADER) {
+                return Some(tiff);
+            }
+        }
+        marker += 2 + length;
+    }
+    None
+}
+
+/// Add an EXIF chunk to the WebP, switching it to the extended format that allows one
+fn add_webp_exif(webp: &[u8], tiff: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let chunks = webp_chunks(webp).ok_or_else(|| BatchError {
+        message: "image isn't a valid WebP".to_string(),
+    })?;
+    let mut body = b"WEBP".to_vec();
+    match chunks.first() {
+        Some((b"VP8X", _)) => {}
+        Some((kind, data)) => {
+            let (width, height, alpha) = webp_size(kind, data).ok_or_else(|| BatchError {
+                message: "WebP image has no size".to_string(),
+            })?;
+            let mut header = vec![if alpha { 0x10 } else { 0 }, 0, 0, 0];
+            header.extend(&(width - 1).to_le_bytes()[..3]);
+            header.extend(&(height - 1).to_le_bytes()[..3]);
+            write_webp_chunk(&mut body, b"VP8X", &header);
+        }
+        None => {}
+    }
+    for (kind, data) in &chunks {
+        match *kind {
+            b"VP8X" => {
+                let mut header = data.to_vec();
+                // Flag that there's EXIF
+                header[0] |= 0x08;
+                write_webp_chunk(&mut body, kind, &header);
+            }
+            b"EXIF" => {}
+            _ => write_webp_chunk(&mut body, kind, data),
+        }
+    }
+    write_webp_chunk(&mut body, b"EXIF", tiff);
+
+    let mut output = b"RIFF".to_vec();
+    output.extend((body.len() as u32).to_le_bytes());
+    output.extend(body);
+    Ok(output)
+}
+
+/// Kind and data of each chunk in a WebP
+fn webp_chunks(webp: &[u8]) -> Option<Vec<(&[u8; 4], &[u8])>> {
+    let mut rest = webp.get(12..)?;
+    let mut chunks = vec![];
+    while rest.len() >= 8 {
+        let kind: &[u8; 4] = rest[..4].try_into().ok()?;
+        let length = u32::from_le_bytes(rest[4..8].try_into().ok()?) as usize;
+        chunks.push((kind, rest.get(8..8 + length)?));
+        // Chunks are padded to an even length
+        rest = rest.get(8 + length + length % 2..).unwrap_or_default();
+    }
+    Some(chunks)
+}
+
+fn write_webp_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend(kind);
+    output.extend((data.len() as u32).to_le_bytes());
+    output.extend(data);
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+/// Width, height and whether there's alpha, from a simple WebP's lossy or lossless image chunk
+fn webp_size(kind: &[u8; 4], data: &[u8]) -> Option<(u32, u32, bool)> {
+    match kind {
+        b"VP8 " => {
+            let size = |at: usize| {
+                Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?) & 0x3fff)
+            };
+            Some((size(6)? as u32, size(8)? as u32, false))
+        }
+        b"VP8L" => {
+            let bits = u32::from_le_bytes(data.get(1..5)?.try_into().ok()?);
+            Some((
+                (bits & 0x3fff) + 1,
+                ((bits >> 14) & 0x3fff) + 1,
+                bits & (1 << 28) != 0,
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn webp_exif(webp: &[u8]) -> Option<&[u8]> {
+    let (_, data) = webp_chunks(webp)?
+        .into_iter()
+        .find(|(kind, _)| *kind == b"EXIF")?;
+    // Some tools keep the JPEG segment's header in the chunk
+    Some(data.strip_prefix(EXIF_HEADER).unwrap_or(data))
+}
+
+/// The UserComment in the TIFF structure of an image's EXIF
+fn read_user_comment(tiff: &[u8]) -> Option<String> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| -> Option<u16> {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    };
+    // Count and offset of the IFD entry with the tag
+    let find_entry = |ifd: usize, tag: u16| -> Option<(usize, usize)> {
+        (0..u16_at(ifd)? as usize)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|entry| u16_at(*entry) == Some(tag))
+            .and_then(|entry| Some((u32_at(entry + 4)? as usize, u32_at(entry + 8)? as usize)))
+    };
+
+    let (_, exif_ifd) = find_entry(u32_at(4)? as usize, EXIF_IFD_TAG)?;
+    let (count, offset) = find_entry(exif_ifd, USER_COMMENT_TAG)?;
+    let comment = tiff.get(offset..offset + count)?;
+    if let Some(text) = comment.strip_prefix(UNICODE_PREFIX) {
+        let units: Vec<u16> = text
+            .chunks_exact(2)
+            .map(|unit| match big_endian {
+                true => u16::from_be_bytes([unit[0], unit[1]]),
+                false => u16::from_le_bytes([unit[0], unit[1]]),
+            })
+            .collect();
+        return Some(String::from_utf16_lossy(&units));
+    }
+    let text = comment.strip_prefix(ASCII_PREFIX).unwrap_or(comment);
+    Some(
+        String::from_utf8_lossy(text)
+            .trim_end_matches('\0')
+            .to_string(),
+    )
+}
+
+struct Chunk<'a> {
+    kind: [u8; 4],
+    data: &'a [u8],
+}
+
+fn read_chunks(png: &[u8]) -> anyhow::Result<Vec<Chunk<'_>>> {
+    let invalid = || BatchError {
+        message: "image isn't a valid PNG".to_string(),
+    };
+    let mut rest = png.strip_prefix(&PNG_SIGNATURE).ok_or_else(invalid)?;
+    let mut chunks = vec![];
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(invalid().into());
+        }
+        let length = u32::from_be_bytes(rest[..4].try_into()?) as usize;
+        let kind: [u8; 4] = rest[4..8].try_into()?;
+        let data = rest.get(8..8 + length).ok_or_else(invalid)?;
+        chunks.push(Chunk { kind, data });
+        // Skip the data and its CRC
+        rest = rest.get(12 + length..).ok_or_else(invalid)?;
+    }
+    Ok(chunks)
+}
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    output.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+fn write_text_chunk(output: &mut Vec<u8>, key: &str, text: &str) {
+    let mut data = key.as_bytes().to_vec();
+    data.push(0);
+    let latin1: Option<Vec<u8>> = text.chars().map(|c| u8::try_from(c).ok()).collect();
+    match latin1 {
+        Some(latin1) => {
+            data.extend(latin1);
+            write_chunk(output, b"tEXt", &data);
+        }
+        None => {
+            // Uncompressed, with no language tag or translated key
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(text.as_bytes());
+            write_chunk(output, b"iTXt", &data);
+        }
+    }
+}
+
+/// Key and text of a tEXt chunk, or an uncompressed iTXt chunk
+fn text_chunk(chunk: &Chunk) -> Option<(String, String)> {
+    let (key, rest) = split_at_nul(chunk.data)?;
+    let key: String = key.iter().map(|b| *b as char).collect();
+    match &chunk.kind {
+        b"tEXt" => Some((key, rest.iter().map(|b| *b as char).collect())),
+        b"iTXt" => {
+            let (&compressed, rest) = rest.split_first()?;
+            if compressed != 0 {
+                return None;
+            }
+            // Skip the compression method, then the language tag and translated key
+            let (_, rest) = split_at_nul(rest.get(1..)?)?;
+            let (_, text) = split_at_nul(rest)?;
+            Some((key, String::from_utf8_lossy(text).into_owned()))
+        }
+        _ => None,
+    }
+}
+
+fn split_at_nul(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let nul = data.iter().position(|b| *b == 0)?;
+    Some((&data[..nul], &data[nul + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_png() -> Vec<u8> {
+        encode_png(&image::RgbImage::new(4, 4).into()).unwrap()
+    }
+
+    #[test]
+    fn text_chunks_are_added_and_replaced() {
+        let png = add_text_chunks(
+            &blank_png(),
+            &[
+                (PARAMETERS_KEY, "old".to_string()),
+                (INDEX_KEY, "1".to_string()),
+            ],
+        )
+        .unwrap();
+        let png = add_text_chunks(
+            &png,
+            &[(PARAMETERS_KEY, "1girl, café\nSteps: 20".to_string())],
+        )
+        .unwrap();
+
+        let texts = read_text_chunks(&png).unwrap();
+        assert_eq!(
+            texts,
+            vec![
+                (INDEX_KEY.to_string(), "1".to_string()),
+                (
+                    PARAMETERS_KEY.to_string(),
+                    "1girl, café\nSteps: 20".to_string()
+                ),
+            ]
+        );
+        // Still a valid image
+        assert_eq!(image::load_from_memory(&png).unwrap().width(), 4);
+    }
+
+    #[test]
+    fn unicode_text_is_written_as_utf8() {
+        let png = add_text_chunks(&blank_png(), &[(PARAMETERS_KEY, "猫".to_string())]).unwrap();
+        assert_eq!(read_text_chunks(&png).unwrap()[0].1, "猫");
+    }
+
+    #[test]
+    fn parameters_are_embedded_as_exif() {
+        let texts = [
+            (PARAMETERS_KEY, "1girl, 猫\nSteps: 20".to_string()),
+            (INDEX_KEY, "1".to_string()),
+        ];
+        let rgb = DynamicImage::ImageRgb8(image::RgbImage::new(6, 4));
+        let rgba = DynamicImage::ImageRgba8(image::RgbaImage::new(6, 4));
+        for (image, format) in [
+            (&rgb, OutputFormat::Jpeg),
+            (&rgb, OutputFormat::WebP),
+            (&rgba, OutputFormat::WebP),
+        ] {
+            let encoded = super::super::post_process::encode(image, format, 90).unwrap();
+            let embedded = embed_texts(&encoded, format, &texts).unwrap();
+            assert_eq!(
+                read_texts(&embedded).unwrap(),
+                vec![(PARAMETERS_KEY.to_string(), texts[0].1.clone())]
+            );
+            // Still a valid image
+            let decoded = image::load_from_memory(&embedded).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (6, 4));
+        }
+    }
+
+    #[test]
+    fn parses_automatic1111_parameters() {
+        let text = "1girl, solo,\n<lora:add_detail:0.5>\nNegative prompt: lowres,\nbad hands\n\
+                    Steps: 25, Sampler: DPM++ 2M, Schedule type: Karras, CFG scale: 6.5, Seed: 1234, \
+                    Size: 512x768, Model hash: abc123, Model: base, Denoising strength: 0.4, Clip skip: 2, \
+                    Hires upscale: 1.5, Hires steps: 10, Hires upscaler: R-ESRGAN 4x+, \
+                    Lora hashes: \"add_detail: 7c6bad76eb54\", Version: v1.9.4";
+        let prompt = parse_infotext(text).unwrap();
+        assert_eq!(prompt.positive, "1girl, solo,\n<lora:add_detail:0.5>");
+        assert_eq!(prompt.negative, "lowres,\nbad hands");
+        assert_eq!(prompt.steps, 25);
+        assert_eq!(prompt.sampler, "DPM++ 2M Karras");
+        assert_eq!(prompt.cfg, 6.5);
+        assert_eq!(prompt.seed, Some(1234));
+        assert_eq!((prompt.width, prompt.height), (512, 768));
+        assert_eq!(prompt.model, "base");
+        assert_eq!(prompt.clip_skip, Some(2));
+        let hires = prompt.hires.unwrap();
+        assert_eq!(hires.upscaler, "R-ESRGAN 4x+");
+        assert_eq!(hires.upscale_by, 1.5);
+        assert_eq!(hires.denoising_strength, 0.4);
+        assert_eq!(hires.steps, 10);
+
+        assert!(parse_infotext("just a prompt").is_err());
+    }
+
+    #[test]
+    fn infotext_matches_automatic1111() {
+        let prompt = PromptData {
+            positive: "1girl, solo".to_string(),
+            negative: "lowres".to_string(),
+            model: "base.safetensors [abc123]".to_string(),
+            sampler: "Euler a".to_string(),
+            steps: 20,
+            width: 512,
+            height: 768,
+            cfg: 7.5,
+            clip_skip: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            infotext(&prompt, 42),
+            "1girl, solo\nNegative prompt: lowres\nSteps: 20, Sampler: Euler a, CFG scale: 7.5, Seed: 42, \
+             Size: 512x768, Model hash: abc123, Model: base.safetensors, Clip skip: 2"
+        );
+    }
+}
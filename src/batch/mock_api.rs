@@ -0,0 +1,1089 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::{json, Value};
+use tiny_http::{Header, Response, Server};
+
+/// What the mock server knows about, and what it was asked to do
+struct MockState {
+    /// Checkpoint currently loaded
+    model: String,
+    models: Vec<String>,
+    samplers: Vec<String>,
+    upscalers: Vec<String>,
+    latent_upscale_modes: Vec<String>,
+    loras: Vec<String>,
+    /// Seed given to the next request that asks for a random one
+    next_seed: i64,
+    /// Status and body to answer the next requests to a path with, instead of handling them
+    errors: HashMap<String, VecDeque<(u16, String)>>,
+    /// Path and body of every POST, in the order they arrived
+    posted: Vec<(String, Value)>,
+}
+
+/// An Automatic1111 API on a random local port, answering generation requests with placeholder PNGs
+///
+/// Stops when dropped.
+pub struct MockServer {
+    pub url: String,
+    state: Arc<Mutex<MockState>>,
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn start() -> MockServer {
+        let server = Arc::new(Server::http("127.0.0.1:0").expect("mock server to start"));
+        let addr = server
+            .server_addr()
+            .to_ip()
+            .expect("mock server to listen on an IP address");
+        let state = Arc::new(Mutex::new(MockState {
+            model: "base.safetensors [abc123]".to_string(),
+            models: vec![
+                "base.safetensors [abc123]".to_string(),
+                "other.safetensors [def456]".to_string(),
+            ],
+            samplers: vec!["Euler a".to_string(), "DPM++ 2M Karras".to_string()],
+            upscalers: vec!["Lanczos".to_string(), "R-ESRGAN 4x+".to_string()],
+            latent_upscale_modes: vec!["Latent".to_string(), "Latent (nearest-exact)".to_string()],
+            loras: vec!["add_detail".to_string(), "film_grain".to_string()],
+            next_seed: 1000,
+            errors: HashMap::new(),
+            posted: vec![],
+        }));
+
+        let thread = {
+            let (server, state) = (server.clone(), state.clone());
+            std::thread::spawn(move || {
+                // Fails once the server is unblocked on drop
+                while let Ok(mut request) = server.recv() {
+                    let mut body = String::new();
+                    let _ = request.as_reader().read_to_string(&mut body);
+                    let path = request.url().split('?').next().unwrap_or("").to_string();
+                    let is_post = *request.method() == tiny_http::Method::Post;
+                    let (status, body) = handle(&state, is_post, &path, &body);
+                    let response = Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(
+                            Header::from_bytes("Content-Type", "application/json")
+                                .expect("valid header"),
+                        );
+                    let _ = request.respond(response);
+                }
+            })
+        };
+
+        MockServer {
+            url: format!("http://{}", addr),
+            state,
+            server,
+            thread: Some(thread),
+        }
+    }
+
+    /// Answer the next request to `path` with `status` and `body`, after any errors already queued for it
+    pub fn fail_next(&self, path: &str, status: u16, body: &str) {
+        self.lock()
+            .errors
+            .entry(path.to_string())
+            .or_default()
+            .push_back((status, body.to_string()));
+    }
+
+    /// Bodies of every POST to `path`, in the order they arrived
+    pub fn posted(&self, path: &str) -> Vec<Value> {
+        self.lock()
+            .posted
+            .iter()
+            .filter(|(posted_path, _)| posted_path == path)
+            .map(|(_, body)| body.clone())
+            .collect()
+    }
+
+    pub fn model(&self) -> String {
+        self.lock().model.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().expect("mock state lock")
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn handle(state: &Mutex<MockState>, is_post: bool, path: &str, body: &str) -> (u16, String) {
+    let mut state = state.lock().expect("mock state lock");
+    if let Some((status, body)) = state.errors.get_mut(path).and_then(VecDeque::pop_front) {
+        return (status, body);
+    }
+    let request: Value = serde_json::from_str(body).unwrap_or(Value::Null);
+    if is_post {
+        state.posted.push((path.to_string(), request.clone()));
+    }
+
+    match (is_post, path) {
+        (false, "/sdapi/v1/options") => ok(json!({ "sd_model_checkpoint": state.model })),
+        (true, "/sdapi/v1/options") => {
+            let model = request["sd_model_checkpoint"].as_str().unwrap_or_default();
+            // Models can be picked by their title or by their name alone
+            let Some(title) = state
+                .models
+                .iter()
+                .find(|title| *title == model || model_name(title) == model)
+                .cloned()
+            else {
+                return error(500, &format!("RuntimeError: model '{}' not found", model));
+            };
+            state.model = title;
+            ok(Value::Null)
+        }
+        (false, "/sdapi/v1/sd-models") => ok(state
+            .models
+            .iter()
+            .map(|title| {
+                json!({
+                    "title": title,
+                    "model_name": model_name(title),
+                    "hash": null,
+                    "sha256": null,
+                    "filename": format!("/models/{}", title),
+                    "config": null,
+                })
+            })
+            .collect()),
+        (false, "/sdapi/v1/samplers") => ok(state
+            .samplers
+            .iter()
+            .map(|name| json!({ "name": name, "aliases": [], "options": {} }))
+            .collect()),
+        (false, "/sdapi/v1/upscalers") => ok(state
+            .upscalers
+            .iter()
+            .map(|name| json!({ "name": name, "model_name": null, "scale": 4.0 }))
+            .collect()),
+        (false, "/sdapi/v1/latent-upscale-modes") => ok(state
+            .latent_upscale_modes
+            .iter()
+            .map(|name| json!({ "name": name }))
+            .collect()),
+        (false, "/sdapi/v1/loras") => ok(state
+            .loras
+            .iter()
+            .map(|name| json!({ "name": name, "alias": name, "path": format!("/loras/{}.safetensors", name), "metadata": {} }))
+            .collect()),
+        (false, "/sdapi/v1/embeddings") => ok(json!({
+            "loaded": { "easynegative": { "step": null, "shape": 768, "vectors": 8 } },
+            "skipped": { "sdxl_only": { "step": null, "shape": 1280, "vectors": 2 } },
+        })),
+        (false, "/sdapi/v1/prompt-styles") => ok(json!([
+            { "name": "cinematic", "prompt": "{prompt}, film still", "negative_prompt": "cartoon" },
+        ])),
+        (false, "/sdapi/v1/sd-vae") => ok(json!([
+            { "model_name": "vae-ft-mse", "filename": "/models/VAE/vae-ft-mse.safetensors" },
+        ])),
+        (false, "/sdapi/v1/progress") => ok(json!({
+            "progress": 0.5,
+            "eta_relative": 1.5,
+            "state": { "sampling_step": 10, "sampling_steps": 20 },
+            "current_image": null,
+        })),
+        (true, "/sdapi/v1/txt2img") | (true, "/sdapi/v1/img2img") => generate(&mut state, request),
+        (true, "/sdapi/v1/extra-single-image") => extras(&state, request),
+        _ => error(404, "Not Found"),
+    }
+}
+
+/// Answer a generation request like Automatic1111, with a grid first when there's more than one image
+fn generate(state: &mut MockState, request: Value) -> (u16, String) {
+    let sampler = request["sampler_name"].as_str().unwrap_or_default();
+    if !state.samplers.iter().any(|s| s == sampler) {
+        return error(404, "Sampler not found");
+    }
+    if request["enable_hr"].as_bool().unwrap_or(false) {
+        let upscaler = request["hr_upscaler"].as_str().unwrap_or_default();
+        let known = state.upscalers.iter().chain(&state.latent_upscale_modes);
+        if !known.into_iter().any(|u| u == upscaler) {
+            return error(
+                500,
+                &format!("ValueError: could not find upscaler named {}", upscaler),
+            );
+        }
+    }
+
+    let count = (request["batch_size"].as_i64().unwrap_or(1)
+        * request["n_iter"].as_i64().unwrap_or(1)) as usize;
+    let first_seed = match request["seed"].as_i64().unwrap_or(-1) {
+        -1 => {
+            state.next_seed += 100;
+            state.next_seed
+        }
+        seed => seed,
+    };
+    let all_seeds: Vec<i64> = (0..count as i64).map(|i| first_seed + i).collect();
+    let width = request["width"].as_u64().unwrap_or(64) as u32;
+    let height = request["height"].as_u64().unwrap_or(64) as u32;
+
+    let mut images = vec![];
+    if count > 1 {
+        images.push(placeholder_png(width, height, 0));
+    }
+    images.extend(
+        all_seeds
+            .iter()
+            .map(|&seed| placeholder_png(width, height, seed)),
+    );
+    let info = json!({
+        "all_seeds": all_seeds,
+        "all_subseeds": all_seeds.iter().map(|seed| seed + 1).collect::<Vec<_>>(),
+        "infotexts": all_seeds
+            .iter()
+            .map(|seed| infotext(&state.model, &request, *seed))
+            .collect::<Vec<_>>(),
+        "index_of_first_image": if count > 1 { 1 } else { 0 },
+    });
+    ok(json!({
+        "images": images,
+        "parameters": request,
+        "info": info.to_string(),
+    }))
+}
+
+/// Answer an extras request by scaling the image up, without any actual upscaler
+fn extras(state: &MockState, request: Value) -> (u16, String) {
+    let upscaler = request["upscaler_1"].as_str().unwrap_or_default();
+    if !state.upscalers.iter().any(|u| u == upscaler) {
+        return error(
+            500,
+            &format!("AssertionError: could not find upscaler named {}", upscaler),
+        );
+    }
+    let image_bytes = general_purpose::STANDARD
+        .decode(request["image"].as_str().unwrap_or_default())
+        .expect("image to be base64");
+    let image = image::load_from_memory(&image_bytes).expect("image to decode");
+    let scale_by = request["upscaling_resize"].as_f64().unwrap_or(1.0) as f32;
+    let upscaled = image.resize_exact(
+        (image.width() as f32 * scale_by) as u32,
+        (image.height() as f32 * scale_by) as u32,
+        image::imageops::FilterType::Nearest,
+    );
+    let mut png = vec![];
+    upscaled
+        .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+        .expect("upscaled image to encode");
+    ok(json!({
+        "html_info": "",
+        "image": general_purpose::STANDARD.encode(png),
+    }))
+}
+
+/// Model title without its extension and hash, like "base"
+fn model_name(title: &str) -> &str {
+    title.split('.').next().unwrap_or(title)
+}
+
+/// Parameters of a generated image, in the format Automatic1111 writes them
+fn infotext(model: &str, request: &Value, seed: i64) -> String {
+    format!(
+        "{}\nNegative prompt: {}\nSteps: {}, Sampler: {}, CFG scale: {}, Seed: {}, Size: {}x{}, Model: {}",
+        request["prompt"].as_str().unwrap_or_default(),
+        request["negative_prompt"].as_str().unwrap_or_default(),
+        request["steps"],
+        request["sampler_name"].as_str().unwrap_or_default(),
+        request["cfg_scale"],
+        seed,
+        request["width"],
+        request["height"],
+        model_name(model),
+    )
+}
+
+/// Base64 encoded PNG filled with a color picked from the seed
+fn placeholder_png(width: u32, height: u32, seed: i64) -> String {
+    let color = image::Rgb([seed as u8, (seed >> 8) as u8, 128]);
+    let mut png = vec![];
+    image::RgbImage::from_pixel(width, height, color)
+        .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+        .expect("placeholder to encode");
+    general_purpose::STANDARD.encode(png)
+}
+
+fn ok(body: Value) -> (u16, String) {
+    (200, body.to_string())
+}
+
+fn error(status: u16, detail: &str) -> (u16, String) {
+    (status, json!({ "detail": detail }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::super::retry::RetryPolicy;
+    use super::super::testing::{test_dir, TestDir};
+    use super::super::*;
+    use super::*;
+
+    fn server_options(server: &MockServer) -> ServerOptions {
+        ServerOptions {
+            api_urls: vec![server.url.clone()],
+            retry: RetryPolicy {
+                retry_backoff: 0.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn run_options(server: &MockServer) -> RunOptions {
+        RunOptions {
+            server: server_options(server),
+            seed: Some(42),
+            quiet: true,
+            ..Default::default()
+        }
+    }
+
+    /// Options that leave every problem to be found by the generation requests
+    fn mid_run_options(server: &MockServer) -> RunOptions {
+        RunOptions {
+            skip_preflight: true,
+            ..run_options(server)
+        }
+    }
+
+    fn template() -> BatchTemplate {
+        BatchTemplate {
+            name: "mock".to_string(),
+            base_prompt: PromptData {
+                positive: "masterpiece".to_string(),
+                model: "other.safetensors [def456]".to_string(),
+                sampler: "Euler a".to_string(),
+                steps: 20,
+                width: 16,
+                height: 16,
+                cfg: 7.0,
+                ..Default::default()
+            },
+            prompts: vec![
+                Prompts::Single("cat".to_string()),
+                Prompts::Single("dog".to_string()),
+                Prompts::Single("bird".to_string()),
+            ],
+            ..Default::default()
+        }
+    }
+
+    /// A fresh directory with the template written to it, returning the directory and the template path
+    fn write_template(template: &BatchTemplate) -> (TestDir, PathBuf) {
+        let dir = test_dir();
+        let template_file = dir.join("template.json");
+        fs::write(&template_file, serde_json::to_string(template).unwrap()).unwrap();
+        (dir, template_file)
+    }
+
+    /// Run the template in a fresh directory, which the images and log are written to
+    fn run(
+        template: &BatchTemplate,
+        options: &RunOptions,
+    ) -> anyhow::Result<(TemplateRunResults, TestDir)> {
+        let (output_dir, template_file) = write_template(template);
+        let results = do_run(
+            &template_file.to_string_lossy(),
+            &output_dir.to_string_lossy(),
+            options,
+        )?;
+        Ok((results, output_dir))
+    }
+
+    fn read_log(log_file: &Path) -> BatchLog {
+        BatchLog::from_file(&log_file.to_string_lossy()).unwrap()
+    }
+
+    fn error_message(result: anyhow::Result<impl Sized>) -> String {
+        match result {
+            Ok(_) => panic!("expected the run to fail"),
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    #[test]
+    fn progress_is_reported() {
+        let server = MockServer::start();
+        let api = auto1111_api::APIClient::new(
+            &server.url,
+            std::time::Duration::from_secs(5),
+            &None,
+            &None,
+        )
+        .unwrap();
+        let progress = api.progress().unwrap().unwrap();
+        assert_eq!(progress.state.sampling_steps, 20);
+    }
+
+    #[test]
+    fn inventory_lists_every_kind() {
+        let server = MockServer::start();
+        let options = server_options(&server);
+        let names = |kind| -> Vec<String> {
+            list_inventory(&options, kind)
+                .unwrap()
+                .into_iter()
+                .map(|item| item.name)
+                .collect()
+        };
+        assert_eq!(names(Inventory::Models)[1], "other.safetensors [def456]");
+        assert_eq!(
+            names(Inventory::Samplers),
+            vec!["Euler a", "DPM++ 2M Karras"]
+        );
+        assert_eq!(names(Inventory::Upscalers).len(), 4);
+        assert_eq!(names(Inventory::Loras), vec!["add_detail", "film_grain"]);
+        assert_eq!(
+            names(Inventory::Embeddings),
+            vec!["easynegative", "sdxl_only"]
+        );
+        assert_eq!(names(Inventory::Styles), vec!["cinematic"]);
+        assert_eq!(names(Inventory::Vaes), vec!["vae-ft-mse"]);
+
+        let models = list_inventory(&options, Inventory::Models).unwrap();
+        let json = serde_json::to_value(&models).unwrap();
+        assert_eq!(json[0]["model_name"], "base");
+    }
+
+    #[test]
+    fn run_generates_every_image() {
+        let server = MockServer::start();
+        let (results, output_dir) = run(&template(), &run_options(&server)).unwrap();
+
+        assert_eq!(results.images_created, 3);
+        assert_eq!(server.model(), "other.safetensors [def456]");
+        assert_eq!(server.posted("/sdapi/v1/txt2img").len(), 3);
+        let log = read_log(&results.log_file);
+        for (index, image) in log.images.iter().enumerate() {
+            assert!(image_path(&output_dir, index).exists());
+            let outputs = image.outputs.as_ref().unwrap();
+            assert_eq!(image.seed, Some(outputs[0].seed));
+            assert!(outputs[0].info.contains("Steps: 20"));
+        }
+    }
+
+    #[test]
+    fn images_embed_their_parameters() {
+        let server = MockServer::start();
+        // Raw images from the server, then resized ones
+        for (post_process, width) in [
+            (None, 16),
+            (Some(vec![PostProcesses::Resize { scale_by: 2.0 }]), 32),
+        ] {
+            let mut template = template();
+            template.base_prompt.post_process = post_process;
+            let (results, output_dir) = run(&template, &run_options(&server)).unwrap();
+
+            let log = read_log(&results.log_file);
+            let log_file = results.log_file.file_name().unwrap().to_string_lossy();
+            for (index, image) in log.images.iter().enumerate() {
+                let png = fs::read(image_path(&output_dir, index)).unwrap();
+                let texts: HashMap<_, _> = metadata::read_text_chunks(&png)
+                    .unwrap()
+                    .into_iter()
+                    .collect();
+                let outputs = image.outputs.as_ref().unwrap();
+                assert_eq!(texts[metadata::PARAMETERS_KEY], outputs[0].info);
+                assert_eq!(texts[metadata::TEMPLATE_KEY], "mock");
+                assert_eq!(texts[metadata::LOG_FILE_KEY], log_file);
+                assert_eq!(texts[metadata::INDEX_KEY], index.to_string());
+                assert_eq!(texts[metadata::BATCH_INDEX_KEY], "0");
+                assert_eq!(image::load_from_memory(&png).unwrap().width(), width);
+            }
+        }
+    }
+
+    #[test]
+    fn run_writes_a_gallery() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.modifiers = Some(vec![PromptModifer {
+            prompt: "wearing a hat".to_string(),
+            ..Default::default()
+        }]);
+        let options = RunOptions {
+            gallery: true,
+            ..run_options(&server)
+        };
+        let (results, _dir) = run(&template, &options).unwrap();
+
+        let log = read_log(&results.log_file);
+        for image in &log.images {
+            let origin = image.origin.as_ref().unwrap();
+            assert_eq!(
+                image.positive,
+                format!("masterpiece, {}, wearing a hat", origin.prompt)
+            );
+            assert_eq!(origin.modifier.as_deref(), Some("wearing a hat"));
+        }
+        let html = fs::read_to_string(results.log_file.with_extension("html")).unwrap();
+        assert_eq!(html.matches("data:image/jpeg;base64,").count(), 3);
+    }
+
+    #[test]
+    fn converted_images_keep_the_original() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.count = Some(1);
+        template.base_prompt.post_process = Some(vec![
+            PostProcesses::ResizeExact {
+                width: 24,
+                height: 8,
+            },
+            PostProcesses::Convert {
+                format: OutputFormat::Jpeg,
+                quality: Some(80),
+            },
+            PostProcesses::KeepOriginal,
+        ]);
+        let (results, output_dir) = run(&template, &run_options(&server)).unwrap();
+
+        let log = read_log(&results.log_file);
+        let output = &log.images[0].outputs.as_ref().unwrap()[0];
+        assert_eq!(output.filename, "00.jpg");
+        assert_eq!(output.original.as_deref(), Some("00-original.png"));
+        let converted = fs::read(output_dir.join(&output.filename)).unwrap();
+        let converted_image = image::load_from_memory(&converted).unwrap();
+        assert_eq!((converted_image.width(), converted_image.height()), (24, 8));
+        assert_eq!(
+            metadata::read_texts(&converted).unwrap(),
+            vec![(metadata::PARAMETERS_KEY.to_string(), output.info.clone())]
+        );
+        let original = fs::read(output_dir.join("00-original.png")).unwrap();
+        assert_eq!(image::load_from_memory(&original).unwrap().width(), 16);
+        assert!(metadata::read_text_chunks(&original)
+            .unwrap()
+            .iter()
+            .any(|(key, _)| key == metadata::PARAMETERS_KEY));
+
+        // Resume finds the converted image and leaves it alone
+        let generated = resume(
+            &results.log_file.to_string_lossy(),
+            &server_options(&server),
+            true,
+            false,
+        )
+        .unwrap();
+        assert_eq!(generated, 0);
+    }
+
+    #[test]
+    fn images_are_upscaled_on_the_server() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.count = Some(1);
+        template.base_prompt.post_process = Some(vec![PostProcesses::Upscale(UpscaleSettings {
+            upscaler: "R-ESRGAN 4x+".to_string(),
+            scale_by: 2.0,
+            gfpgan: None,
+            codeformer: Some(0.5),
+            codeformer_weight: None,
+        })]);
+        let (_, output_dir) = run(&template, &run_options(&server)).unwrap();
+
+        let upscaled = image::open(image_path(&output_dir, 0)).unwrap();
+        assert_eq!(upscaled.width(), 32);
+        let request = &server.posted("/sdapi/v1/extra-single-image")[0];
+        assert_eq!(request["upscaling_resize"], 2.0);
+        assert_eq!(request["codeformer_visibility"], 0.5);
+    }
+
+    #[test]
+    fn upscale_keeps_the_originals() {
+        let server = MockServer::start();
+        let (results, output_dir) = run(&template(), &run_options(&server)).unwrap();
+        let log_file = results.log_file.to_string_lossy();
+        // As if the run had stopped before the last image
+        let mut edited = read_log(&results.log_file);
+        edited.images[2].outputs = None;
+        fs::write(&results.log_file, serde_json::to_string(&edited).unwrap()).unwrap();
+        let mut settings = UpscaleSettings {
+            upscaler: "4x-Nothing".to_string(),
+            scale_by: 3.0,
+            gfpgan: None,
+            codeformer: None,
+            codeformer_weight: None,
+        };
+        let result = upscale(&log_file, &settings, &server_options(&server));
+        assert!(error_message(result).contains("Upscaler \"4x-Nothing\" not found"));
+
+        settings.upscaler = "Lanczos".to_string();
+        assert_eq!(
+            upscale(&log_file, &settings, &server_options(&server)).unwrap(),
+            2
+        );
+        // Upscaling again with the same settings leaves the images and steps as they are
+        assert_eq!(
+            upscale(&log_file, &settings, &server_options(&server)).unwrap(),
+            0
+        );
+        let log = read_log(&results.log_file);
+        let output = &log.images[0].outputs.as_ref().unwrap()[0];
+        assert_eq!(output.original.as_deref(), Some("00-original.png"));
+        // Including the image that hasn't been generated, so Resume upscales it
+        assert!(log.images.iter().all(|prompt| matches!(
+            prompt.post_process.as_deref(),
+            Some([PostProcesses::Upscale(_)])
+        )));
+        let png = fs::read(image_path(&output_dir, 0)).unwrap();
+        assert_eq!(image::load_from_memory(&png).unwrap().width(), 48);
+        assert!(metadata::read_text_chunks(&png)
+            .unwrap()
+            .iter()
+            .any(|(key, _)| key == metadata::PARAMETERS_KEY));
+        let original = image::open(output_dir.join("00-original.png")).unwrap();
+        assert_eq!(original.width(), 16);
+    }
+
+    #[test]
+    fn batch_images_skip_the_grid() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.base_prompt.batch_size = Some(2);
+        template.count = Some(1);
+        let (results, output_dir) = run(&template, &run_options(&server)).unwrap();
+
+        let log = read_log(&results.log_file);
+        let outputs = log.images[0].outputs.as_ref().unwrap();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[1].seed, outputs[0].seed + 1);
+        let second = image::open(output_dir.join(&outputs[1].filename))
+            .unwrap()
+            .to_rgb8();
+        // The grid is drawn with seed 0, the batch images with their own seeds
+        assert_eq!(second.get_pixel(0, 0)[0], outputs[1].seed as u8);
+    }
+
+    #[test]
+    fn resume_generates_missing_images() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.base_prompt.batch_size = Some(2);
+        let (results, output_dir) = run(&template, &run_options(&server)).unwrap();
+        let log = read_log(&results.log_file);
+        let missing_batch_image = &log.images[2].outputs.as_ref().unwrap()[1];
+        fs::remove_file(image_path(&output_dir, 0)).unwrap();
+        fs::remove_file(output_dir.join(&missing_batch_image.filename)).unwrap();
+        // Forget the first image entirely, as if the run had stopped before it
+        let mut edited = read_log(&results.log_file);
+        edited.images[0].outputs = None;
+        fs::write(&results.log_file, serde_json::to_string(&edited).unwrap()).unwrap();
+
+        let created = resume(
+            &results.log_file.to_string_lossy(),
+            &server_options(&server),
+            true,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(created, 2);
+        assert!(image_path(&output_dir, 0).exists());
+        assert!(output_dir.join(&missing_batch_image.filename).exists());
+        // The batch image is restored with the seed it was recorded with
+        let requests = server.posted("/sdapi/v1/txt2img");
+        assert!(requests
+            .iter()
+            .any(|request| request["seed"] == missing_batch_image.seed));
+    }
+
+    #[test]
+    fn reroll_uses_a_new_seed() {
+        let server = MockServer::start();
+        let (results, _dir) = run(&template(), &run_options(&server)).unwrap();
+        let before = read_log(&results.log_file);
+
+        reroll(
+            &results.log_file.to_string_lossy(),
+            1,
+            None,
+            &server_options(&server),
+            true,
+        )
+        .unwrap();
+
+        let after = read_log(&results.log_file);
+        assert_eq!(
+            server.posted("/sdapi/v1/txt2img").last().unwrap()["seed"],
+            -1
+        );
+        assert_ne!(after.images[1].seed, before.images[1].seed);
+        assert_eq!(after.images[0].seed, before.images[0].seed);
+        assert_eq!(after.images[1].positive, before.images[1].positive);
+    }
+
+    #[test]
+    fn imported_images_can_be_rerolled() {
+        let server = MockServer::start();
+        let (results, generated_dir) = run(&template(), &run_options(&server)).unwrap();
+        let generated = read_log(&results.log_file);
+        let import_dir = test_dir();
+
+        let template_file = import_template(
+            &[generated_dir.to_string_lossy().into_owned()],
+            "imported",
+            &import_dir,
+        )
+        .unwrap();
+        let imported = BatchTemplate::from_file(&template_file).unwrap();
+        assert_eq!(imported.prompts.len(), 3);
+        assert_eq!(imported.base_prompt.model, "other");
+        assert_eq!(imported.base_prompt.sampler, "Euler a");
+        assert!(validate(
+            &template_file.to_string_lossy(),
+            &server_options(&server),
+            false
+        )
+        .unwrap()
+        .is_empty());
+
+        let log_file = import_log(
+            &[generated_dir.to_string_lossy().into_owned()],
+            "imported",
+            &import_dir,
+        )
+        .unwrap();
+        let before = read_log(&log_file);
+        assert_eq!(before.images.len(), 3);
+        for (imported, generated) in before.images.iter().zip(&generated.images) {
+            assert_eq!(imported.positive, generated.positive);
+            assert_eq!(imported.seed, generated.seed);
+        }
+        assert!(image_path(&import_dir, 2).exists());
+        let message = error_message(import_log(
+            &[generated_dir.to_string_lossy().into_owned()],
+            "again",
+            &import_dir,
+        ));
+        assert!(message.contains("already exists"));
+
+        reroll(
+            &log_file.to_string_lossy(),
+            0,
+            None,
+            &server_options(&server),
+            true,
+        )
+        .unwrap();
+        let after = read_log(&log_file);
+        assert_ne!(after.images[0].seed, before.images[0].seed);
+        assert_eq!(server.model(), "other.safetensors [def456]");
+    }
+
+    #[test]
+    fn reroll_all_regenerates_every_image() {
+        let server = MockServer::start();
+        let (results, _dir) = run(&template(), &run_options(&server)).unwrap();
+        let before = read_log(&results.log_file);
+
+        reroll_all(
+            &results.log_file.to_string_lossy(),
+            &server_options(&server),
+            true,
+            false,
+        )
+        .unwrap();
+
+        let after = read_log(&results.log_file);
+        assert_eq!(server.posted("/sdapi/v1/txt2img").len(), 6);
+        for (before, after) in before.images.iter().zip(&after.images) {
+            assert_ne!(before.seed, after.seed);
+        }
+    }
+
+    #[test]
+    fn sweep_cells_share_their_seed_when_resumed_and_rerolled() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.count = Some(2);
+        template.sweep = Some(
+            serde_json::from_value(json!({
+                "x": { "field": "cfg", "values": [5, 7.5] },
+            }))
+            .unwrap(),
+        );
+        let (results, output_dir) = run(&template, &run_options(&server)).unwrap();
+        let before = read_log(&results.log_file);
+        assert_eq!(before.images.len(), 4);
+        fs::remove_file(image_path(&output_dir, 1)).unwrap();
+        let mut edited = read_log(&results.log_file);
+        edited.images[1].outputs = None;
+        fs::write(&results.log_file, serde_json::to_string(&edited).unwrap()).unwrap();
+
+        resume(
+            &results.log_file.to_string_lossy(),
+            &server_options(&server),
+            true,
+            false,
+        )
+        .unwrap();
+        let resumed = read_log(&results.log_file);
+        assert_eq!(
+            server.posted("/sdapi/v1/txt2img").last().unwrap()["seed"],
+            before.images[1].seed.unwrap()
+        );
+        assert_eq!(resumed.images[1].seed, resumed.images[0].seed);
+
+        reroll_all(
+            &results.log_file.to_string_lossy(),
+            &server_options(&server),
+            true,
+            false,
+        )
+        .unwrap();
+        let after = read_log(&results.log_file);
+        assert_ne!(after.images[0].seed, before.images[0].seed);
+        assert_eq!(after.images[0].seed, after.images[1].seed);
+        assert_eq!(after.images[2].seed, after.images[3].seed);
+        assert_ne!(after.images[0].seed, after.images[2].seed);
+
+        // Rerolling a cell rerolls its whole sweep
+        let log_file = results.log_file.to_string_lossy();
+        let rerolled = reroll(&log_file, 3, Some(0), &server_options(&server), true);
+        assert!(error_message(rerolled).contains("reroll the whole sweep"));
+        reroll(&log_file, 3, None, &server_options(&server), true).unwrap();
+        let rerolled = read_log(&results.log_file);
+        assert_eq!(rerolled.images[0].seed, after.images[0].seed);
+        assert_eq!(rerolled.images[1].seed, after.images[1].seed);
+        assert_ne!(rerolled.images[2].seed, after.images[2].seed);
+        assert_eq!(rerolled.images[2].seed, rerolled.images[3].seed);
+    }
+
+    #[test]
+    fn invalid_sampler_lists_the_samplers() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.base_prompt.sampler = "Euler z".to_string();
+        let message = error_message(run(&template, &mid_run_options(&server)));
+        assert!(message.contains("Sampler \"Euler z\" not found"));
+        assert!(message.contains("DPM++ 2M Karras"));
+    }
+
+    #[test]
+    fn invalid_model_lists_the_models() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.base_prompt.model = "missing.safetensors".to_string();
+        let message = error_message(run(&template, &mid_run_options(&server)));
+        assert!(message.contains("Model \"missing.safetensors\" not found"));
+        assert!(message.contains("base.safetensors [abc123]"));
+    }
+
+    #[test]
+    fn invalid_upscaler_lists_the_upscalers() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.base_prompt.hires = Some(HiResSettings {
+            upscaler: "Lanczos 9x".to_string(),
+            upscale_by: 2.0,
+            denoising_strength: 0.5,
+            steps: 10,
+        });
+        let message = error_message(run(&template, &mid_run_options(&server)));
+        assert!(message.contains("Upscaler \"Lanczos 9x\" not found"));
+        assert!(message.contains("R-ESRGAN 4x+"));
+    }
+
+    #[test]
+    fn preflight_reports_every_problem_before_generating() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.base_prompt.model = "othr.safetensors".to_string();
+        template.base_prompt.sampler = "Euler z".to_string();
+        template.base_prompt.positive = "masterpiece, <lora:add_detial:0.5>".to_string();
+        template.base_prompt.hires = Some(HiResSettings {
+            upscaler: "Latent".to_string(),
+            upscale_by: 2.0,
+            denoising_strength: 0.5,
+            steps: 10,
+        });
+        template.base_prompt.width = 500;
+
+        let message = error_message(run(&template, &run_options(&server)));
+        assert!(message.contains("4 problems"));
+        assert!(message.contains("base_prompt.width"));
+        assert!(message.contains("did you mean \"other.safetensors [def456]\"?"));
+        assert!(message.contains("did you mean \"Euler a\"?"));
+        assert!(message.contains("did you mean \"add_detail\"?"));
+        assert!(!message.contains("upscaler"));
+        assert!(server.posted("/sdapi/v1/txt2img").is_empty());
+    }
+
+    #[test]
+    fn loras_and_embeddings_are_added_to_the_prompt() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.count = Some(1);
+        template.base_prompt.loras = Some(vec![LoraEntry {
+            name: "add_detail".to_string(),
+            weight: None,
+            weight_range: Some([0.2, 0.4]),
+        }]);
+        template.base_prompt.negative_embeddings = Some(vec!["easynegative".to_string()]);
+        template.modifiers = Some(vec![PromptModifer {
+            loras: Some(vec![LoraEntry {
+                name: "film_grain".to_string(),
+                weight: Some(0.5),
+                weight_range: None,
+            }]),
+            ..Default::default()
+        }]);
+        let (results, _dir) = run(&template, &run_options(&server)).unwrap();
+
+        let log = read_log(&results.log_file);
+        let loras = log.images[0].loras.as_ref().unwrap();
+        let weight = loras[0].weight.unwrap();
+        assert!((0.2..=0.4).contains(&weight));
+        assert_eq!(loras[0].weight_range, None);
+        let request = &server.posted("/sdapi/v1/txt2img")[0];
+        assert_eq!(
+            request["prompt"],
+            format!(
+                "{}, <lora:add_detail:{}>, <lora:film_grain:0.5>",
+                log.images[0].positive, weight
+            )
+        );
+        assert_eq!(request["negative_prompt"], "easynegative");
+
+        template.base_prompt.loras.as_mut().unwrap()[0].name = "add_detial".to_string();
+        template.base_prompt.embeddings = Some(vec!["easynegativ".to_string()]);
+        let message = error_message(run(&template, &run_options(&server)));
+        assert!(message.contains("base_prompt.loras[0]: LoRA \"add_detial\" not found"));
+        assert!(message.contains("did you mean \"easynegative\"?"));
+    }
+
+    #[test]
+    fn controlnet_units_cycle_through_the_control_images() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.base_prompt.controlnet = Some(vec![ControlNetUnit {
+            module: Some("openpose".to_string()),
+            model: "control_v11p_sd15_openpose".to_string(),
+            image: "poses".to_string(),
+            ..Default::default()
+        }]);
+        let (output_dir, template_file) = write_template(&template);
+        let poses = template_file.parent().unwrap().join("poses");
+        fs::create_dir_all(&poses).unwrap();
+        fs::write(poses.join("a.png"), [1, 2, 3]).unwrap();
+        fs::write(poses.join("b.png"), [4, 5, 6]).unwrap();
+        let options = RunOptions {
+            sequential: true,
+            ..run_options(&server)
+        };
+        let results = do_run(
+            &template_file.to_string_lossy(),
+            &output_dir.to_string_lossy(),
+            &options,
+        )
+        .unwrap();
+
+        let log = read_log(&results.log_file);
+        let unit = &log.images[1].controlnet.as_ref().unwrap()[0];
+        assert!(unit.image.ends_with("b.png"));
+        let mut control_images: Vec<_> = server
+            .posted("/sdapi/v1/txt2img")
+            .iter()
+            .map(|request| request["alwayson_scripts"]["controlnet"]["args"][0]["image"].clone())
+            .collect();
+        control_images.sort_by_key(|image| image.to_string());
+        assert_eq!(control_images, vec!["AQID", "AQID", "BAUG"]);
+    }
+
+    #[test]
+    fn sweep_cells_share_their_control_image() {
+        let server = MockServer::start();
+        let mut template = template();
+        template.count = Some(2);
+        template.base_prompt.controlnet = Some(vec![ControlNetUnit {
+            model: "control_v11p_sd15_openpose".to_string(),
+            image: "poses".to_string(),
+            ..Default::default()
+        }]);
+        template.sweep = Some(
+            serde_json::from_value(json!({
+                "x": { "field": "cfg", "values": [5, 7.5] },
+            }))
+            .unwrap(),
+        );
+        let (output_dir, template_file) = write_template(&template);
+        let poses = template_file.parent().unwrap().join("poses");
+        fs::create_dir_all(&poses).unwrap();
+        fs::write(poses.join("a.png"), [1, 2, 3]).unwrap();
+        fs::write(poses.join("b.png"), [4, 5, 6]).unwrap();
+        let options = RunOptions {
+            sequential: true,
+            dry_run: true,
+            ..run_options(&server)
+        };
+        let results = do_run(
+            &template_file.to_string_lossy(),
+            &output_dir.to_string_lossy(),
+            &options,
+        )
+        .unwrap();
+
+        let log = read_log(&results.log_file);
+        let control_images: Vec<_> = log
+            .images
+            .iter()
+            .map(|prompt| {
+                let image = &prompt.controlnet.as_ref().unwrap()[0].image;
+                Path::new(image).file_name().unwrap().to_owned()
+            })
+            .collect();
+        assert_eq!(control_images, ["a.png", "a.png", "b.png", "b.png"]);
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let server = MockServer::start();
+        server.fail_next("/sdapi/v1/txt2img", 503, "loading");
+        let (results, _dir) = run(&template(), &run_options(&server)).unwrap();
+        assert_eq!(results.images_created, 3);
+    }
+
+    #[test]
+    fn failed_images_are_recorded_and_resumed() {
+        let server = MockServer::start();
+        let mut options = run_options(&server);
+        options.continue_on_error = true;
+        options.server.retry.max_attempts = 1;
+        server.fail_next("/sdapi/v1/txt2img", 500, "out of memory");
+        let (results, output_dir) = run(&template(), &options).unwrap();
+
+        assert_eq!(results.images_created, 2);
+        assert_eq!(read_log(&results.log_file).failed, vec![0]);
+        assert!(!image_path(&output_dir, 0).exists());
+
+        let created = resume(
+            &results.log_file.to_string_lossy(),
+            &options.server,
+            true,
+            true,
+        )
+        .unwrap();
+        assert_eq!(created, 1);
+        assert!(read_log(&results.log_file).failed.is_empty());
+        assert!(image_path(&output_dir, 0).exists());
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::VecDeque;
+use std::sync::{mpsc, Condvar, Mutex};
+
+use super::backend::Backend;
+use super::metadata::ImageDestination;
+use super::progress::{BatchProgress, ProgressMode};
+use super::{BatchError, BatchTemplate, PromptData};
+
+/// An image to generate for the log entry at `index`
+pub struct Job {
+    pub index: usize,
+    /// Only regenerate this image out of the entry's batch
+    pub batch_index: Option<usize>,
+    /// Seed for the batch image, a new random seed if not set
+    pub seed: Option<i64>,
+    pub prompt: PromptData,
+}
+
+impl Job {
+    /// Generate the whole log entry at `index` with the prompt's seed
+    pub fn new(index: usize, prompt: PromptData) -> Job {
+        Job {
+            index,
+            batch_index: None,
+            seed: None,
+            prompt,
+        }
+    }
+
+    /// Record the generated outputs in the log entry the job was for
+    ///
+    /// Only the batch image is copied for batch image jobs, other jobs for the same entry may have finished first
+    pub fn record(self, images: &mut [PromptData]) {
+        match self.batch_index {
+            None => images[self.index] = self.prompt,
+            Some(batch_index) => {
+                let generated = self
+                    .prompt
+                    .outputs
+                    .and_then(|outputs| outputs.into_iter().nth(batch_index));
+                if let (Some(outputs), Some(generated)) =
+                    (images[self.index].outputs.as_mut(), generated)
+                {
+                    outputs[batch_index] = generated;
+                }
+            }
+        }
+    }
+}
+
+/// A server the pool can generate with
+pub struct Server {
+    pub url: String,
+    pub api: Box<dyn Backend>,
+}
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    /// Jobs taken by a worker that haven't finished yet, and might be put back in the queue
+    in_flight: usize,
+    /// Set once a job fails, so no more jobs are started
+    failed: bool,
+}
+
+/// What came of the jobs given to the pool
+pub struct PoolResults {
+    /// Number of jobs generated
+    pub generated: usize,
+    /// Log indices of the jobs that failed, only filled when continuing on errors
+    pub failed: Vec<usize>,
+}
+
+/// Generate the jobs spread across the servers, each server taking the next job as soon as it's free
+///
+/// Images are saved to the `NN.png` of their job's index, whichever server generates them.
+/// A server that can't be reached is dropped from the pool and its job is put back for the others.
+/// A job that fails stops the pool, unless `continue_on_error` is set, then it's reported and skipped.
+/// `on_finished` is called on this thread with each job once its prompt has been updated with the outputs.
+pub fn generate(
+    servers: &[Server],
+    destination: &ImageDestination,
+    jobs: Vec<Job>,
+    progress_mode: ProgressMode,
+    continue_on_error: bool,
+    mut on_finished: impl FnMut(Job) -> anyhow::Result<()>,
+) -> anyhow::Result<PoolResults> {
+    let total = jobs.len();
+    let state = Mutex::new(PoolState {
+        queue: VecDeque::from(jobs),
+        in_flight: 0,
+        failed: false,
+    });
+    let job_changed = Condvar::new();
+    // The live progress bar can only be drawn for a single server
+    let progress_mode = match servers.len() {
+        1 => progress_mode,
+        _ => ProgressMode::Plain,
+    };
+
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel::<(Job, anyhow::Result<()>)>();
+        for server in servers {
+            let sender = sender.clone();
+            let (state, job_changed) = (&state, &job_changed);
+            scope.spawn(move || {
+                let mut progress = BatchProgress::new(progress_mode, total);
+                while let Some(mut job) = take_job(state, job_changed) {
+                    progress.start_image();
+                    match job.batch_index {
+                        None => println!("Generating image {} on {}...", job.index, server.url),
+                        Some(batch_index) => println!(
+                            "Generating image {} of batch {} on {}...",
+                            batch_index, job.index, server.url
+                        ),
+                    }
+                    let result = generate_job(server.api.as_ref(), destination, &mut job, &progress);
+
+                    let mut state = state.lock().expect("pool state lock");
+                    state.in_flight -= 1;
+                    match result {
+                        Ok(()) => {
+                            let _ = sender.send((job, Ok(())));
+                        }
+                        Err(e) if is_server_down(&e) => {
+                            println!(
+                                "Server {} can't be reached, leaving its images to the other servers: {}",
+                                server.url, e
+                            );
+                            state.queue.push_front(job);
+                            job_changed.notify_all();
+                            return;
+                        }
+                        Err(e) => {
+                            // Never clear a failure another job already stopped the pool with
+                            state.failed |= !continue_on_error;
+                            let _ = sender.send((job, Err(e)));
+                        }
+                    }
+                    job_changed.notify_all();
+                }
+            });
+        }
+        // Only the workers' senders are left, so the channel closes once they've all stopped
+        drop(sender);
+
+        let mut generated = 0;
+        let mut failed = vec![];
+        let mut first_error = None;
+        for (job, result) in receiver {
+            let finished = match result {
+                Ok(()) => {
+                    generated += 1;
+                    on_finished(job)
+                }
+                Err(e) if continue_on_error => {
+                    println!("Image {} failed, continuing: {}", job.index, e);
+                    failed.push(job.index);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = finished {
+                state.lock().expect("pool state lock").failed = true;
+                job_changed.notify_all();
+                first_error.get_or_insert(e);
+            }
+        }
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+
+        let left = state.lock().expect("pool state lock").queue.len();
+        if left > 0 {
+            return Err(BatchError {
+                message: format!(
+                    "none of the servers can be reached, {} images were not generated, use Resume to finish them",
+                    left
+                ),
+            }
+            .into());
+        }
+        // Several images of one batch can fail, and jobs finish in any order
+        failed.sort_unstable();
+        failed.dedup();
+        Ok(PoolResults { generated, failed })
+    })
+}
+
+/// Wait for the next job, or None once there are no more jobs to take
+fn take_job(state: &Mutex<PoolState>, job_changed: &Condvar) -> Option<Job> {
+    let mut state = state.lock().expect("pool state lock");
+    loop {
+        if state.failed {
+            return None;
+        }
+        if let Some(job) = state.queue.pop_front() {
+            state.in_flight += 1;
+            return Some(job);
+        }
+        // A job in flight on another server could still be put back if its server goes down
+        if state.in_flight == 0 {
+            return None;
+        }
+        state = job_changed.wait(state).expect("pool state lock");
+    }
+}
+
+fn generate_job(
+    api: &dyn Backend,
+    destination: &ImageDestination,
+    job: &mut Job,
+    progress: &BatchProgress,
+) -> anyhow::Result<()> {
+    match job.batch_index {
+        None => {
+            BatchTemplate::generate_image(destination, api, &mut job.prompt, job.index, progress)
+        }
+        Some(batch_index) => BatchTemplate::generate_batch_image(
+            destination,
+            api,
+            &mut job.prompt,
+            job.index,
+            batch_index,
+            job.seed,
+            progress,
+        ),
+    }
+}
+
+/// Whether the error means the server couldn't be reached, rather than it failing to generate the image
+///
+/// A timeout isn't the server being down, it may still be busy with the image
+fn is_server_down(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_connect)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use std::io::Cursor;
+
+    use super::super::backend::{Inventory, InventoryItem, Progress};
+    use super::super::testing::test_dir;
+    use super::super::Txt2ImgInfo;
+    use super::*;
+
+    /// Returns a blank image for every prompt, or fails to connect if it's down
+    ///
+    /// Positive prompts of "fail" fail to generate even when it's up
+    struct FakeBackend {
+        down: bool,
+    }
+
+    impl Backend for FakeBackend {
+        fn generate(&self, prompt: &PromptData) -> anyhow::Result<(Vec<Vec<u8>>, Txt2ImgInfo)> {
+            if self.down {
+                // Nothing listens on port 1, so this is refused like a server that's gone
+                reqwest::blocking::get("http://127.0.0.1:1")?;
+            }
+            if prompt.positive == "fail" {
+                return Err(BatchError {
+                    message: "out of memory".to_string(),
+                }
+                .into());
+            }
+            let mut png = vec![];
+            image::RgbImage::new(8, 8)
+                .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)?;
+            let info = Txt2ImgInfo {
+                all_seeds: vec![prompt.seed.unwrap_or(-1)],
+                all_subseeds: vec![],
+                infotexts: vec![],
+                index_of_first_image: 0,
+            };
+            Ok((vec![png], info))
+        }
+
+        fn progress(&self) -> anyhow::Result<Option<Progress>> {
+            Ok(None)
+        }
+
+        fn inventory(&self, _kind: Inventory) -> anyhow::Result<Vec<InventoryItem>> {
+            Ok(vec![])
+        }
+
+        fn set_model(&self, _model: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn server(url: &str, down: bool) -> Server {
+        Server {
+            url: url.to_string(),
+            api: Box::new(FakeBackend { down }),
+        }
+    }
+
+    fn destination(output_dir: &Path) -> ImageDestination {
+        ImageDestination {
+            log_file: output_dir.join("log.json"),
+            output_dir: output_dir.to_path_buf(),
+            template: "test".to_string(),
+        }
+    }
+
+    fn jobs(count: usize) -> Vec<Job> {
+        (0..count)
+            .map(|index| {
+                let prompt = PromptData {
+                    seed: Some(index as i64 * 10),
+                    ..Default::default()
+                };
+                Job::new(index, prompt)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn jobs_move_to_servers_that_are_up() {
+        let dir = test_dir();
+        let destination = destination(&dir);
+        let servers = [server("down", true), server("up", false)];
+        let mut images = vec![PromptData::default(); 4];
+
+        let results = generate(
+            &servers,
+            &destination,
+            jobs(4),
+            ProgressMode::Plain,
+            false,
+            |job| {
+                job.record(&mut images);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(results.generated, 4);
+        for (index, image) in images.iter().enumerate() {
+            assert_eq!(image.seed, Some(index as i64 * 10));
+            assert!(destination
+                .output_dir
+                .join(format!("{:02}.png", index))
+                .exists());
+        }
+    }
+
+    #[test]
+    fn all_servers_down_is_an_error() {
+        let dir = test_dir();
+        let destination = destination(&dir);
+        let servers = [server("down", true), server("also down", true)];
+        let result = generate(
+            &servers,
+            &destination,
+            jobs(2),
+            ProgressMode::Plain,
+            false,
+            |_| panic!("no job should finish"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn failed_jobs_are_skipped_when_continuing() {
+        let dir = test_dir();
+        let destination = destination(&dir);
+        let servers = [server("up", false)];
+        let mut jobs = jobs(4);
+        for index in [1, 2] {
+            jobs[index].prompt.positive = "fail".to_string();
+        }
+
+        let mut finished = vec![];
+        let results = generate(
+            &servers,
+            &destination,
+            jobs,
+            ProgressMode::Plain,
+            true,
+            |job| {
+                finished.push(job.index);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(results.generated, 2);
+        assert_eq!(results.failed, vec![1, 2]);
+        assert_eq!(finished, vec![0, 3]);
+    }
+
+    #[test]
+    fn failed_job_stops_the_pool() {
+        let dir = test_dir();
+        let destination = destination(&dir);
+        let mut jobs = jobs(2);
+        jobs[0].prompt.positive = "fail".to_string();
+        let result = generate(
+            &[server("up", false)],
+            &destination,
+            jobs,
+            ProgressMode::Plain,
+            false,
+            |_| panic!("no job should finish"),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn timeouts_are_not_server_down() {
+        // Accepts connections but never answers them
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = reqwest::blocking::Client::builder()
+            .timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let timeout = client
+            .get(format!("http://{}", listener.local_addr().unwrap()))
+            .send()
+            .unwrap_err();
+        assert!(!is_server_down(&timeout.into()));
+
+        let refused = reqwest::blocking::get("http://127.0.0.1:1").unwrap_err();
+        assert!(is_server_down(&refused.into()));
+    }
+}
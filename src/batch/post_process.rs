@@ -0,0 +1,249 @@
+use std::io::Cursor;
+
+use image::codecs::webp::{WebPEncoder, WebPQuality};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, Rgba, RgbaImage};
+
+use super::backend::Backend;
+use super::metadata;
+use super::{BatchError, OutputFormat, PostProcesses};
+
+const DEFAULT_QUALITY: u8 = 90;
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+        }
+    }
+}
+
+/// Run the steps on the image in order, returning it with a description of each step that changed it
+///
+/// Converting and keeping the original happen when the image is saved, see [output_format].
+/// Upscale steps are sent to `api`, and fail without one.
+pub fn apply(
+    mut image: DynamicImage,
+    steps: &[PostProcesses],
+    api: Option<&dyn Backend>,
+) -> anyhow::Result<(DynamicImage, Vec<String>)> {
+    let mut done = vec![];
+    for step in steps {
+        let (width, height) = image.dimensions();
+        image = match step {
+            PostProcesses::Resize { scale_by } => {
+                let new_w = (width as f32 * scale_by) as u32;
+                let new_h = (height as f32 * scale_by) as u32;
+                done.push(format!("resizing to {}x{}", new_w, new_h));
+                image.resize_exact(new_w, new_h, FilterType::Lanczos3)
+            }
+            PostProcesses::ResizeExact { width, height } => {
+                done.push(format!("resizing to {}x{}", width, height));
+                image.resize_exact(*width, *height, FilterType::Lanczos3)
+            }
+            PostProcesses::ResizeFit { width, height } => {
+                let resized = image.resize(*width, *height, FilterType::Lanczos3);
+                done.push(format!(
+                    "resizing to {}x{}",
+                    resized.width(),
+                    resized.height()
+                ));
+                resized
+            }
+            PostProcesses::Crop {
+                width: crop_w,
+                height: crop_h,
+                focus,
+            } => {
+                let crop_w = (*crop_w).min(width);
+                let crop_h = (*crop_h).min(height);
+                let [focus_x, focus_y] = focus.unwrap_or([0.5, 0.5]);
+                let x = crop_start(width, crop_w, focus_x);
+                let y = crop_start(height, crop_h, focus_y);
+                done.push(format!("cropping to {}x{}", crop_w, crop_h));
+                image.crop_imm(x, y, crop_w, crop_h)
+            }
+            PostProcesses::Pad {
+                aspect_ratio,
+                color,
+            } => {
+                let ratio = parse_aspect_ratio(aspect_ratio).ok_or_else(|| BatchError {
+                    message: format!("\"{}\" isn't an aspect ratio like \"16:9\"", aspect_ratio),
+                })?;
+                let (new_w, new_h) = if (width as f32 / height as f32) < ratio {
+                    ((height as f32 * ratio).round() as u32, height)
+                } else {
+                    (width, (width as f32 / ratio).round() as u32)
+                };
+                let [r, g, b] = color.unwrap_or([0, 0, 0]);
+                let mut padded = RgbaImage::from_pixel(new_w, new_h, Rgba([r, g, b, 255]));
+                imageops::overlay(
+                    &mut padded,
+                    &image,
+                    ((new_w - width) / 2) as i64,
+                    ((new_h - height) / 2) as i64,
+                );
+                done.push(format!("padding to {}x{}", new_w, new_h));
+                padded.into()
+            }
+            PostProcesses::Sharpen { sigma, threshold } => {
+                done.push("sharpening".to_string());
+                image.unsharpen(sigma.unwrap_or(1.0), threshold.unwrap_or(0))
+            }
+            PostProcesses::Adjust {
+                brightness,
+                contrast,
+                hue,
+            } => {
+                done.push("adjusting colors".to_string());
+                if let Some(brightness) = brightness {
+                    image = image.brighten(*brightness);
+                }
+                if let Some(contrast) = contrast {
+                    image = image.adjust_contrast(*contrast);
+                }
+                if let Some(hue) = hue {
+                    image = image.huerotate(*hue);
+                }
+                image
+            }
+            PostProcesses::Upscale(settings) => {
+                let api = api.ok_or_else(|| BatchError {
+                    message: "upscaling needs a server".to_string(),
+                })?;
+                let upscaled = api.upscale(&metadata::encode_png(&image)?, settings)?;
+                done.push(format!("upscaling with {}", settings.upscaler));
+                image::load_from_memory(&upscaled)?
+            }
+            PostProcesses::Convert { .. } | PostProcesses::KeepOriginal => image,
+        };
+    }
+    Ok((image, done))
+}
+
+/// Offset of a `crop_size` long crop out of `size` that keeps `focus` in its middle, without leaving the image
+fn crop_start(size: u32, crop_size: u32, focus: f32) -> u32 {
+    let start = size as f32 * focus.clamp(0.0, 1.0) - crop_size as f32 / 2.0;
+    (start.max(0.0) as u32).min(size - crop_size)
+}
+
+/// Parse an aspect ratio like "16:9", or a single number like "1.5"
+pub fn parse_aspect_ratio(text: &str) -> Option<f32> {
+    let ratio = match text.split_once(':') {
+        Some((width, height)) => {
+            width.trim().parse::<f32>().ok()? / height.trim().parse::<f32>().ok()?
+        }
+        None => text.trim().parse().ok()?,
+    };
+    (ratio.is_finite() && ratio > 0.0).then_some(ratio)
+}
+
+/// Format and quality to save the image in, from the last Convert step
+pub fn output_format(steps: &[PostProcesses]) -> (OutputFormat, u8) {
+    steps
+        .iter()
+        .rev()
+        .find_map(|step| match step {
+            PostProcesses::Convert { format, quality } => {
+                Some((*format, quality.unwrap_or(DEFAULT_QUALITY)))
+            }
+            _ => None,
+        })
+        .unwrap_or((OutputFormat::Png, DEFAULT_QUALITY))
+}
+
+pub fn encode(image: &DynamicImage, format: OutputFormat, quality: u8) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    match format {
+        OutputFormat::Png => bytes = metadata::encode_png(image)?,
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_to(
+                &mut Cursor::new(&mut bytes),
+                ImageOutputFormat::Jpeg(quality),
+            )?;
+        }
+        OutputFormat::WebP => {
+            let image = match image.color().has_alpha() {
+                true => DynamicImage::ImageRgba8(image.to_rgba8()),
+                false => DynamicImage::ImageRgb8(image.to_rgb8()),
+            };
+            WebPEncoder::new_with_quality(&mut bytes, WebPQuality::lossy(quality)).encode(
+                image.as_bytes(),
+                image.width(),
+                image.height(),
+                image.color(),
+            )?;
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    fn image(width: u32, height: u32) -> DynamicImage {
+        RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 0])).into()
+    }
+
+    #[test]
+    fn steps_run_in_order() {
+        let steps = [
+            PostProcesses::ResizeFit {
+                width: 100,
+                height: 100,
+            },
+            PostProcesses::Pad {
+                aspect_ratio: "2:1".to_string(),
+                color: None,
+            },
+            PostProcesses::KeepOriginal,
+        ];
+        let (processed, done) = apply(image(64, 128), &steps, None).unwrap();
+        assert_eq!(processed.dimensions(), (200, 100));
+        assert_eq!(done, vec!["resizing to 50x100", "padding to 200x100"]);
+        // The image is centered between the borders
+        assert_eq!(processed.get_pixel(74, 50), Rgba([0, 0, 0, 255]));
+        assert_ne!(processed.get_pixel(75, 50), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn crop_keeps_the_focus_inside_the_image() {
+        let crop = |focus| PostProcesses::Crop {
+            width: 32,
+            height: 32,
+            focus,
+        };
+        let (centered, _) = apply(image(128, 64), &[crop(None)], None).unwrap();
+        assert_eq!(centered.get_pixel(0, 0).0[..2], [48, 16]);
+        let (corner, _) = apply(image(128, 64), &[crop(Some([1.0, 0.0]))], None).unwrap();
+        assert_eq!(corner.get_pixel(0, 0).0[..2], [96, 0]);
+    }
+
+    #[test]
+    fn converts_to_the_last_format() {
+        let steps = [
+            PostProcesses::Convert {
+                format: OutputFormat::WebP,
+                quality: None,
+            },
+            PostProcesses::Convert {
+                format: OutputFormat::Jpeg,
+                quality: Some(80),
+            },
+        ];
+        let (format, quality) = output_format(&steps);
+        assert_eq!((format, quality), (OutputFormat::Jpeg, 80));
+        for format in [OutputFormat::Jpeg, OutputFormat::WebP] {
+            let bytes = encode(&image(16, 16), format, quality).unwrap();
+            assert_eq!(image::load_from_memory(&bytes).unwrap().width(), 16);
+        }
+        assert_eq!(parse_aspect_ratio("16:9"), Some(16.0 / 9.0));
+        assert_eq!(parse_aspect_ratio("1:0"), None);
+    }
+}
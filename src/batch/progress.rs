@@ -0,0 +1,151 @@
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crate::util;
+
+use super::backend::Progress;
+
+const BAR_WIDTH: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// Live progress bar, redrawn in place while each image generates
+    Bar,
+    /// Only the per-image status lines, for when stdout isn't a terminal or the bar is turned off
+    Plain,
+}
+
+impl ProgressMode {
+    /// Use the progress bar when stdout is a terminal, falling back to plain output otherwise
+    pub fn detect(quiet: bool) -> ProgressMode {
+        if !quiet && std::io::stdout().is_terminal() {
+            ProgressMode::Bar
+        } else {
+            ProgressMode::Plain
+        }
+    }
+}
+
+/// Tracks and reports progress through a batch of images
+pub struct BatchProgress {
+    mode: ProgressMode,
+    start: Instant,
+    total: usize,
+    images_started: usize,
+}
+
+impl BatchProgress {
+    pub fn new(mode: ProgressMode, total: usize) -> BatchProgress {
+        BatchProgress {
+            mode,
+            start: Instant::now(),
+            total,
+            images_started: 0,
+        }
+    }
+
+    /// Whether the server's progress endpoint should be polled during generation
+    pub fn wants_updates(&self) -> bool {
+        self.mode == ProgressMode::Bar
+    }
+
+    /// Count the start of the next image in the batch
+    pub fn start_image(&mut self) {
+        self.images_started += 1;
+    }
+
+    /// Redraw the progress bar for the image currently generating
+    pub fn update(&self, progress: &Progress) {
+        if self.mode != ProgressMode::Bar {
+            return;
+        }
+        let line = format!(
+            "{} | image {} of {} | elapsed {}{}",
+            render_step_bar(progress),
+            self.images_started,
+            self.total,
+            util::print_elapsed(&self.start.elapsed()),
+            self.batch_eta()
+                .map(|eta| format!(" | batch ETA {}", util::print_elapsed(&eta)))
+                .unwrap_or_default()
+        );
+        let mut stdout = std::io::stdout();
+        // \x1b[K clears whatever was left over from a longer previous line
+        let _ = write!(stdout, "\r{}\x1b[K", line);
+        let _ = stdout.flush();
+    }
+
+    /// Report that the image currently generating has been received from the server
+    pub fn finish_image(&self) {
+        if self.mode == ProgressMode::Bar {
+            let _ = write!(std::io::stdout(), "\r\x1b[K");
+            let _ = std::io::stdout().flush();
+        }
+    }
+
+    /// Estimate time remaining for the batch from the average time of the images completed so far
+    fn batch_eta(&self) -> Option<Duration> {
+        let completed = self.images_started.checked_sub(1)?;
+        if completed == 0 {
+            return None;
+        }
+        let per_image = self.start.elapsed() / completed as u32;
+        Some(per_image * (self.total - completed) as u32)
+    }
+}
+
+fn render_step_bar(progress: &Progress) -> String {
+    let fraction = progress.progress.clamp(0.0, 1.0);
+    let filled = (fraction * BAR_WIDTH as f32).round() as usize;
+    format!(
+        "[{}{}] {}/{} steps, ETA {}",
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        progress.state.sampling_step,
+        progress.state.sampling_steps,
+        util::print_elapsed(
+            &Duration::try_from_secs_f32(progress.eta_relative.max(0.0)).unwrap_or_default()
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::backend::ProgressState;
+    use super::*;
+
+    #[test]
+    fn step_bar_half_done() {
+        let progress = Progress {
+            progress: 0.5,
+            eta_relative: 3.25,
+            state: ProgressState {
+                sampling_step: 10,
+                sampling_steps: 20,
+            },
+        };
+        assert_eq!(
+            render_step_bar(&progress),
+            "[##########----------] 10/20 steps, ETA 3.250s"
+        );
+    }
+
+    #[test]
+    fn step_bar_clamps_progress() {
+        let progress = Progress {
+            progress: 1.5,
+            eta_relative: -1.0,
+            state: Default::default(),
+        };
+        assert_eq!(
+            render_step_bar(&progress),
+            "[####################] 0/0 steps, ETA 0.0s"
+        );
+        let progress = Progress {
+            progress: 0.0,
+            eta_relative: f32::INFINITY,
+            state: Default::default(),
+        };
+        assert!(render_step_bar(&progress).ends_with("ETA 0.0s"));
+    }
+}
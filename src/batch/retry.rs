@@ -0,0 +1,267 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::util;
+
+use super::backend::{Backend, Inventory, InventoryItem, Progress, StatusError};
+use super::{PromptData, Txt2ImgInfo, UpscaleSettings};
+
+/// Longest to wait between two attempts, however many attempts have failed
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A kind of failed request that is worth trying again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryOn {
+    /// The request took longer than the timeout
+    ///
+    /// Not retried by default, the server may still be generating the image for the first request
+    Timeout,
+    /// The server couldn't be connected to, like while it's restarting
+    Connect,
+    /// The server responded with this HTTP status code
+    Status(u16),
+}
+
+impl FromStr for RetryOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "timeout" => Ok(RetryOn::Timeout),
+            "connect" => Ok(RetryOn::Connect),
+            status => status.parse().map(RetryOn::Status).map_err(|_| {
+                format!(
+                    "\"{}\" isn't timeout, connect or an HTTP status code",
+                    status
+                )
+            }),
+        }
+    }
+}
+
+impl fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryOn::Timeout => write!(f, "timeout"),
+            RetryOn::Connect => write!(f, "connect"),
+            RetryOn::Status(status) => write!(f, "{}", status),
+        }
+    }
+}
+
+fn default_retry_on() -> Vec<RetryOn> {
+    vec![
+        RetryOn::Connect,
+        RetryOn::Status(500),
+        RetryOn::Status(502),
+        RetryOn::Status(503),
+        RetryOn::Status(504),
+    ]
+}
+
+/// How to retry requests to the server that fail for reasons that might go away
+#[derive(clap::Args, Clone, Debug)]
+pub struct RetryPolicy {
+    /// How many times to try each request before giving up
+    #[arg(long, default_value_t = 3)]
+    pub max_attempts: u32,
+    /// Seconds to wait before the first retry, doubling after each failed attempt
+    #[arg(long, default_value_t = 2.0)]
+    pub retry_backoff: f32,
+    /// Failures to retry, separated by commas: timeout, connect, or HTTP status codes
+    ///
+    /// Retrying timeouts can queue the same image on the server twice
+    #[arg(long, value_delimiter = ',', default_values_t = default_retry_on())]
+    pub retry_on: Vec<RetryOn>,
+    /// Seconds to wait for each request to the server
+    #[arg(long, default_value_t = 180)]
+    pub timeout: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            retry_backoff: 2.0,
+            retry_on: default_retry_on(),
+            timeout: 180,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// Run `request` until it succeeds, fails for a reason that isn't retried, or runs out of attempts
+    pub fn run<T>(&self, mut request: impl FnMut() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        let mut backoff = self.first_backoff();
+        let mut attempt = 1;
+        loop {
+            match request() {
+                Err(e) if attempt < self.max_attempts && self.is_retryable(&e) => {
+                    attempt += 1;
+                    println!(
+                        "Request failed, retrying in {} (attempt {} of {}): {}",
+                        util::print_elapsed(&backoff),
+                        attempt,
+                        self.max_attempts,
+                        e
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Wait before the first retry, at most the longest backoff, even for backoffs too long for a Duration
+    fn first_backoff(&self) -> Duration {
+        Duration::try_from_secs_f32(self.retry_backoff.max(0.0))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+    }
+
+    fn is_retryable(&self, error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return (e.is_timeout() && self.retry_on.contains(&RetryOn::Timeout))
+                    || (e.is_connect() && self.retry_on.contains(&RetryOn::Connect))
+                    || e.status()
+                        .is_some_and(|status| self.retries_status(status.as_u16()));
+            }
+            cause
+                .downcast_ref::<StatusError>()
+                .is_some_and(|e| self.retries_status(e.status))
+        })
+    }
+
+    fn retries_status(&self, status: u16) -> bool {
+        self.retry_on.contains(&RetryOn::Status(status))
+    }
+}
+
+/// Wraps a backend, retrying its requests according to the policy
+pub struct Retrying {
+    pub inner: Box<dyn Backend>,
+    pub policy: RetryPolicy,
+}
+
+impl Backend for Retrying {
+    fn generate(&self, prompt: &PromptData) -> anyhow::Result<(Vec<Vec<u8>>, Txt2ImgInfo)> {
+        self.policy.run(|| self.inner.generate(prompt))
+    }
+
+    fn progress(&self) -> anyhow::Result<Option<Progress>> {
+        // Progress is polled often enough already
+        self.inner.progress()
+    }
+
+    fn inventory(&self, kind: Inventory) -> anyhow::Result<Vec<InventoryItem>> {
+        self.policy.run(|| self.inner.inventory(kind))
+    }
+
+    fn set_model(&self, model: &str) -> anyhow::Result<()> {
+        self.policy.run(|| self.inner.set_model(model))
+    }
+
+    fn upscale(&self, image: &[u8], settings: &UpscaleSettings) -> anyhow::Result<Vec<u8>> {
+        self.policy.run(|| self.inner.upscale(image, settings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BatchError;
+    use super::*;
+
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy {
+            retry_backoff: 0.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_retry_conditions() {
+        assert_eq!("timeout".parse(), Ok(RetryOn::Timeout));
+        assert_eq!(" 503".parse(), Ok(RetryOn::Status(503)));
+        assert!("sometimes".parse::<RetryOn>().is_err());
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let backoff = |retry_backoff| {
+            RetryPolicy {
+                retry_backoff,
+                ..Default::default()
+            }
+            .first_backoff()
+        };
+        assert_eq!(backoff(2.0), Duration::from_secs(2));
+        assert_eq!(backoff(-1.0), Duration::ZERO);
+        assert_eq!(backoff(1e12), MAX_BACKOFF);
+        assert_eq!(backoff(f32::INFINITY), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn retries_status_errors_until_success() {
+        let mut attempts = 0;
+        let result = quick_policy().run(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(StatusError {
+                    status: 503,
+                    message: "loading".to_string(),
+                }
+                .into())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = quick_policy().run(|| {
+            attempts += 1;
+            Err(StatusError {
+                status: 500,
+                message: "out of memory".to_string(),
+            }
+            .into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let mut attempts = 0;
+        let policy = RetryPolicy {
+            retry_on: vec![RetryOn::Status(503)],
+            ..quick_policy()
+        };
+        let result: anyhow::Result<()> = policy.run(|| {
+            attempts += 1;
+            match attempts {
+                1 => Err(StatusError {
+                    status: 500,
+                    message: "out of memory".to_string(),
+                }
+                .into()),
+                _ => Err(BatchError {
+                    message: "sampler not found".to_string(),
+                }
+                .into()),
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,92 @@
+use std::io::Write;
+use std::path::Path;
+
+use super::PromptData;
+
+/// What to do with an image after the user has reviewed it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReviewAction {
+    Keep,
+    /// Regenerate with the same prompt and a new seed
+    RerollSeed,
+    /// Regenerate with a new pick from the template's prompt pool
+    RerollPrompt,
+    Abort,
+}
+
+impl ReviewAction {
+    fn parse(input: &str, can_reroll_prompt: bool) -> Option<ReviewAction> {
+        match input.trim().to_lowercase().as_str() {
+            "" | "k" | "keep" => Some(ReviewAction::Keep),
+            "s" | "seed" => Some(ReviewAction::RerollSeed),
+            "p" | "prompt" if can_reroll_prompt => Some(ReviewAction::RerollPrompt),
+            "a" | "abort" => Some(ReviewAction::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// Show the generated image's details and ask the user what to do with it
+///
+/// Rerolling the prompt is only offered when the template is available to pick from
+pub fn ask_action(
+    index: usize,
+    image_path: &Path,
+    prompt: &PromptData,
+    can_reroll_prompt: bool,
+) -> anyhow::Result<ReviewAction> {
+    println!();
+    println!("Image {}: {}", index, image_path.display());
+    println!("  Prompt: {}", prompt.full_positive());
+    if let Some(seed) = prompt.seed {
+        println!("  Seed: {}", seed);
+    }
+
+    let choices = if can_reroll_prompt {
+        "[k]eep (default), reroll new [s]eed, reroll new [p]rompt, [a]bort"
+    } else {
+        "[k]eep (default), reroll new [s]eed, [a]bort"
+    };
+    loop {
+        print!("{}: ", choices);
+        std::io::stdout().flush()?;
+
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            // stdin was closed, there's nobody left to ask
+            return Ok(ReviewAction::Abort);
+        }
+        match ReviewAction::parse(&input, can_reroll_prompt) {
+            Some(action) => return Ok(action),
+            None => println!("Unrecognized choice \"{}\"", input.trim()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_actions() {
+        assert_eq!(ReviewAction::parse("\n", true), Some(ReviewAction::Keep));
+        assert_eq!(
+            ReviewAction::parse("S\n", true),
+            Some(ReviewAction::RerollSeed)
+        );
+        assert_eq!(
+            ReviewAction::parse("p", true),
+            Some(ReviewAction::RerollPrompt)
+        );
+        assert_eq!(
+            ReviewAction::parse("abort", false),
+            Some(ReviewAction::Abort)
+        );
+        assert_eq!(ReviewAction::parse("x", true), None);
+    }
+
+    #[test]
+    fn prompt_reroll_needs_template() {
+        assert_eq!(ReviewAction::parse("p", false), None);
+    }
+}
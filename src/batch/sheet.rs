@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+
+use image::{imageops, RgbaImage};
+
+use super::grid::{self, BACKGROUND, GLYPH_SIZE};
+use super::{post_process, BatchError, BatchLog, OutputFormat};
+
+/// Layout of a contact sheet of a run's images
+#[derive(clap::Args, Clone, Debug)]
+pub struct SheetSettings {
+    /// Number of images in each row
+    #[arg(long, default_value_t = 4)]
+    pub columns: u32,
+    /// Most rows on one sheet, more images are put on more sheets. Defaults to putting every image on one sheet
+    #[arg(long)]
+    pub rows: Option<u32>,
+    /// Longest side of each image on the sheet, in pixels
+    #[arg(long, default_value_t = 256)]
+    pub thumbnail_size: u32,
+    /// Space around each image, in pixels
+    #[arg(long, default_value_t = 8)]
+    pub padding: u32,
+    /// Write the index, seed and prompt of each image under it
+    #[arg(long)]
+    pub captions: bool,
+    /// Index of the first image to include
+    #[arg(long)]
+    pub from: Option<usize>,
+    /// Index of the last image to include
+    #[arg(long)]
+    pub to: Option<usize>,
+    /// Format to save the sheets in
+    #[arg(long, value_enum, default_value_t = OutputFormat::Png)]
+    pub format: OutputFormat,
+}
+
+/// A generated image to put on the sheet
+struct Tile {
+    image: RgbaImage,
+    caption: [String; 2],
+}
+
+/// Save contact sheets of the log's images next to it, returning their paths
+pub fn write_sheets(log: &BatchLog, settings: &SheetSettings) -> anyhow::Result<Vec<PathBuf>> {
+    if settings.columns == 0 || settings.thumbnail_size == 0 || settings.rows == Some(0) {
+        return Err(BatchError {
+            message: "columns, rows and thumbnail size must be more than 0".to_string(),
+        }
+        .into());
+    }
+
+    let output_dir = log.destination().output_dir;
+    let tiles = read_tiles(log, &output_dir, settings)?;
+    if tiles.is_empty() {
+        return Err(BatchError {
+            message: "there are no generated images to put on a sheet".to_string(),
+        }
+        .into());
+    }
+
+    let per_sheet = match settings.rows {
+        Some(rows) => (rows * settings.columns) as usize,
+        None => tiles.len(),
+    };
+    let sheet_count = (tiles.len() + per_sheet - 1) / per_sheet;
+    let stem = log
+        .file_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut sheet_paths = vec![];
+    for (i, sheet_tiles) in tiles.chunks(per_sheet).enumerate() {
+        let filename = match sheet_count {
+            1 => format!("{}-sheet.{}", stem, settings.format.extension()),
+            _ => format!("{}-sheet-{:02}.{}", stem, i, settings.format.extension()),
+        };
+        let sheet = stitch_sheet(sheet_tiles, settings).into();
+        let sheet_path = output_dir.join(filename);
+        std::fs::write(
+            &sheet_path,
+            post_process::encode(&sheet, settings.format, 90)?,
+        )?;
+        sheet_paths.push(sheet_path);
+    }
+    Ok(sheet_paths)
+}
+
+/// Thumbnails of every generated image in the settings' index range, skipping missing files
+fn read_tiles(
+    log: &BatchLog,
+    output_dir: &Path,
+    settings: &SheetSettings,
+) -> anyhow::Result<Vec<Tile>> {
+    let from = settings.from.unwrap_or(0);
+    let to = settings.to.unwrap_or(usize::MAX);
+    let mut tiles = vec![];
+    for (index, prompt) in log.images.iter().enumerate() {
+        if index < from || index > to {
+            continue;
+        }
+        let outputs = prompt.outputs.as_deref().unwrap_or_default();
+        for (batch_index, output) in outputs.iter().enumerate() {
+            let image_path = output_dir.join(&output.filename);
+            if !image_path.exists() {
+                continue;
+            }
+            let image = image::open(image_path)?
+                .thumbnail(settings.thumbnail_size, settings.thumbnail_size)
+                .to_rgba8();
+            let label = match outputs.len() {
+                1 => format!("#{}", index),
+                _ => format!("#{}.{}", index, batch_index),
+            };
+            tiles.push(Tile {
+                image,
+                caption: [
+                    format!("{}  seed {}", label, output.seed),
+                    prompt.full_positive().replace('\n', " "),
+                ],
+            });
+        }
+    }
+    Ok(tiles)
+}
+
+fn stitch_sheet(tiles: &[Tile], settings: &SheetSettings) -> RgbaImage {
+    let size = settings.thumbnail_size;
+    let padding = settings.padding;
+    let scale = (size / 256).max(1);
+    let line_height = GLYPH_SIZE * scale;
+    let caption_height = match settings.captions {
+        true => 2 * line_height + line_height / 2,
+        false => 0,
+    };
+
+    let columns = settings.columns.min(tiles.len() as u32);
+    let rows = (tiles.len() as u32 + columns - 1) / columns;
+    let cell_width = size + padding;
+    let cell_height = size + caption_height + padding;
+    let mut sheet = RgbaImage::from_pixel(
+        padding + columns * cell_width,
+        padding + rows * cell_height,
+        BACKGROUND,
+    );
+
+    for (i, tile) in tiles.iter().enumerate() {
+        let x = padding + (i as u32 % columns) * cell_width;
+        let y = padding + (i as u32 / columns) * cell_height;
+        // Thumbnails keep their aspect ratio, centered in their cell
+        let tile_x = x + (size - tile.image.width()) / 2;
+        let tile_y = y + (size - tile.image.height()) / 2;
+        imageops::overlay(&mut sheet, &tile.image, tile_x as i64, tile_y as i64);
+
+        if settings.captions {
+            for (line, text) in tile.caption.iter().enumerate() {
+                let text = grid::fit_text(text, size, scale);
+                let text_y = y + size + line_height / 2 + line as u32 * line_height;
+                grid::draw_text(&mut sheet, x, text_y, &text, scale);
+            }
+        }
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::test_dir;
+    use super::super::{GeneratedImage, PromptData};
+    use super::*;
+
+    fn settings() -> SheetSettings {
+        SheetSettings {
+            columns: 2,
+            rows: None,
+            thumbnail_size: 64,
+            padding: 4,
+            captions: false,
+            from: None,
+            to: None,
+            format: OutputFormat::Png,
+        }
+    }
+
+    fn log(output_dir: &Path, count: usize) -> BatchLog {
+        let mut log = BatchLog::new("sheet", output_dir);
+        for index in 0..count {
+            let filename = format!("{:02}.png", index);
+            RgbaImage::from_pixel(128, 64, image::Rgba([255, 0, 0, 255]))
+                .save(output_dir.join(&filename))
+                .unwrap();
+            log.images.push(PromptData {
+                positive: "a very long prompt that won't fit under the image".to_string(),
+                outputs: Some(vec![GeneratedImage {
+                    filename,
+                    seed: index as i64,
+                    subseed: 0,
+                    info: String::new(),
+                    original: None,
+                }]),
+                ..Default::default()
+            });
+        }
+        // Not generated yet
+        log.images.push(PromptData::default());
+        log
+    }
+
+    #[test]
+    fn images_are_laid_out_in_rows() {
+        let dir = test_dir();
+        let log = log(&dir, 3);
+        let sheets = write_sheets(&log, &settings()).unwrap();
+        assert_eq!(sheets.len(), 1);
+        let sheet = image::open(&sheets[0]).unwrap();
+        assert_eq!(sheet.width(), 4 + 2 * (64 + 4));
+        assert_eq!(sheet.height(), 4 + 2 * (64 + 4));
+
+        let captioned = SheetSettings {
+            captions: true,
+            from: Some(1),
+            ..settings()
+        };
+        let sheet = image::open(&write_sheets(&log, &captioned).unwrap()[0]).unwrap();
+        assert_eq!(sheet.height(), 4 + (64 + 20 + 4));
+    }
+
+    #[test]
+    fn rows_split_the_images_across_sheets() {
+        let dir = test_dir();
+        let log = log(&dir, 5);
+        let split = SheetSettings {
+            rows: Some(1),
+            format: OutputFormat::Jpeg,
+            ..settings()
+        };
+        let sheets = write_sheets(&log, &split).unwrap();
+        assert_eq!(sheets.len(), 3);
+        assert!(sheets[2].to_string_lossy().ends_with("-sheet-02.jpg"));
+        assert_eq!(image::open(&sheets[2]).unwrap().width(), 4 + 64 + 4);
+
+        let empty = SheetSettings {
+            from: Some(5),
+            ..settings()
+        };
+        assert!(write_sheets(&log, &empty).is_err());
+    }
+}
@@ -0,0 +1,442 @@
+use std::path::{Path, PathBuf};
+
+use image::{imageops, RgbaImage};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::grid::{self, BACKGROUND, GLYPH_SIZE};
+use super::{BatchError, PromptData};
+
+/// Axes to sweep each prompt picked for a run across
+///
+/// Every combination of the axis values is generated with the prompt's seed,
+/// and stitched into a labeled grid image for each prompt
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct SweepSettings {
+    /// Values across the columns of the grid
+    x: Option<SweepAxis>,
+    /// Values down the rows of the grid
+    y: Option<SweepAxis>,
+    /// Values to make a separate grid for each of
+    z: Option<SweepAxis>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SweepAxis {
+    /// Prompt data field to set, with a "." for nested settings and list indices
+    ///
+    /// ex., "sampler", "cfg", "hires.denoising_strength" or "loras.0.weight"
+    field: String,
+    /// Values to set the field to
+    values: Vec<Value>,
+}
+
+/// Where an image belongs in a sweep grid, with the labels of the axis values it was generated with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SweepCell {
+    /// Which of the run's sweeps the image belongs to, one per prompt picked from the pool
+    sweep: usize,
+    x: Option<String>,
+    y: Option<String>,
+    z: Option<String>,
+}
+
+impl SweepCell {
+    /// Which of the run's sweeps the image belongs to
+    pub fn sweep(&self) -> usize {
+        self.sweep
+    }
+}
+
+/// Indices of the images that are cells of the sweep
+pub fn cells_of(images: &[PromptData], sweep: usize) -> Vec<usize> {
+    images
+        .iter()
+        .enumerate()
+        .filter(|(_, prompt)| prompt.sweep_cell.as_ref().map(SweepCell::sweep) == Some(sweep))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+impl SweepSettings {
+    /// Every value an axis sets `field` to, with the name of the axis
+    pub fn values_for(&self, field: &str) -> Vec<(&'static str, &Value)> {
+        [("x", &self.x), ("y", &self.y), ("z", &self.z)]
+            .into_iter()
+            .filter_map(|(name, axis)| Some((name, axis.as_ref()?)))
+            .filter(|(_, axis)| axis.field == field)
+            .flat_map(|(name, axis)| axis.values.iter().map(move |value| (name, value)))
+            .collect()
+    }
+
+    /// Replace each prompt with a copy for every combination of the axis values
+    pub fn expand_all(&self, prompts: &[PromptData]) -> anyhow::Result<Vec<PromptData>> {
+        let mut cells = vec![];
+        for (sweep, prompt) in prompts.iter().enumerate() {
+            cells.extend(self.expand(prompt, sweep)?);
+        }
+        Ok(cells)
+    }
+
+    fn expand(&self, prompt: &PromptData, sweep: usize) -> anyhow::Result<Vec<PromptData>> {
+        let base = serde_json::to_value(prompt)?;
+        let mut cells = vec![];
+        for z in axis_values(&self.z)? {
+            for y in axis_values(&self.y)? {
+                for x in axis_values(&self.x)? {
+                    let mut value = base.clone();
+                    let cell = SweepCell {
+                        sweep,
+                        x: set_axis_value(&mut value, x)?,
+                        y: set_axis_value(&mut value, y)?,
+                        z: set_axis_value(&mut value, z)?,
+                    };
+                    let mut cell_prompt: PromptData = serde_json::from_value(value)?;
+                    cell_prompt.sweep_cell = Some(cell);
+                    cells.push(cell_prompt);
+                }
+            }
+        }
+        Ok(cells)
+    }
+}
+
+/// Each value of the axis with the axis it belongs to, or a single `None` if the axis isn't set
+fn axis_values(axis: &Option<SweepAxis>) -> anyhow::Result<Vec<Option<(&SweepAxis, &Value)>>> {
+    match axis {
+        None => Ok(vec![None]),
+        Some(axis) if axis.values.is_empty() => Err(BatchError {
+            message: format!("sweep axis for {} has no values", axis.field),
+        }
+        .into()),
+        Some(axis) => Ok(axis
+            .values
+            .iter()
+            .map(|value| Some((axis, value)))
+            .collect()),
+    }
+}
+
+/// Set the axis field of the serialized prompt to the value, returning the value's label
+fn set_axis_value(
+    prompt: &mut Value,
+    axis_value: Option<(&SweepAxis, &Value)>,
+) -> anyhow::Result<Option<String>> {
+    let Some((axis, value)) = axis_value else {
+        return Ok(None);
+    };
+
+    let mut target = prompt;
+    for key in axis.field.split('.') {
+        target = match target {
+            Value::Object(fields) => fields.get_mut(key).ok_or_else(|| BatchError {
+                message: format!("can't sweep {}, prompts have no such field", axis.field),
+            })?,
+            Value::Array(items) => key
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| items.get_mut(index))
+                .ok_or_else(|| BatchError {
+                    message: format!(
+                        "can't sweep {}, the base prompt's list has no item {}",
+                        axis.field, key
+                    ),
+                })?,
+            _ => {
+                return Err(BatchError {
+                    message: format!(
+                        "can't sweep {}, its settings must be set in the base prompt",
+                        axis.field
+                    ),
+                }
+                .into())
+            }
+        };
+    }
+    *target = value.clone();
+
+    let value_label = match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    };
+    Ok(Some(format!("{}: {}", axis.field, value_label)))
+}
+
+/// Stitch the images of each sweep in the log into labeled grids saved in `output_dir`
+///
+/// Images that haven't been generated yet are left blank, and sweeps without any images are skipped.
+/// Returns the paths of the grids written.
+pub fn write_grids(output_dir: &Path, images: &[PromptData]) -> anyhow::Result<Vec<PathBuf>> {
+    // Images of the same sweep and z value, in the order they appear in the log
+    let mut grids: Vec<(&SweepCell, Vec<&PromptData>)> = vec![];
+    for prompt in images {
+        let Some(cell) = &prompt.sweep_cell else {
+            continue;
+        };
+        match grids
+            .iter_mut()
+            .find(|(first, _)| first.sweep == cell.sweep && first.z == cell.z)
+        {
+            Some((_, prompts)) => prompts.push(prompt),
+            None => grids.push((cell, vec![prompt])),
+        }
+    }
+
+    let mut grid_paths = vec![];
+    for (i, (first, prompts)) in grids.iter().enumerate() {
+        let Some(grid) = stitch_grid(output_dir, first.z.as_deref(), prompts)? else {
+            continue;
+        };
+        let grid_path = match &first.z {
+            None => output_dir.join(format!("grid-{:02}.png", first.sweep)),
+            Some(_) => {
+                // Number the grids of each sweep by their z value
+                let z_index = grids[..i]
+                    .iter()
+                    .filter(|(other, _)| other.sweep == first.sweep)
+                    .count();
+                output_dir.join(format!("grid-{:02}-{}.png", first.sweep, z_index))
+            }
+        };
+        grid.save(&grid_path)?;
+        grid_paths.push(grid_path);
+    }
+    Ok(grid_paths)
+}
+
+fn stitch_grid(
+    output_dir: &Path,
+    title: Option<&str>,
+    prompts: &[&PromptData],
+) -> anyhow::Result<Option<RgbaImage>> {
+    let cells: Vec<&SweepCell> = prompts.iter().flat_map(|p| &p.sweep_cell).collect();
+    let mut columns: Vec<Option<&str>> = vec![];
+    let mut rows: Vec<Option<&str>> = vec![];
+    for cell in &cells {
+        if !columns.contains(&cell.x.as_deref()) {
+            columns.push(cell.x.as_deref());
+        }
+        if !rows.contains(&cell.y.as_deref()) {
+            rows.push(cell.y.as_deref());
+        }
+    }
+
+    let mut tiles = vec![];
+    for prompt in prompts {
+        let image_path = prompt
+            .outputs
+            .as_ref()
+            .and_then(|outputs| outputs.first())
+            .map(|output| output_dir.join(&output.filename))
+            .filter(|image_path| image_path.exists());
+        tiles.push(match image_path {
+            Some(image_path) => Some(image::open(image_path)?.to_rgba8()),
+            None => None,
+        });
+    }
+    let Some(cell_width) = tiles.iter().flatten().map(|tile| tile.width()).max() else {
+        return Ok(None);
+    };
+    let cell_height = tiles.iter().flatten().map(|tile| tile.height()).max();
+    let cell_height = cell_height.unwrap_or(cell_width);
+
+    let scale = (cell_width / 256).max(1);
+    let line_height = GLYPH_SIZE * scale;
+    let padding = line_height / 2;
+    let label_height = line_height + 2 * padding;
+    let title_height = if title.is_some() { label_height } else { 0 };
+    let header_height = if columns.iter().any(Option::is_some) {
+        label_height
+    } else {
+        0
+    };
+    let row_label_width = rows
+        .iter()
+        .flatten()
+        .map(|label| grid::text_width(label, scale) + 2 * padding)
+        .max()
+        .unwrap_or_default()
+        .min(cell_width);
+
+    let width = row_label_width + columns.len() as u32 * cell_width;
+    let height = title_height + header_height + rows.len() as u32 * cell_height;
+    let mut image = RgbaImage::from_pixel(width, height, BACKGROUND);
+
+    if let Some(title) = title {
+        let title = grid::fit_text(title, width.saturating_sub(2 * padding), scale);
+        grid::draw_text(&mut image, padding, padding, &title, scale);
+    }
+    for (column, label) in columns.iter().enumerate() {
+        if let Some(label) = label {
+            let label = grid::fit_text(label, cell_width.saturating_sub(2 * padding), scale);
+            let x = row_label_width
+                + column as u32 * cell_width
+                + cell_width.saturating_sub(grid::text_width(&label, scale)) / 2;
+            grid::draw_text(&mut image, x, title_height + padding, &label, scale);
+        }
+    }
+    for (row, label) in rows.iter().enumerate() {
+        if let Some(label) = label {
+            let label = grid::fit_text(label, row_label_width.saturating_sub(padding), scale);
+            let y = title_height
+                + header_height
+                + row as u32 * cell_height
+                + cell_height.saturating_sub(line_height) / 2;
+            grid::draw_text(&mut image, padding, y, &label, scale);
+        }
+    }
+    for (cell, tile) in cells.iter().zip(&tiles) {
+        let Some(tile) = tile else {
+            continue;
+        };
+        let column = columns.iter().position(|x| *x == cell.x.as_deref());
+        let row = rows.iter().position(|y| *y == cell.y.as_deref());
+        let x = row_label_width + column.unwrap_or_default() as u32 * cell_width;
+        let y = title_height + header_height + row.unwrap_or_default() as u32 * cell_height;
+        imageops::overlay(&mut image, tile, x as i64, y as i64);
+    }
+    Ok(Some(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::testing::test_dir;
+    use super::super::{GeneratedImage, HiResSettings, LoraEntry};
+    use super::*;
+
+    fn sweep(x: SweepAxis, y: Option<SweepAxis>) -> SweepSettings {
+        SweepSettings {
+            x: Some(x),
+            y,
+            z: None,
+        }
+    }
+
+    fn axis(field: &str, values: Vec<Value>) -> SweepAxis {
+        SweepAxis {
+            field: field.to_string(),
+            values,
+        }
+    }
+
+    #[test]
+    fn cells_keep_the_prompt_seed() {
+        let settings = sweep(
+            axis("sampler", vec![json!("Euler a"), json!("DPM++ 2M")]),
+            Some(axis("cfg", vec![json!(5), json!(7.5)])),
+        );
+        let prompt = PromptData {
+            positive: "1girl".to_string(),
+            seed: Some(1234),
+            ..Default::default()
+        };
+        let cells = settings.expand_all(&[prompt]).unwrap();
+
+        assert_eq!(cells.len(), 4);
+        assert!(cells.iter().all(|cell| cell.seed == Some(1234)));
+        assert_eq!(cells[1].sampler, "DPM++ 2M");
+        assert_eq!(cells[2].cfg, 7.5);
+        assert_eq!(
+            cells[3].sweep_cell,
+            Some(SweepCell {
+                sweep: 0,
+                x: Some("sampler: DPM++ 2M".to_string()),
+                y: Some("cfg: 7.5".to_string()),
+                z: None
+            })
+        );
+    }
+
+    #[test]
+    fn nested_fields_are_swept() {
+        let settings = sweep(
+            axis("hires.denoising_strength", vec![json!(0.3), json!(0.6)]),
+            None,
+        );
+        let prompt = PromptData {
+            hires: Some(HiResSettings::default()),
+            ..Default::default()
+        };
+        let cells = settings.expand_all(&[prompt]).unwrap();
+        assert_eq!(cells[1].hires.as_ref().unwrap().denoising_strength, 0.6);
+
+        let lora_weights = sweep(axis("loras.0.weight", vec![json!(0.5), json!(1)]), None);
+        let prompt = PromptData {
+            loras: Some(vec![LoraEntry::default()]),
+            ..Default::default()
+        };
+        let cells = lora_weights.expand_all(&[prompt]).unwrap();
+        assert_eq!(cells[1].loras.as_ref().unwrap()[0].weight, Some(1.0));
+        assert!(lora_weights.expand_all(&[PromptData::default()]).is_err());
+
+        assert!(settings.expand_all(&[PromptData::default()]).is_err());
+    }
+
+    #[test]
+    fn invalid_axes_are_errors() {
+        let prompts = [PromptData::default()];
+        let unknown = sweep(axis("sampler_name", vec![json!("Euler")]), None);
+        assert!(unknown.expand_all(&prompts).is_err());
+        let wrong_type = sweep(axis("steps", vec![json!("twenty")]), None);
+        assert!(wrong_type.expand_all(&prompts).is_err());
+        let empty = sweep(axis("steps", vec![]), None);
+        assert!(empty.expand_all(&prompts).is_err());
+    }
+
+    #[test]
+    fn grids_are_stitched_from_generated_images() {
+        let output_dir = test_dir();
+        let settings = sweep(
+            axis("steps", vec![json!(10), json!(20)]),
+            Some(axis("cfg", vec![json!(5), json!(7)])),
+        );
+        let mut cells = settings.expand_all(&[PromptData::default()]).unwrap();
+        // The last cell is left ungenerated
+        for (index, cell) in cells.iter_mut().take(3).enumerate() {
+            let filename = format!("{:02}.png", index);
+            RgbaImage::from_pixel(64, 32, image::Rgba([255, 0, 0, 255]))
+                .save(output_dir.join(&filename))
+                .unwrap();
+            cell.outputs = Some(vec![GeneratedImage {
+                filename,
+                seed: 0,
+                subseed: 0,
+                info: String::new(),
+                original: None,
+            }]);
+        }
+
+        let grids = write_grids(&output_dir, &cells).unwrap();
+        assert_eq!(grids, vec![output_dir.join("grid-00.png")]);
+        let grid = image::open(&grids[0]).unwrap();
+        // "cfg: 5" row labels with padding, and a header line with padding
+        assert_eq!(grid.width(), 6 * 8 + 2 * 4 + 2 * 64);
+        assert_eq!(grid.height(), 16 + 2 * 32);
+    }
+
+    #[test]
+    fn cells_smaller_than_the_padding_are_stitched() {
+        let output_dir = test_dir();
+        let settings = sweep(
+            axis("steps", vec![json!(10)]),
+            Some(axis("cfg", vec![json!(5)])),
+        );
+        let mut cells = settings.expand_all(&[PromptData::default()]).unwrap();
+        RgbaImage::new(2, 2)
+            .save(output_dir.join("00.png"))
+            .unwrap();
+        cells[0].outputs = Some(vec![GeneratedImage {
+            filename: "00.png".to_string(),
+            seed: 0,
+            subseed: 0,
+            info: String::new(),
+            original: None,
+        }]);
+
+        let grids = write_grids(&output_dir, &cells).unwrap();
+        let grid = image::open(&grids[0]).unwrap();
+        assert_eq!(grid.width(), 2 + 2);
+    }
+}
@@ -0,0 +1,27 @@
+use std::ops::Deref;
+use std::path::Path;
+
+use tempfile::TempDir;
+
+/// A directory for a test's files, unique to the test and removed when it's dropped
+pub struct TestDir(TempDir);
+
+/// Create a new, empty directory for a test's files
+pub fn test_dir() -> TestDir {
+    let dir = tempfile::Builder::new().prefix("sdbatch-").tempdir();
+    TestDir(dir.expect("to create a test directory"))
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        self.0.path()
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        self.0.path()
+    }
+}
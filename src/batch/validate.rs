@@ -0,0 +1,638 @@
+use std::fmt;
+
+use rand::SeedableRng;
+
+use super::backend::{Backend, Inventory};
+use super::post_process::parse_aspect_ratio;
+use super::{
+    BatchError, BatchRng, BatchTemplate, ControlNetUnit, LoraEntry, PostProcesses, Prompts,
+    ResizeMode,
+};
+
+/// How far the chances of weighted prompts can be from adding up to 1.0
+const CHANCE_TOLERANCE: f32 = 0.001;
+
+/// Something wrong with a template, and where in the template it was found
+pub struct Problem {
+    /// Path to the setting, ex. "prompts[2]" or "base_prompt.sampler"
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn problem(location: impl Into<String>, message: impl Into<String>) -> Problem {
+    Problem {
+        location: location.into(),
+        message: message.into(),
+    }
+}
+
+/// Check everything that can be checked without the server
+///
+/// The count isn't checked for combinatorial runs, which ignore it
+pub fn check_template(template: &BatchTemplate, combinatorial: bool) -> Vec<Problem> {
+    let mut problems = vec![];
+    let base = &template.base_prompt;
+
+    if let Some(count) = template.count {
+        if !combinatorial && count > template.prompts.len() {
+            problems.push(problem(
+                "count",
+                format!(
+                    "count is {} but there are only {} prompts to pick from",
+                    count,
+                    template.prompts.len()
+                ),
+            ));
+        }
+    }
+    if template.prompts.is_empty() {
+        problems.push(problem("prompts", "there are no prompts to pick from"));
+    }
+    if base.model.is_empty() {
+        problems.push(problem("base_prompt.model", "no model is set"));
+    }
+    for (name, size) in [("width", base.width), ("height", base.height)] {
+        if size == 0 || size % 8 != 0 {
+            problems.push(problem(
+                format!("base_prompt.{}", name),
+                format!("{} is {}, it must be a multiple of 8", name, size),
+            ));
+        }
+    }
+    check_syntax(&mut problems, "base_prompt.positive", &base.positive);
+    check_syntax(&mut problems, "base_prompt.negative", &base.negative);
+    check_loras(&mut problems, "base_prompt", &base.loras);
+    check_controlnet(&mut problems, "base_prompt", &base.controlnet);
+    for (index, step) in base.post_process.iter().flatten().enumerate() {
+        check_post_process(
+            &mut problems,
+            &format!("base_prompt.post_process[{}]", index),
+            step,
+        );
+    }
+
+    for (index, prompts) in template.prompts.iter().enumerate() {
+        let location = format!("prompts[{}]", index);
+        match prompts {
+            Prompts::Single(prompt) => check_prompt(&mut problems, &location, prompt),
+            Prompts::Multiple(options) => {
+                if options.is_empty() {
+                    problems.push(problem(&location, "there are no options to pick from"));
+                }
+                for (option_index, option) in options.iter().enumerate() {
+                    let location = format!("{}[{}]", location, option_index);
+                    check_prompt(&mut problems, &location, option);
+                }
+            }
+            Prompts::MultipleWeighted(options) => {
+                if options.is_empty() {
+                    problems.push(problem(&location, "there are no options to pick from"));
+                    continue;
+                }
+                for (option_index, option) in options.iter().enumerate() {
+                    let location = format!("{}[{}]", location, option_index);
+                    check_prompt(&mut problems, &location, &option.prompt);
+                    check_chance(&mut problems, &location, option.chance);
+                }
+                let total: f32 = options.iter().map(|o| o.chance.unwrap_or(1.0)).sum();
+                if (total - 1.0).abs() > CHANCE_TOLERANCE {
+                    problems.push(problem(
+                        &location,
+                        format!("chances add up to {}, they must add up to 1.0", total),
+                    ));
+                }
+            }
+            Prompts::Detailed(detailed) => {
+                check_prompt(&mut problems, &location, &detailed.prompt);
+                check_controlnet(&mut problems, &location, &detailed.controlnet);
+            }
+        }
+    }
+
+    for (index, modifier) in template.modifiers.iter().flatten().enumerate() {
+        let location = format!("modifiers[{}]", index);
+        if modifier.loras.is_some() || modifier.embeddings.is_some() {
+            check_syntax(&mut problems, &location, &modifier.prompt);
+        } else {
+            check_prompt(&mut problems, &location, &modifier.prompt);
+        }
+        check_chance(&mut problems, &location, modifier.chance);
+        check_loras(&mut problems, &location, &modifier.loras);
+        if modifier.if_activator.as_deref() == Some("") {
+            problems.push(problem(&location, "\"if\" is empty, so it always matches"));
+        }
+        let if_not = match &modifier.if_not_activator {
+            Some(super::OneToManyPrompts::One(text)) => vec![text],
+            Some(super::OneToManyPrompts::Many(texts)) => texts.iter().collect(),
+            None => vec![],
+        };
+        if if_not.iter().any(|text| text.is_empty()) {
+            problems.push(problem(
+                &location,
+                "\"if-not\" has an empty string, so the modifier is never used",
+            ));
+        }
+    }
+
+    problems
+}
+
+fn check_post_process(problems: &mut Vec<Problem>, location: &str, step: &PostProcesses) {
+    match step {
+        PostProcesses::Resize { scale_by } if *scale_by <= 0.0 => {
+            problems.push(problem(location, "scale_by must be more than 0"));
+        }
+        PostProcesses::ResizeExact { width, height }
+        | PostProcesses::ResizeFit { width, height }
+        | PostProcesses::Crop { width, height, .. }
+            if *width == 0 || *height == 0 =>
+        {
+            problems.push(problem(
+                location,
+                format!("size is {}x{}, it can't be 0", width, height),
+            ));
+        }
+        PostProcesses::Crop {
+            focus: Some(focus), ..
+        } if focus.iter().any(|f| !(0.0..=1.0).contains(f)) => {
+            problems.push(problem(location, "focus must be from 0.0 to 1.0"));
+        }
+        PostProcesses::Pad { aspect_ratio, .. } if parse_aspect_ratio(aspect_ratio).is_none() => {
+            problems.push(problem(
+                location,
+                format!("\"{}\" isn't an aspect ratio like \"16:9\"", aspect_ratio),
+            ));
+        }
+        PostProcesses::Convert {
+            quality: Some(quality),
+            ..
+        } if !(1..=100).contains(quality) => {
+            problems.push(problem(
+                location,
+                format!("quality is {}, it must be from 1 to 100", quality),
+            ));
+        }
+        _ => {}
+    }
+}
+
+fn check_loras(problems: &mut Vec<Problem>, location: &str, loras: &Option<Vec<LoraEntry>>) {
+    for (index, lora) in loras.iter().flatten().enumerate() {
+        let location = format!("{}.loras[{}]", location, index);
+        if lora.name.trim().is_empty() {
+            problems.push(problem(&location, "LoRA name is empty"));
+        }
+        if let Some([min, max]) = lora.weight_range {
+            if min > max {
+                problems.push(problem(
+                    &location,
+                    format!(
+                        "weight_range is [{}, {}], the first weight must be the lowest",
+                        min, max
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+fn check_controlnet(
+    problems: &mut Vec<Problem>,
+    location: &str,
+    units: &Option<Vec<ControlNetUnit>>,
+) {
+    for (index, unit) in units.iter().flatten().enumerate() {
+        let location = format!("{}.controlnet[{}]", location, index);
+        if unit.model.trim().is_empty() {
+            problems.push(problem(&location, "no ControlNet model is set"));
+        }
+        if unit.image.trim().is_empty() {
+            problems.push(problem(&location, "no control image is set"));
+        }
+        if let Some(weight) = unit.weight.filter(|weight| *weight < 0.0) {
+            problems.push(problem(
+                &location,
+                format!("weight is {}, it can't be below 0", weight),
+            ));
+        }
+        let start = unit.guidance_start.unwrap_or(0.0);
+        let end = unit.guidance_end.unwrap_or(1.0);
+        if !(0.0..=1.0).contains(&start) || !(0.0..=1.0).contains(&end) || start > end {
+            problems.push(problem(
+                &location,
+                format!(
+                    "guidance is from {} to {}, it must be from 0.0 to 1.0 and start before it ends",
+                    start, end
+                ),
+            ));
+        }
+        if let Some(ResizeMode::LatentUpscale) = unit.resize_mode {
+            problems.push(problem(
+                &location,
+                "LatentUpscale is only an img2img resize mode",
+            ));
+        }
+    }
+}
+
+fn check_prompt(problems: &mut Vec<Problem>, location: &str, prompt: &str) {
+    if prompt.trim().is_empty() {
+        problems.push(problem(location, "prompt is empty"));
+    }
+    check_syntax(problems, location, prompt);
+}
+
+/// Report `{a|b}` variants that can't be expanded
+fn check_syntax(problems: &mut Vec<Problem>, location: &str, prompt: &str) {
+    if let Err(e) = BatchTemplate::expand_variants(prompt, &mut BatchRng::seed_from_u64(0)) {
+        let message = match e.downcast_ref::<BatchError>() {
+            Some(e) => e.message.clone(),
+            None => e.to_string(),
+        };
+        problems.push(problem(location, message));
+    }
+}
+
+fn check_chance(problems: &mut Vec<Problem>, location: &str, chance: Option<f32>) {
+    if let Some(chance) = chance.filter(|c| !(0.0..=1.0).contains(c)) {
+        problems.push(problem(
+            location,
+            format!("chance is {}, it must be between 0.0 and 1.0", chance),
+        ));
+    }
+}
+
+/// Check every model, sampler, upscaler and LoRA the template uses is on the server
+pub fn check_server(template: &BatchTemplate, api: &dyn Backend) -> anyhow::Result<Vec<Problem>> {
+    let mut problems = vec![];
+    let base = &template.base_prompt;
+
+    let mut models = vec![("base_prompt.model".to_string(), base.model.clone())];
+    let mut samplers = vec![("base_prompt.sampler".to_string(), base.sampler.clone())];
+    let mut upscalers = vec![];
+    if let Some(hires) = &base.hires {
+        upscalers.push((
+            "base_prompt.hires.upscaler".to_string(),
+            hires.upscaler.clone(),
+        ));
+    }
+    for (index, step) in base.post_process.iter().flatten().enumerate() {
+        if let PostProcesses::Upscale(settings) = step {
+            upscalers.push((
+                format!("base_prompt.post_process[{}].upscaler", index),
+                settings.upscaler.clone(),
+            ));
+        }
+    }
+    if let Some(sweep) = &template.sweep {
+        for (field, names) in [
+            ("model", &mut models),
+            ("sampler", &mut samplers),
+            ("hires.upscaler", &mut upscalers),
+        ] {
+            for (axis, value) in sweep.values_for(field) {
+                if let Some(name) = value.as_str() {
+                    names.push((format!("sweep.{}", axis), name.to_string()));
+                }
+            }
+        }
+    }
+    // An empty model is already reported by the offline checks
+    models.retain(|(_, model)| !model.is_empty());
+
+    check_names(
+        &mut problems,
+        "model",
+        &models,
+        &api.names(Inventory::Models)?,
+        model_name,
+    );
+    check_names(
+        &mut problems,
+        "sampler",
+        &samplers,
+        &api.names(Inventory::Samplers)?,
+        |s| s,
+    );
+    if !upscalers.is_empty() {
+        check_names(
+            &mut problems,
+            "upscaler",
+            &upscalers,
+            &api.names(Inventory::Upscalers)?,
+            |s| s,
+        );
+    }
+    let loras = template_loras(template);
+    if !loras.is_empty() {
+        check_names(
+            &mut problems,
+            "LoRA",
+            &loras,
+            &api.names(Inventory::Loras)?,
+            strip_extension,
+        );
+    }
+    let embeddings = template_embeddings(template);
+    if !embeddings.is_empty() {
+        check_names(
+            &mut problems,
+            "embedding",
+            &embeddings,
+            &api.names(Inventory::Embeddings)?,
+            strip_extension,
+        );
+    }
+
+    Ok(problems)
+}
+
+/// Report each of `used` that isn't one of `available`, comparing them after `normalize`
+fn check_names(
+    problems: &mut Vec<Problem>,
+    kind: &str,
+    used: &[(String, String)],
+    available: &[String],
+    normalize: fn(&str) -> &str,
+) {
+    for (location, name) in used {
+        let found = available
+            .iter()
+            .any(|option| option == name || normalize(option) == normalize(name));
+        if found {
+            continue;
+        }
+        let message = match closest_match(name, available, normalize) {
+            Some(suggestion) => format!(
+                "{} \"{}\" not found, did you mean \"{}\"?",
+                kind, name, suggestion
+            ),
+            None => format!(
+                "{} \"{}\" not found, must be one of: {}",
+                kind,
+                name,
+                available.join(", ")
+            ),
+        };
+        problems.push(problem(location, message));
+    }
+}
+
+/// Every LoRA in the template, in its LoRA lists or written like `<lora:name:weight>` in its prompts,
+/// with where it was found
+fn template_loras(template: &BatchTemplate) -> Vec<(String, String)> {
+    let base = &template.base_prompt;
+    let mut loras = vec![];
+    let mut add_entries = |location: &str, entries: &Option<Vec<LoraEntry>>| {
+        for (index, lora) in entries.iter().flatten().enumerate() {
+            loras.push((format!("{}.loras[{}]", location, index), lora.name.clone()));
+        }
+    };
+    add_entries("base_prompt", &base.loras);
+    for (index, modifier) in template.modifiers.iter().flatten().enumerate() {
+        add_entries(&format!("modifiers[{}]", index), &modifier.loras);
+    }
+
+    let mut texts = vec![
+        ("base_prompt.positive".to_string(), base.positive.as_str()),
+        ("base_prompt.negative".to_string(), base.negative.as_str()),
+    ];
+    for (index, prompts) in template.prompts.iter().enumerate() {
+        let location = format!("prompts[{}]", index);
+        match prompts {
+            Prompts::Single(prompt) => texts.push((location, prompt)),
+            Prompts::Multiple(options) => {
+                texts.extend(options.iter().map(|o| (location.clone(), o.as_str())))
+            }
+            Prompts::MultipleWeighted(options) => texts.extend(
+                options
+                    .iter()
+                    .map(|o| (location.clone(), o.prompt.as_str())),
+            ),
+            Prompts::Detailed(detailed) => texts.push((location, &detailed.prompt)),
+        }
+    }
+    for (index, modifier) in template.modifiers.iter().flatten().enumerate() {
+        texts.push((format!("modifiers[{}]", index), &modifier.prompt));
+    }
+
+    loras.extend(texts.into_iter().flat_map(|(location, text)| {
+        lora_names(text)
+            .into_iter()
+            .map(move |name| (location.clone(), name.to_string()))
+    }));
+    loras
+}
+
+/// Every embedding in the template's embedding lists, with where it was found
+fn template_embeddings(template: &BatchTemplate) -> Vec<(String, String)> {
+    let base = &template.base_prompt;
+    let mut lists = vec![
+        ("base_prompt.embeddings".to_string(), &base.embeddings),
+        (
+            "base_prompt.negative_embeddings".to_string(),
+            &base.negative_embeddings,
+        ),
+    ];
+    for (index, modifier) in template.modifiers.iter().flatten().enumerate() {
+        lists.push((
+            format!("modifiers[{}].embeddings", index),
+            &modifier.embeddings,
+        ));
+    }
+    lists
+        .into_iter()
+        .flat_map(|(location, names)| {
+            names
+                .iter()
+                .flatten()
+                .enumerate()
+                .map(move |(index, name)| (format!("{}[{}]", location, index), name.clone()))
+        })
+        .collect()
+}
+
+/// Names of the LoRAs in a prompt, written like `<lora:name:0.8>`
+fn lora_names(prompt: &str) -> Vec<&str> {
+    prompt
+        .split("<lora:")
+        .skip(1)
+        .filter_map(|tag| tag.split('>').next())
+        .filter_map(|tag| tag.split(':').next())
+        .collect()
+}
+
+/// Model title without its " [hash]"
+fn strip_hash(model: &str) -> &str {
+    match model.rsplit_once(" [") {
+        Some((name, hash)) if hash.ends_with(']') => name,
+        _ => model,
+    }
+}
+
+/// Model title without its hash or extension, Automatic1111 accepts either
+fn model_name(model: &str) -> &str {
+    strip_extension(strip_hash(model))
+}
+
+/// LoRA name without its file extension, ComfyUI lists the files
+fn strip_extension(lora: &str) -> &str {
+    [".safetensors", ".ckpt", ".pt"]
+        .into_iter()
+        .find_map(|extension| lora.strip_suffix(extension))
+        .unwrap_or(lora)
+}
+
+/// The option closest to `name`, if any is close enough to be a likely typo
+fn closest_match<'a>(
+    name: &str,
+    options: &'a [String],
+    normalize: fn(&str) -> &str,
+) -> Option<&'a str> {
+    let name = normalize(name).to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(2);
+    options
+        .iter()
+        .map(|option| {
+            let distance = edit_distance(&name, &normalize(option).to_lowercase());
+            (distance, option)
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, option)| option.as_str())
+}
+
+/// Levenshtein distance, the number of single character edits to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{PromptData, PromptModifer, WeightedPrompt};
+    use super::*;
+
+    fn locations(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|p| p.location.as_str()).collect()
+    }
+
+    #[test]
+    fn reports_every_offline_problem() {
+        let template = BatchTemplate {
+            base_prompt: PromptData {
+                model: "model.safetensors".to_string(),
+                width: 512,
+                height: 500,
+                controlnet: Some(vec![ControlNetUnit {
+                    model: "control_v11p_sd15_openpose".to_string(),
+                    image: "poses".to_string(),
+                    guidance_start: Some(0.9),
+                    guidance_end: Some(0.5),
+                    ..Default::default()
+                }]),
+                post_process: Some(vec![
+                    PostProcesses::Pad {
+                        aspect_ratio: "wide".to_string(),
+                        color: None,
+                    },
+                    PostProcesses::Convert {
+                        format: super::super::OutputFormat::Jpeg,
+                        quality: Some(0),
+                    },
+                ]),
+                ..Default::default()
+            },
+            count: Some(4),
+            prompts: vec![
+                Prompts::Single("1girl".to_string()),
+                Prompts::Multiple(vec!["cat".to_string(), " ".to_string()]),
+                Prompts::MultipleWeighted(vec![
+                    WeightedPrompt {
+                        prompt: "day".to_string(),
+                        chance: Some(0.5),
+                    },
+                    WeightedPrompt {
+                        prompt: "night".to_string(),
+                        chance: Some(0.4),
+                    },
+                ]),
+            ],
+            modifiers: Some(vec![PromptModifer {
+                prompt: "{smiling|-1::frowning}".to_string(),
+                chance: Some(1.5),
+                if_activator: None,
+                if_not_activator: None,
+                loras: Some(vec![LoraEntry {
+                    name: "add_detail".to_string(),
+                    weight: None,
+                    weight_range: Some([1.0, 0.5]),
+                }]),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        };
+
+        let problems = check_template(&template, false);
+        assert_eq!(
+            locations(&problems),
+            vec![
+                "count",
+                "base_prompt.height",
+                "base_prompt.controlnet[0]",
+                "base_prompt.post_process[0]",
+                "base_prompt.post_process[1]",
+                "prompts[1][1]",
+                "prompts[2]",
+                "modifiers[0]",
+                "modifiers[0]",
+                "modifiers[0].loras[0]",
+            ]
+        );
+        assert!(check_template(&template, true)
+            .iter()
+            .all(|p| p.location != "count"));
+    }
+
+    #[test]
+    fn finds_loras_in_prompts() {
+        assert_eq!(
+            lora_names("1girl, <lora:add_detail:0.8>, <lora:film grain:1>"),
+            vec!["add_detail", "film grain"]
+        );
+        assert!(lora_names("1girl, solo").is_empty());
+    }
+
+    #[test]
+    fn suggests_near_matches() {
+        let samplers = vec!["Euler a".to_string(), "DPM++ 2M Karras".to_string()];
+        assert_eq!(closest_match("euler z", &samplers, |s| s), Some("Euler a"));
+        assert_eq!(
+            closest_match("DPM++ 2M karas", &samplers, |s| s),
+            Some("DPM++ 2M Karras")
+        );
+        assert_eq!(closest_match("UniPC", &samplers, |s| s), None);
+        let models = vec!["base.safetensors [abc123]".to_string()];
+        assert_eq!(
+            closest_match("bse.safetensors", &models, strip_hash),
+            Some("base.safetensors [abc123]")
+        );
+        assert_eq!(strip_hash("base.safetensors [abc123]"), "base.safetensors");
+        assert_eq!(strip_extension("add_detail.safetensors"), "add_detail");
+    }
+}
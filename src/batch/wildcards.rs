@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use super::BatchError;
+
+/// How many levels of wildcards inside wildcard files are expanded by default
+pub const DEFAULT_MAX_DEPTH: usize = 10;
+
+/// Expands `__name__` tokens with a random line from `name.txt` in one of the search directories
+pub struct Wildcards {
+    search_dirs: Vec<PathBuf>,
+    max_depth: usize,
+    /// Lines of each wildcard file that has been read, by wildcard name
+    files: RefCell<HashMap<String, Vec<String>>>,
+}
+
+impl Wildcards {
+    pub fn new(search_dirs: Vec<PathBuf>, max_depth: usize) -> Wildcards {
+        Wildcards {
+            search_dirs,
+            max_depth,
+            files: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Replace every wildcard in `text`, including wildcards in the picked lines
+    pub fn expand(&self, text: &str, rng: &mut impl Rng) -> anyhow::Result<String> {
+        self.expand_at_depth(text, rng, 0)
+    }
+
+    fn expand_at_depth(
+        &self,
+        text: &str,
+        rng: &mut impl Rng,
+        depth: usize,
+    ) -> anyhow::Result<String> {
+        let mut expanded = String::new();
+        let mut rest = text;
+        while let Some((before, name, after)) = next_wildcard(rest) {
+            if depth >= self.max_depth {
+                return Err(BatchError {
+                    message: format!(
+                        "wildcards nested more than {} deep while expanding __{}__, does it include itself?",
+                        self.max_depth, name
+                    ),
+                }
+                .into());
+            }
+            let line = self.pick_line(name, rng)?;
+            expanded.push_str(before);
+            expanded.push_str(&self.expand_at_depth(&line, rng, depth + 1)?);
+            rest = after;
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    fn pick_line(&self, name: &str, rng: &mut impl Rng) -> anyhow::Result<String> {
+        let mut files = self.files.borrow_mut();
+        if !files.contains_key(name) {
+            let lines = self.read_file(name)?;
+            files.insert(name.to_string(), lines);
+        }
+        let line = files[name].choose(rng).ok_or_else(|| BatchError {
+            message: format!("wildcard file for __{}__ has no lines", name),
+        })?;
+        Ok(line.clone())
+    }
+
+    /// Read the non-empty, non-comment lines of the first matching wildcard file
+    fn read_file(&self, name: &str) -> anyhow::Result<Vec<String>> {
+        let file_path = self
+            .search_dirs
+            .iter()
+            .map(|dir| dir.join(format!("{}.txt", name)))
+            .find(|file_path| file_path.is_file())
+            .ok_or_else(|| BatchError {
+                message: format!(
+                    "no wildcard file {}.txt found for __{}__, searched: {}",
+                    name,
+                    name,
+                    self.search_dirs
+                        .iter()
+                        .map(|dir| dir.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            })?;
+
+        let contents = fs::read_to_string(file_path)?;
+        Ok(contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// Split `text` around its first `__name__` token, returning the text before it, the name, and the text after it
+fn next_wildcard(text: &str) -> Option<(&str, &str, &str)> {
+    let mut search_from = 0;
+    while let Some(start) = text[search_from..].find("__").map(|i| i + search_from) {
+        let name_start = start + 2;
+        let end = name_start + text[name_start..].find("__")?;
+        let name = &text[name_start..end];
+        if !name.is_empty() && !name.contains(char::is_whitespace) {
+            return Some((&text[..start], name, &text[end + 2..]));
+        }
+        // Not a wildcard, the closing underscores might start the next one
+        search_from = end;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::super::testing::{test_dir, TestDir};
+    use super::*;
+
+    fn wildcards_dir(files: &[(&str, &str)]) -> TestDir {
+        let dir = test_dir();
+        for (file_name, contents) in files {
+            let file_path = dir.join(file_name);
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            fs::write(file_path, contents).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn finds_wildcard_tokens() {
+        assert_eq!(
+            next_wildcard("a __hair_color__ b"),
+            Some(("a ", "hair_color", " b"))
+        );
+        assert_eq!(next_wildcard("____colors__"), Some(("__", "colors", "")));
+        assert_eq!(next_wildcard("no wildcards __ here"), None);
+        assert_eq!(
+            next_wildcard("__ spaced __ __x__"),
+            Some(("__ spaced __ ", "x", ""))
+        );
+    }
+
+    #[test]
+    fn expands_nested_wildcards() {
+        let dir = wildcards_dir(&[
+            ("outfit.txt", "# comment\n\n__colors__ dress\n"),
+            ("colors.txt", "red\n"),
+            ("nested/place.txt", "beach"),
+        ]);
+        let wildcards = Wildcards::new(vec![dir.to_path_buf()], DEFAULT_MAX_DEPTH);
+        let expanded = wildcards
+            .expand(
+                "1girl, __outfit__, __nested/place__",
+                &mut ChaCha8Rng::seed_from_u64(0),
+            )
+            .unwrap();
+        assert_eq!(expanded, "1girl, red dress, beach");
+    }
+
+    #[test]
+    fn recursion_is_limited() {
+        let dir = wildcards_dir(&[("loop.txt", "__loop__")]);
+        let wildcards = Wildcards::new(vec![dir.to_path_buf()], 3);
+        assert!(wildcards
+            .expand("__loop__", &mut ChaCha8Rng::seed_from_u64(0))
+            .is_err());
+    }
+
+    #[test]
+    fn missing_wildcard_is_an_error() {
+        let wildcards = Wildcards::new(vec![], DEFAULT_MAX_DEPTH);
+        assert!(wildcards
+            .expand("__missing__", &mut ChaCha8Rng::seed_from_u64(0))
+            .is_err());
+    }
+}
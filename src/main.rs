@@ -1,6 +1,6 @@
 use std::{path, time::Instant};
 
-use batch::BatchTemplate;
+use batch::{BatchTemplate, Inventory, RunOptions, ServerOptions, SheetSettings, UpscaleSettings};
 use clap::{Parser, Subcommand};
 
 mod batch;
@@ -19,10 +19,31 @@ fn main() {
                 file,
                 output,
                 sequential,
-                api_url,
+                combinatorial,
+                max_combinations,
+                server,
+                seed,
+                quiet,
+                interactive,
+                continue_on_error,
+                skip_preflight,
+                gallery,
             } => {
                 let start = Instant::now();
-                match batch::do_run(dry_run, sequential, &file, &output, api_url.as_deref()) {
+                let options = RunOptions {
+                    dry_run,
+                    sequential,
+                    combinatorial,
+                    max_combinations,
+                    server,
+                    seed,
+                    quiet,
+                    interactive,
+                    continue_on_error,
+                    skip_preflight,
+                    gallery,
+                };
+                match batch::do_run(&file, &output, &options) {
                     Ok(results) => {
                         let duration = start.elapsed();
                         if dry_run {
@@ -38,15 +59,27 @@ fn main() {
                                 util::print_elapsed(&duration)
                             )
                         }
+                        if let Some(seed) = results.seed {
+                            println!(
+                                "Log written to {}, replay this run with --seed {}",
+                                results.log_file.display(),
+                                seed
+                            )
+                        }
                     }
                     Err(err) => {
                         println!("Template run error: {:?}", err)
                     }
                 }
-            },
-            Commands::Resume { api_url, file } => {
+            }
+            Commands::Resume {
+                server,
+                file,
+                quiet,
+                continue_on_error,
+            } => {
                 let start = Instant::now();
-                match batch::resume(&file, api_url.as_deref()) {
+                match batch::resume(&file, &server, quiet, continue_on_error) {
                     Ok(images_created) => {
                         let duration = start.elapsed();
                         println!(
@@ -57,25 +90,28 @@ fn main() {
                     }
                     Err(e) => println!("Template run error: {}", e),
                 }
-            },
+            }
             Commands::Reroll {
                 file,
                 index,
+                image,
                 all,
-                api_url,
+                server,
+                quiet,
+                continue_on_error,
             } => {
                 if index.is_none() && !all {
                     println!("Reroll requires either --all or an INDEX to run")
                 } else if index.is_some() && all {
                     println!("Reroll requires either --all or an INDEX to run, not both")
                 } else if let Some(index) = index {
-                    match batch::reroll(&file, index, api_url.as_deref()) {
+                    match batch::reroll(&file, index, image, &server, quiet) {
                         Ok(_) => println!("Done!"),
                         Err(e) => println!("Reroll error: {}", e),
                     }
                 } else if all {
                     let start = Instant::now();
-                    match batch::reroll_all(&file, api_url.as_deref()) {
+                    match batch::reroll_all(&file, &server, quiet, continue_on_error) {
                         Ok(_) => {
                             let duration = start.elapsed();
                             println!(
@@ -87,9 +123,107 @@ fn main() {
                     }
                 }
             }
+            Commands::Review {
+                server,
+                template,
+                quiet,
+                file,
+            } => match batch::review(&file, template.as_deref(), &server, quiet) {
+                Ok(images_reviewed) => println!("Reviewed {} images", images_reviewed),
+                Err(e) => println!("Review error: {}", e),
+            },
+            Commands::Upscale {
+                server,
+                settings,
+                file,
+            } => {
+                let start = Instant::now();
+                match batch::upscale(&file, &settings, &server) {
+                    Ok(images_upscaled) => println!(
+                        "Upscaled {} images in {}",
+                        images_upscaled,
+                        util::print_elapsed(&start.elapsed())
+                    ),
+                    Err(e) => println!("Upscale error: {}", e),
+                }
+            }
+            Commands::Gallery { file } => match batch::gallery(&file) {
+                Ok(gallery) => println!("Created gallery: {}", gallery.display()),
+                Err(e) => println!("Gallery error: {}", e),
+            },
+            Commands::Sheet { settings, file } => match batch::sheet(&file, &settings) {
+                Ok(sheets) => {
+                    for sheet in sheets {
+                        println!("Created sheet: {}", sheet.display());
+                    }
+                }
+                Err(e) => println!("Sheet error: {}", e),
+            },
+            Commands::Validate {
+                server,
+                offline,
+                file,
+            } => match batch::validate(&file, &server, offline) {
+                Ok(problems) if problems.is_empty() => println!("No problems found in {}", file),
+                Ok(problems) => {
+                    println!("Found {} problems in {}:", problems.len(), file);
+                    for problem in problems {
+                        println!("  {}", problem);
+                    }
+                }
+                Err(e) => println!("Validate error: {}", e),
+            },
+            Commands::List { server, json, kind } => match batch::list_inventory(&server, kind) {
+                Ok(items) if json => match serde_json::to_string_pretty(&items) {
+                    Ok(json) => println!("{}", json),
+                    Err(e) => println!("List error: {}", e),
+                },
+                Ok(items) if items.is_empty() => println!("The server has no {}", kind),
+                Ok(items) => {
+                    let mut headers = vec!["name"];
+                    headers.extend(items[0].details.iter().map(|(column, _)| *column));
+                    let rows: Vec<Vec<String>> = items
+                        .into_iter()
+                        .map(|item| {
+                            let mut row = vec![item.name];
+                            row.extend(item.details.into_iter().map(|(_, value)| value));
+                            row
+                        })
+                        .collect();
+                    println!("{}", util::format_table(&headers, &rows));
+                }
+                Err(e) => println!("List error: {}", e),
+            },
+            Commands::Import {
+                log,
+                name,
+                output_dir,
+                files,
+            } => {
+                let output_dir = output_dir.unwrap_or("./".to_string());
+                let output_path = path::Path::new(&output_dir);
+                if log {
+                    match batch::import_log(&files, &name, output_path) {
+                        Ok(log_file) => println!(
+                            "Created log file {}, use Reroll or Resume to generate from it",
+                            log_file.display()
+                        ),
+                        Err(e) => println!("Import error: {}", e),
+                    }
+                } else {
+                    match batch::import_template(&files, &name, output_path) {
+                        Ok(template_file) => {
+                            println!("Created template file: {}", template_file.display())
+                        }
+                        Err(e) => println!("Import error: {}", e),
+                    }
+                }
+            }
             Commands::Create { name, output_dir } => {
-                let mut template: BatchTemplate = Default::default();
-                template.name = name;
+                let template = BatchTemplate {
+                    name,
+                    ..Default::default()
+                };
                 let output_dir = &output_dir.unwrap_or("./".to_string());
                 let output_path = path::Path::new(output_dir);
                 match template.write(output_path) {
@@ -123,15 +257,45 @@ enum Commands {
         #[arg(short = 'n', long)]
         dry_run: bool,
 
-        /// API URL to use, defaults to 127.0.0.1:7860 if not set
-        #[arg(long)]
-        api_url: Option<String>,
+        #[command(flatten)]
+        server: ServerOptions,
 
         /// Generate images sequentially instead of picking prompts in a random order
         #[arg(short, long)]
         sequential: bool,
 
-        // TODO: idea: interactive mode, pause after generating each image and display it to the user until they continue
+        /// Generate every combination of prompts, prompt options and modifiers instead of picking at random
+        #[arg(short, long, conflicts_with = "sequential")]
+        combinatorial: bool,
+
+        /// Most combinations to generate with --combinatorial, defaults to 1000
+        #[arg(long, requires = "combinatorial")]
+        max_combinations: Option<usize>,
+
+        /// Master seed for all random choices in the run, overrides the template's seed if set
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Don't show the live progress bar, only print a line per image
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Pause after generating each image to keep it, reroll it, or abort the run
+        #[arg(short, long, conflicts_with = "dry_run")]
+        interactive: bool,
+
+        /// Keep generating the other images when one fails, then list the failed images at the end
+        #[arg(long)]
+        continue_on_error: bool,
+
+        /// Start generating without first checking the template and that the server has the models, samplers, upscalers and LoRAs it uses
+        #[arg(long)]
+        skip_preflight: bool,
+
+        /// Write an HTML gallery of the images next to the log when the run finishes
+        #[arg(long, conflicts_with = "dry_run")]
+        gallery: bool,
+
         /// JSON input file for batch template
         file: String,
 
@@ -140,9 +304,16 @@ enum Commands {
     },
     /// Resume generation of a template run that was interrupted or stopped partway through
     Resume {
-        /// API URL to use, defaults to 127.0.0.1:7860 if not set
+        #[command(flatten)]
+        server: ServerOptions,
+
+        /// Don't show the live progress bar, only print a line per image
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Keep generating the other images when one fails, then list the failed images at the end
         #[arg(long)]
-        api_url: Option<String>,
+        continue_on_error: bool,
 
         /// Batch log file to resume
         file: String,
@@ -153,15 +324,109 @@ enum Commands {
         #[arg(long)]
         all: bool,
 
-        /// API URL to use, defaults to 127.0.0.1:7860 if not set
-        #[arg(long)]
-        api_url: Option<String>,
+        #[command(flatten)]
+        server: ServerOptions,
+
+        /// Don't show the live progress bar, only print a line per image
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Keep generating the other images when one fails, then list the failed images at the end
+        #[arg(long, requires = "all")]
+        continue_on_error: bool,
 
         /// Batch log file to reroll for
         file: String,
 
         /// Image index to reroll
         index: Option<usize>,
+
+        /// Only reroll this image out of the INDEX image's batch, when using batch_size or n_iter
+        #[arg(long, requires = "index", conflicts_with = "all")]
+        image: Option<usize>,
+    },
+    /// Step through the images of a previous run, keeping or rerolling each one
+    Review {
+        #[command(flatten)]
+        server: ServerOptions,
+
+        /// Template to pick new prompts from, defaults to the template recorded in the log
+        #[arg(short, long)]
+        template: Option<String>,
+
+        /// Don't show the live progress bar, only print a line per image
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// Batch log file to review
+        file: String,
+    },
+    /// Upscale the images of a previous run with one of the server's upscalers, keeping the originals
+    Upscale {
+        #[command(flatten)]
+        server: ServerOptions,
+
+        #[command(flatten)]
+        settings: UpscaleSettings,
+
+        /// Batch log file to upscale the images of
+        file: String,
+    },
+    /// Write an HTML page of the images of a previous run next to its log, with their prompts and settings
+    Gallery {
+        /// Batch log file to make the gallery for
+        file: String,
+    },
+    /// Save contact sheets of the images of a previous run next to its log, to share them as a few images
+    Sheet {
+        #[command(flatten)]
+        settings: SheetSettings,
+
+        /// Batch log file to make the sheets for
+        file: String,
+    },
+    /// Check a template for problems, and that the server has the models, samplers, upscalers and LoRAs it uses
+    Validate {
+        #[command(flatten)]
+        server: ServerOptions,
+
+        /// Only check the template itself, without connecting to the server
+        #[arg(long)]
+        offline: bool,
+
+        /// JSON input file for batch template
+        file: String,
+    },
+    /// List the names the server knows, to use in templates
+    List {
+        #[command(flatten)]
+        server: ServerOptions,
+
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+
+        /// What to list
+        #[arg(value_enum)]
+        kind: Inventory,
+    },
+    /// Create a template from the generation parameters Automatic1111 saved in PNGs, with their prompts as the pool
+    Import {
+        /// Create a log with the images copied next to it instead, to Reroll or Resume
+        #[arg(long)]
+        log: bool,
+
+        /// Name of the template or log to create
+        #[arg(short, long, default_value = "imported")]
+        name: String,
+
+        /// Directory to place the template or log in, defaults to current directory
+        #[arg(short, long)]
+        output_dir: Option<String>,
+
+        /// PNG files, or directories of them, to import
+        #[arg(required = true)]
+        files: Vec<String>,
     },
     /// Generate an empty Template file
     Create {
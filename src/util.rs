@@ -1,8 +1,7 @@
 use std::time::Duration;
 
 pub fn print_elapsed(duration: &Duration) -> String {
-    let duration = chrono::Duration::from_std(*duration)
-        .unwrap_or(chrono::Duration::zero());
+    let duration = chrono::Duration::from_std(*duration).unwrap_or(chrono::Duration::zero());
     if duration.num_hours() > 0 {
         let minutes = duration.num_minutes() - duration.num_hours() * 60;
         let seconds = duration.num_seconds() - duration.num_minutes() * 60;
@@ -14,7 +13,46 @@ pub fn print_elapsed(duration: &Duration) -> String {
     }
 
     let milliseconds = duration.num_milliseconds() - (duration.num_seconds() * 1000);
-    return format!("{}.{}s", duration.num_seconds(), milliseconds);
+    format!("{}.{}s", duration.num_seconds(), milliseconds)
+}
+
+/// Widest a table cell can be before it's shortened with "..."
+const MAX_CELL_WIDTH: usize = 60;
+
+/// Lay out rows as columns of text, with the headers underlined
+pub fn format_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let cell = |text: &str| -> String {
+        if text.chars().count() <= MAX_CELL_WIDTH {
+            return text.to_string();
+        }
+        let mut shortened: String = text.chars().take(MAX_CELL_WIDTH - 3).collect();
+        shortened.push_str("...");
+        shortened
+    };
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.iter().map(|text| cell(text)).collect())
+        .collect();
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, text) in widths.iter_mut().zip(row) {
+            *width = (*width).max(text.chars().count());
+        }
+    }
+
+    let line = |cells: &[String]| -> String {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(text, width)| format!("{:<width$}", text, width = width))
+            .collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    let underlines: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+    let mut table = vec![line(&headers), line(&underlines)];
+    table.extend(rows.iter().map(|row| line(row)));
+    table.join("\n")
 }
 
 #[cfg(test)]
@@ -35,9 +73,24 @@ mod test {
         assert_eq!(print_elapsed(&duration), "03:09");
     }
 
+    #[test]
+    fn table_columns_line_up() {
+        let table = format_table(
+            &["name", "hash"],
+            &[
+                vec!["base.safetensors".to_string(), "abc123".to_string()],
+                vec!["x".to_string(), "".to_string()],
+            ],
+        );
+        assert_eq!(
+            table,
+            "name              hash\n----------------  ------\nbase.safetensors  abc123\nx"
+        );
+    }
+
     #[test]
     fn more_than_an_hour() {
-        let duration = time::Duration::new(60*60*3+60*9+12, 0);
+        let duration = time::Duration::new(60 * 60 * 3 + 60 * 9 + 12, 0);
         assert_eq!(print_elapsed(&duration), "03:09:12");
     }
 }